@@ -0,0 +1,367 @@
+// This module assembles areas such as buildings, parks and land use out of
+// the ways and relations of a map. Simple areas are closed ways with tags
+// that describe an area. Larger or holed ones are `type=multipolygon`
+// relations, whose member ways are joined end to end into closed rings:
+// `outer` rings bound the area and `inner` rings cut holes into it, each
+// inner ring belonging to the smallest outer ring around it.
+//
+// Relations that can't be assembled, because members are missing, ways don't
+// join up into closed rings or inner rings lie outside every outer ring, are
+// reported instead of guessed at.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Write;
+use serde_json;
+use avoid::contains_point;
+use entities::{Map, Way};
+use loader::Result;
+use tags::Tags;
+
+/// A closed ring of `(lat, lon)` points, whose last point repeats the first.
+pub type Ring = Vec<(f64, f64)>;
+
+/// The OSM object an area was assembled from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AreaId {
+    Way(i64),
+    Relation(i64)
+}
+
+/// An outer ring with the holes cut into it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+    pub outer: Ring,
+    pub inners: Vec<Ring>
+}
+
+impl Polygon {
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        contains_point(&self.outer, point) && !self.inners.iter().any(|inner| contains_point(inner, point))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Area {
+    pub id: AreaId,
+    pub tags: Tags,
+    pub polygons: Vec<Polygon>
+}
+
+impl Area {
+    /// Whether `(lat, lon)` lies inside the area, outside its holes.
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        self.polygons.iter().any(|polygon| polygon.contains(point))
+    }
+}
+
+/// Why a relation couldn't be assembled into an area.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Defect {
+    /// A member way, or a node of one, is missing from the map.
+    Incomplete,
+    /// The member ways don't join up into closed rings.
+    OpenRing,
+    /// An inner ring isn't inside any outer ring.
+    InnerOutside,
+    /// There are no outer rings.
+    NoOuter
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    pub relation_id: i64,
+    pub defect: Defect
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.defect {
+            Defect::Incomplete => "has missing members",
+            Defect::OpenRing => "has rings that aren't closed",
+            Defect::InnerOutside => "has an inner ring outside its outer rings",
+            Defect::NoOuter => "has no outer ring"
+        };
+        write!(f, "multipolygon relation {} {}", self.relation_id, reason)
+    }
+}
+
+/// Keys whose presence makes a closed way an area.
+const AREA_KEYS: &[&str] = &[
+    "amenity", "building", "building:part", "historic", "landuse", "leisure",
+    "man_made", "military", "natural", "place", "shop", "tourism"
+];
+
+/// Whether a way describes an area rather than a line: it has to be closed
+/// and tagged `area=yes` or with an area key. Closed highways, barriers and
+/// coastlines are lines unless tagged `area=yes`.
+pub fn is_area(way: &Way) -> bool {
+    let closed = way.node_refs.len() >= 4 && way.node_refs.first() == way.node_refs.last();
+    if !closed {
+        return false;
+    }
+    match way.tags.get("area") {
+        Some("yes") => true,
+        Some("no") => false,
+        _ => way.tags.get("natural") != Some("coastline") && AREA_KEYS.iter().any(|key| way.tags.contains_key(key))
+    }
+}
+
+// Twice the signed area of a ring, in square degrees, for comparing sizes
+fn ring_area(ring: &[(f64, f64)]) -> f64 {
+    ring.windows(2).map(|pair| pair[0].1 * pair[1].0 - pair[1].1 * pair[0].0).sum()
+}
+
+// Joins ways, given as node ids, end to end into closed rings
+fn join_rings(mut segments: Vec<Vec<i64>>) -> Option<Vec<Vec<i64>>> {
+    let mut rings = Vec::new();
+    while let Some(mut ring) = segments.pop() {
+        while ring.first() != ring.last() {
+            let end = ring[ring.len() - 1];
+            let next = segments.iter().position(|segment| segment[0] == end || segment[segment.len() - 1] == end)?;
+            let mut segment = segments.swap_remove(next);
+            if segment[0] != end {
+                segment.reverse();
+            }
+            ring.extend(segment.into_iter().skip(1));
+        }
+        if ring.len() < 4 {
+            return None;
+        }
+        rings.push(ring);
+    }
+    Some(rings)
+}
+
+/// Assembles the areas of a map from its closed ways and multipolygon
+/// relations. Returns them along with the relations that failed.
+pub fn assemble(map: &Map) -> (Vec<Area>, Vec<AssemblyError>) {
+    let positions: HashMap<i64, (f64, f64)> = map.nodes.iter().map(|node| (node.id, node.position())).collect();
+    let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+    let ring = |ids: &[i64]| -> Option<Ring> { ids.iter().map(|id| positions.get(id).cloned()).collect() };
+
+    let mut areas = Vec::new();
+    for way in map.ways.iter().filter(|way| is_area(way)) {
+        let ids: Vec<i64> = way.node_refs.iter().map(|node_ref| node_ref.id).collect();
+        if let Some(outer) = ring(&ids) {
+            areas.push(Area {
+                id: AreaId::Way(way.id),
+                tags: named(&way.tags, &way.name),
+                polygons: vec![Polygon { outer, inners: Vec::new() }]
+            });
+        }
+    }
+
+    let mut errors = Vec::new();
+    let multipolygons = map.relations.iter().filter(|relation| relation.tags.get("type") == Some("multipolygon"));
+    for relation in multipolygons {
+        let error = |defect| AssemblyError { relation_id: relation.id, defect };
+        let mut outers = Vec::new();
+        let mut inners = Vec::new();
+        let mut complete = true;
+        for member in relation.members.iter().filter(|member| member.kind == "way") {
+            match ways.get(&member.id) {
+                Some(way) if way.node_refs.len() >= 2 => {
+                    let ids = way.node_refs.iter().map(|node_ref| node_ref.id).collect();
+                    if member.role == "inner" { inners.push(ids) } else { outers.push(ids) }
+                },
+                _ => complete = false
+            }
+        }
+        if !complete {
+            errors.push(error(Defect::Incomplete));
+            continue;
+        }
+        if outers.is_empty() {
+            errors.push(error(Defect::NoOuter));
+            continue;
+        }
+        let (outers, inners) = match (join_rings(outers), join_rings(inners)) {
+            (Some(outers), Some(inners)) => (outers, inners),
+            _ => {
+                errors.push(error(Defect::OpenRing));
+                continue;
+            }
+        };
+        let rings = |rings: Vec<Vec<i64>>| rings.iter().map(|ids| ring(ids)).collect::<Option<Vec<Ring>>>();
+        let (outers, inners) = match (rings(outers), rings(inners)) {
+            (Some(outers), Some(inners)) => (outers, inners),
+            _ => {
+                errors.push(error(Defect::Incomplete));
+                continue;
+            }
+        };
+
+        let mut polygons: Vec<Polygon> = outers.into_iter().map(|outer| Polygon { outer, inners: Vec::new() }).collect();
+        let mut inside = true;
+        for inner in inners {
+            let smallest = polygons.iter().enumerate().
+                filter(|(_, polygon)| contains_point(&polygon.outer, inner[0])).
+                min_by(|(_, a), (_, b)| ring_area(&a.outer).abs().partial_cmp(&ring_area(&b.outer).abs()).unwrap()).
+                map(|(i, _)| i);
+            match smallest {
+                Some(i) => polygons[i].inners.push(inner),
+                None => inside = false
+            }
+        }
+        if !inside {
+            errors.push(error(Defect::InnerOutside));
+            continue;
+        }
+        areas.push(Area { id: AreaId::Relation(relation.id), tags: named(&relation.tags, &relation.name), polygons });
+    }
+    (areas, errors)
+}
+
+// Tags of an area, with the name that entities keep apart put back in
+fn named(tags: &Tags, name: &Option<String>) -> Tags {
+    let mut tags = tags.clone();
+    if let Some(ref name) = *name {
+        tags.insert("name", name);
+    }
+    tags
+}
+
+#[derive(Serialize)]
+struct FeatureCollection<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature<'a>>
+}
+
+#[derive(Serialize)]
+struct Feature<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+    properties: BTreeMap<&'a str, &'a str>,
+    geometry: Geometry
+}
+
+#[derive(Serialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<Vec<Vec<[f64; 2]>>>
+}
+
+/// Writes areas as GeoJSON, one `MultiPolygon` feature per area with its
+/// tags as properties and `way/<id>` or `relation/<id>` as feature id.
+pub fn write_geojson<W: Write>(writer: W, areas: &[Area]) -> Result<()> {
+    let features = areas.iter().map(|area| Feature {
+        kind: "Feature",
+        id: match area.id {
+            AreaId::Way(id) => format!("way/{}", id),
+            AreaId::Relation(id) => format!("relation/{}", id)
+        },
+        properties: area.tags.iter().collect(),
+        geometry: Geometry {
+            kind: "MultiPolygon",
+            coordinates: area.polygons.iter().map(|polygon| {
+                Some(&polygon.outer).into_iter().chain(&polygon.inners).
+                    map(|ring| ring.iter().map(|&(lat, lon)| [lon, lat]).collect()).
+                    collect()
+            }).collect()
+        }
+    }).collect();
+    Ok(serde_json::to_writer(writer, &FeatureCollection { kind: "FeatureCollection", features })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use serde_json::Value;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64], tags: &[(&str, &str)]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    fn relation(id: i64, members: &[(i64, &str)], tags: &[(&str, &str)]) -> Relation {
+        Relation {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            members: members.iter().map(|&(id, role)| Member { kind: "way".to_string(), id, role: role.to_string() }).collect(),
+            name: Some("Centennial Park".to_string()), tags: tags.iter().cloned().collect()
+        }
+    }
+
+    // A park from (0, 0) to (1, 1) with its outline split in two ways and a
+    // pond from (0.4, 0.4) to (0.6, 0.6) cut out of it, and a building
+    fn create_map() -> Map {
+        Map {
+            version: "0.6".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 2.0, maxlon: 2.0 },
+            nodes: vec![
+                node(1, 0.0, 0.0), node(2, 0.0, 1.0), node(3, 1.0, 1.0), node(4, 1.0, 0.0),
+                node(5, 0.4, 0.4), node(6, 0.4, 0.6), node(7, 0.6, 0.6), node(8, 0.6, 0.4),
+                node(9, 1.5, 1.5), node(10, 1.5, 1.6), node(11, 1.6, 1.6)
+            ],
+            ways: vec![
+                way(1, &[1, 2, 3], &[]),
+                way(2, &[1, 4, 3], &[]),
+                way(3, &[5, 6, 7, 8, 5], &[("natural", "water")]),
+                way(4, &[9, 10, 11, 9], &[("building", "yes")]),
+                way(5, &[9, 10, 11, 9], &[("highway", "service")]),
+                way(6, &[1, 2], &[])
+            ],
+            relations: vec![
+                relation(10, &[(1, "outer"), (2, "outer"), (3, "inner")], &[("type", "multipolygon"), ("leisure", "park")]),
+                relation(11, &[(6, "outer")], &[("type", "multipolygon")]),
+                relation(12, &[(4, "outer"), (3, "inner")], &[("type", "multipolygon")]),
+                relation(13, &[(1, "outer"), (99, "outer")], &[("type", "multipolygon")]),
+                relation(14, &[(1, "outer"), (2, "outer")], &[("type", "route")])
+            ]
+        }
+    }
+
+    #[test]
+    fn assembles_ways_and_multipolygons() {
+        let map = create_map();
+        let (areas, errors) = assemble(&map);
+        assert_eq!(vec![AreaId::Way(3), AreaId::Way(4), AreaId::Relation(10)],
+            areas.iter().map(|area| area.id).collect::<Vec<AreaId>>());
+        assert_eq!(vec![
+            AssemblyError { relation_id: 11, defect: Defect::OpenRing },
+            AssemblyError { relation_id: 12, defect: Defect::InnerOutside },
+            AssemblyError { relation_id: 13, defect: Defect::Incomplete }
+        ], errors);
+        assert_eq!("multipolygon relation 11 has rings that aren't closed", errors[0].to_string());
+
+        let park = &areas[2];
+        assert_eq!(Some("Centennial Park"), park.tags.get("name"));
+        assert_eq!(1, park.polygons.len());
+        assert_eq!(5, park.polygons[0].outer.len());
+        assert_eq!(park.polygons[0].outer[0], park.polygons[0].outer[4]);
+        assert_eq!(1, park.polygons[0].inners.len());
+        assert!(park.contains((0.2, 0.2)));
+        assert!(!park.contains((0.5, 0.5)));
+        assert!(!park.contains((1.5, 0.5)));
+        assert!(areas[0].contains((0.5, 0.5)));
+    }
+
+    #[test]
+    fn writes_geojson() {
+        let (areas, _) = assemble(&create_map());
+        let mut buf = Vec::new();
+        write_geojson(&mut buf, &areas).unwrap();
+        let value: Value = serde_json::from_slice(&buf).unwrap();
+        let park = &value["features"][2];
+        assert_eq!("relation/10", park["id"]);
+        assert_eq!("park", park["properties"]["leisure"]);
+        assert_eq!("MultiPolygon", park["geometry"]["type"]);
+        assert_eq!(2, park["geometry"]["coordinates"][0].as_array().unwrap().len());
+        assert_eq!(0.6, park["geometry"]["coordinates"][0][1][1][0]);
+    }
+}
@@ -0,0 +1,364 @@
+// This module computes a static traffic assignment: how a demand spreads over
+// the road network once every driver takes the route that is fastest given
+// the congestion caused by everyone else (Wardrop's user equilibrium). Edge
+// travel times follow the BPR volume-delay function and the equilibrium is
+// approached with the Frank-Wolfe algorithm.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use demand::OdMatrix;
+use entities::{Map, Way};
+use graph::{Graph, RoutingGraph};
+use loader::Result;
+use pathfinder::shortest_path_tree;
+use roads;
+
+/// Bureau of Public Roads volume-delay function:
+/// `t = t0 * (1 + alpha * (flow / capacity) ^ beta)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bpr {
+    pub alpha: f64,
+    pub beta: f64
+}
+
+impl Default for Bpr {
+    fn default() -> Bpr {
+        Bpr { alpha: 0.15, beta: 4.0 }
+    }
+}
+
+impl Bpr {
+    pub fn travel_time(&self, free_flow_time: f64, flow: f64, capacity: f64) -> f64 {
+        free_flow_time * (1.0 + self.alpha * (flow / capacity).powf(self.beta))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bpr: Bpr,
+    /// Length in hours of the period the demand is spread over; capacities
+    /// are per hour.
+    pub period: f64,
+    pub max_iterations: usize,
+    /// Relative gap at which the assignment counts as converged.
+    pub relative_gap: f64
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { bpr: Bpr::default(), period: 1.0, max_iterations: 100, relative_gap: 1e-4 }
+    }
+}
+
+/// Result of an assignment, indexed like `Graph::edges`.
+#[derive(Debug, Clone)]
+pub struct Equilibrium {
+    /// Vehicles per period on each edge.
+    pub flows: Vec<f64>,
+    /// Travel time in seconds on each edge at those flows.
+    pub times: Vec<f64>,
+    pub iterations: usize,
+    /// Relative gap between the total travel time and the total travel time
+    /// if everyone took the currently fastest route; zero at equilibrium.
+    pub relative_gap: f64,
+    /// Trips that had no route, or had an unknown origin or destination.
+    pub unassigned: f64
+}
+
+/// Flow along one direction of a way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WayFlow {
+    pub way_id: i64,
+    /// Whether the flow runs in the order of the way's nodes.
+    pub forward: bool,
+    /// Largest flow on any segment of the way, in vehicles per period.
+    pub flow: f64,
+    /// Capacity in vehicles per period.
+    pub capacity: f64,
+    /// Time in seconds to drive the whole way on an empty road.
+    pub free_flow_time: f64,
+    /// Time in seconds to drive the whole way at the assigned flows.
+    pub travel_time: f64
+}
+
+impl WayFlow {
+    /// Volume/capacity ratio; values near or above 1 are where jams form.
+    pub fn saturation(&self) -> f64 {
+        self.flow / self.capacity
+    }
+}
+
+pub struct Assignment<'a> {
+    pub config: Config,
+    graph: Graph<'a>,
+    free_flow_times: Vec<f64>,
+    capacities: Vec<f64>
+}
+
+impl<'a> Assignment<'a> {
+    /// Prepares the graph of `map` with free-flow times from the speed limits
+    /// and capacities from the `highway` class and `lanes` of every way.
+    pub fn new(map: &'a Map, config: Config) -> Assignment<'a> {
+        let graph = Graph::new(map);
+        let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+        let free_flow_times = graph.edges.iter().
+            map(|edge| edge.distance / (roads::max_speed(ways[&edge.way_id]) / 3.6)).
+            collect();
+        let capacities = graph.edges.iter().
+            map(|edge| roads::capacity(ways[&edge.way_id], edge.forward) * config.period).
+            collect();
+        Assignment { config, graph, free_flow_times, capacities }
+    }
+
+    pub fn graph(&self) -> &Graph<'a> {
+        &self.graph
+    }
+
+    /// Travel times of all edges at the given flows.
+    pub fn travel_times(&self, flows: &[f64]) -> Vec<f64> {
+        flows.iter().enumerate().
+            map(|(e, &flow)| self.config.bpr.travel_time(self.free_flow_times[e], flow, self.capacities[e])).
+            collect()
+    }
+
+    /// Assigns the trips of `demand`, taken to happen within
+    /// `Config::period`, with Frank-Wolfe iterations until the relative gap
+    /// drops below `Config::relative_gap`.
+    pub fn run(&self, demand: &OdMatrix) -> Equilibrium {
+        // Group the demand by origin so each iteration needs one shortest
+        // path tree per origin
+        let mut unassigned = 0.0;
+        let mut by_origin: BTreeMap<usize, Vec<(usize, f64)>> = BTreeMap::new();
+        for &(origin, destination, trips) in &demand.pairs {
+            match (self.graph.node_index(origin), self.graph.node_index(destination)) {
+                (Some(o), Some(d)) if o != d => by_origin.entry(o).or_default().push((d, trips)),
+                (Some(_), Some(_)) => {},
+                _ => unassigned += trips
+            }
+        }
+
+        let (mut flows, unrouted) = self.all_or_nothing(&by_origin, &self.free_flow_times);
+        unassigned += unrouted;
+        let mut times = self.travel_times(&flows);
+        let mut relative_gap = f64::INFINITY;
+        let mut iterations = 0;
+        while iterations < self.config.max_iterations {
+            let (target, _) = self.all_or_nothing(&by_origin, &times);
+            let current: f64 = flows.iter().zip(&times).map(|(x, t)| x * t).sum();
+            let best: f64 = target.iter().zip(&times).map(|(y, t)| y * t).sum();
+            relative_gap = if current > 0.0 { (current - best) / current } else { 0.0 };
+            if relative_gap < self.config.relative_gap {
+                break;
+            }
+
+            let step = self.line_search(&flows, &target);
+            for (x, y) in flows.iter_mut().zip(&target) {
+                *x += step * (y - *x);
+            }
+            times = self.travel_times(&flows);
+            iterations += 1;
+        }
+
+        Equilibrium { flows, times, iterations, relative_gap, unassigned }
+    }
+
+    // Loads every trip onto the fastest route at the given edge times.
+    // Returns the edge flows and the number of trips without a route.
+    fn all_or_nothing(&self, by_origin: &BTreeMap<usize, Vec<(usize, f64)>>, times: &[f64]) -> (Vec<f64>, f64) {
+        let mut flows = vec![0.0; self.graph.edges.len()];
+        let mut unrouted = 0.0;
+        for (&origin, destinations) in by_origin {
+            let tree = shortest_path_tree(&self.graph, origin, |e| times[e]);
+            for &(destination, trips) in destinations {
+                match tree.edges_to(&self.graph, destination) {
+                    Some(edges) => for e in edges {
+                        flows[e] += trips;
+                    },
+                    None => unrouted += trips
+                }
+            }
+        }
+        (flows, unrouted)
+    }
+
+    // Finds the step towards `target` that minimizes the Beckmann objective,
+    // by bisection on its derivative.
+    fn line_search(&self, flows: &[f64], target: &[f64]) -> f64 {
+        let derivative = |step: f64| -> f64 {
+            flows.iter().zip(target).enumerate().map(|(e, (&x, &y))| {
+                let flow = x + step * (y - x);
+                (y - x) * self.config.bpr.travel_time(self.free_flow_times[e], flow, self.capacities[e])
+            }).sum()
+        };
+        if derivative(1.0) <= 0.0 {
+            return 1.0;
+        }
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..40 {
+            let middle = (low + high) / 2.0;
+            if derivative(middle) > 0.0 {
+                high = middle;
+            } else {
+                low = middle;
+            }
+        }
+        (low + high) / 2.0
+    }
+
+    /// Sums up an equilibrium per way and direction, ordered by way id.
+    pub fn way_flows(&self, equilibrium: &Equilibrium) -> Vec<WayFlow> {
+        let mut ways: BTreeMap<(i64, bool), WayFlow> = BTreeMap::new();
+        for (e, edge) in self.graph.edges.iter().enumerate() {
+            let way = ways.entry((edge.way_id, !edge.forward)).or_insert(WayFlow {
+                way_id: edge.way_id,
+                forward: edge.forward,
+                flow: 0.0,
+                capacity: f64::INFINITY,
+                free_flow_time: 0.0,
+                travel_time: 0.0
+            });
+            way.flow = way.flow.max(equilibrium.flows[e]);
+            way.capacity = way.capacity.min(self.capacities[e]);
+            way.free_flow_time += self.free_flow_times[e];
+            way.travel_time += equilibrium.times[e];
+        }
+        ways.into_values().collect()
+    }
+}
+
+/// Writes way flows as CSV, one line per way and direction.
+pub fn write_csv<W: Write>(mut writer: W, flows: &[WayFlow]) -> Result<()> {
+    writeln!(writer, "way_id,forward,flow,capacity,saturation,free_flow_time,travel_time")?;
+    for way in flows {
+        writeln!(writer, "{},{},{:.1},{:.1},{:.3},{:.1},{:.1}",
+            way.way_id, way.forward, way.flow, way.capacity, way.saturation(),
+            way.free_flow_time, way.travel_time)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64], tags: &[(&str, &str)]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    // Two routes from node 1 to node 3: a short residential street through
+    // node 2 and a longer primary road through node 4
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.01, maxlon: 0.003 },
+            nodes: vec![
+                node(1, 0.0, 0.0), node(2, 0.005, 0.0), node(3, 0.01, 0.0),
+                node(4, 0.005, 0.003), node(5, 0.02, 0.0)
+            ],
+            ways: vec![
+                way(1, &[1, 2, 3], &[("highway", "residential")]),
+                way(2, &[1, 4, 3], &[("highway", "primary")])
+            ],
+            relations: Vec::new()
+        }
+    }
+
+    fn route_time(assignment: &Assignment, equilibrium: &Equilibrium, via: i64) -> f64 {
+        let graph = assignment.graph();
+        let path = [1, via, 3];
+        path.windows(2).map(|pair| {
+            let from = graph.node_index(pair[0]).unwrap();
+            let to = graph.node_index(pair[1]).unwrap();
+            let e = graph.outgoing(from).iter().cloned().find(|&e| graph.edges[e].to == to).unwrap();
+            equilibrium.times[e]
+        }).sum()
+    }
+
+    #[test]
+    fn bpr_travel_time() {
+        let bpr = Bpr::default();
+        assert_eq!(10.0, bpr.travel_time(10.0, 0.0, 1000.0));
+        assert!((bpr.travel_time(10.0, 1000.0, 1000.0) - 11.5).abs() < 1e-9);
+        assert!((bpr.travel_time(10.0, 2000.0, 1000.0) - 34.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn light_demand_takes_fastest_route() {
+        let map = create_map();
+        let assignment = Assignment::new(&map, Config::default());
+        let mut demand = OdMatrix::new();
+        demand.add(1, 3, 10.0);
+        let equilibrium = assignment.run(&demand);
+
+        let flows = assignment.way_flows(&equilibrium);
+        let flow = |way_id, forward| flows.iter().
+            find(|w| w.way_id == way_id && w.forward == forward).unwrap().flow;
+        // The primary road is longer but faster
+        assert!(route_time(&assignment, &equilibrium, 4) < route_time(&assignment, &equilibrium, 2));
+        assert!((flow(2, true) - 10.0).abs() < 1e-6);
+        assert_eq!(0.0, flow(1, true));
+        assert_eq!(0.0, flow(2, false));
+    }
+
+    #[test]
+    fn heavy_demand_reaches_equilibrium() {
+        let map = create_map();
+        let assignment = Assignment::new(&map, Config::default());
+        let mut demand = OdMatrix::new();
+        demand.add(1, 3, 3000.0);
+        let equilibrium = assignment.run(&demand);
+
+        assert!(equilibrium.relative_gap < 1e-4);
+        let flows = assignment.way_flows(&equilibrium);
+        let flow = |way_id| flows.iter().find(|w| w.way_id == way_id && w.forward).unwrap().flow;
+        assert!(flow(1) > 100.0 && flow(2) > 100.0);
+        assert!((flow(1) + flow(2) - 3000.0).abs() < 1e-6);
+
+        // Both routes are used, so they take the same time
+        let via_street = route_time(&assignment, &equilibrium, 2);
+        let via_primary = route_time(&assignment, &equilibrium, 4);
+        assert!((via_street - via_primary).abs() / via_primary < 0.01);
+        assert!(flows.iter().any(|w| w.saturation() > 1.0));
+    }
+
+    #[test]
+    fn unassigned_demand() {
+        let map = create_map();
+        let assignment = Assignment::new(&map, Config::default());
+        let mut demand = OdMatrix::new();
+        demand.add(1, 5, 7.0);
+        demand.add(1, 99, 2.0);
+        demand.add(1, 1, 5.0);
+        let equilibrium = assignment.run(&demand);
+        assert_eq!(9.0, equilibrium.unassigned);
+        assert!(equilibrium.flows.iter().all(|&flow| flow == 0.0));
+    }
+
+    #[test]
+    fn way_flows_csv() {
+        let flows = vec![WayFlow {
+            way_id: 7, forward: false, flow: 500.0, capacity: 1000.0,
+            free_flow_time: 30.0, travel_time: 30.28125
+        }];
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &flows).unwrap();
+        assert_eq!(
+            "way_id,forward,flow,capacity,saturation,free_flow_time,travel_time\n\
+             7,false,500.0,1000.0,0.500,30.0,30.3\n",
+            String::from_utf8(csv).unwrap());
+    }
+}
@@ -0,0 +1,210 @@
+// This module describes what a route should stay away from: specific ways
+// and nodes, areas given as polygons, and classes of roads recognized by
+// their tags. It turns these into a filter over the edges of a graph for the
+// pathfinder.
+
+use std::collections::{HashMap, HashSet};
+use entities::{Map, Node, Way};
+use graph::Graph;
+use loader::{Error, Result};
+
+/// Things a route must not use.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Avoid {
+    pub ways: HashSet<i64>,
+    pub nodes: HashSet<i64>,
+    /// Areas as rings of `(lat, lon)` points; routes don't pass through any
+    /// node inside them.
+    pub polygons: Vec<Vec<(f64, f64)>>,
+    /// `(key, value)` tag filters. Ways with a matching tag are avoided; a
+    /// filter without a value matches any value except `no`.
+    pub tags: Vec<(String, Option<String>)>
+}
+
+impl Avoid {
+    pub fn new() -> Avoid {
+        Avoid::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ways.is_empty() && self.nodes.is_empty() && self.polygons.is_empty() && self.tags.is_empty()
+    }
+
+    /// Avoids a class of roads by name: `tolls`, `ferries`, `motorways`,
+    /// `tunnels`, `bridges` or `unpaved`.
+    pub fn add_class(&mut self, class: &str) -> Result<()> {
+        let filters: &[(&str, Option<&str>)] = match class {
+            "tolls" => &[("toll", Some("yes"))],
+            "ferries" => &[("route", Some("ferry"))],
+            "motorways" => &[("highway", Some("motorway")), ("highway", Some("motorway_link"))],
+            "tunnels" => &[("tunnel", None)],
+            "bridges" => &[("bridge", None)],
+            "unpaved" => &[("surface", Some("unpaved")), ("surface", Some("gravel")), ("surface", Some("dirt"))],
+            _ => return Err(Error::Parse(format!("unknown road class {:?}", class)))
+        };
+        for &(key, value) in filters {
+            self.add_tag(key, value);
+        }
+        Ok(())
+    }
+
+    pub fn add_tag(&mut self, key: &str, value: Option<&str>) {
+        self.tags.push((key.to_string(), value.map(|value| value.to_string())));
+    }
+
+    /// Adds a comma-separated list of things to avoid, as given on the
+    /// command line. Items are class names (see `add_class`), `way:<id>`,
+    /// `node:<id>`, `key=value` or `tag:<key>` for any value of a key. Other
+    /// words are rejected, so a misspelt class isn't taken for a tag key.
+    pub fn add_list(&mut self, list: &str) -> Result<()> {
+        for item in list.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+            if let Some(id) = item.strip_prefix("way:") {
+                self.ways.insert(parse_id(id)?);
+            } else if let Some(id) = item.strip_prefix("node:") {
+                self.nodes.insert(parse_id(id)?);
+            } else if let Some(key) = item.strip_prefix("tag:") {
+                self.add_tag(parse_key(key)?, None);
+            } else if let Some(equals) = item.find('=') {
+                self.add_tag(parse_key(&item[..equals])?, Some(&item[equals + 1..]));
+            } else {
+                self.add_class(item)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a polygon given as `lat,lon;lat,lon;...`.
+    pub fn add_polygon_str(&mut self, polygon: &str) -> Result<()> {
+        let error = || Error::Parse(format!("invalid polygon {:?}", polygon));
+        let points = polygon.split(';').map(|point| {
+            let mut coordinates = point.split(',').map(|c| c.trim().parse::<f64>());
+            match (coordinates.next(), coordinates.next(), coordinates.next()) {
+                (Some(Ok(lat)), Some(Ok(lon)), None) => Ok((lat, lon)),
+                _ => Err(error())
+            }
+        }).collect::<Result<Vec<(f64, f64)>>>()?;
+        if points.len() < 3 {
+            return Err(error());
+        }
+        self.polygons.push(points);
+        Ok(())
+    }
+
+    pub fn avoids_way(&self, way: &Way) -> bool {
+        self.ways.contains(&way.id) || self.tags.iter().any(|(key, value)| {
+            match (way.tags.get(key), value) {
+                (Some(actual), Some(value)) => actual == value,
+                (Some(actual), None) => actual != "no",
+                (None, _) => false
+            }
+        })
+    }
+
+    pub fn avoids_node(&self, node: &Node) -> bool {
+        self.nodes.contains(&node.id) ||
+            self.polygons.iter().any(|polygon| contains_point(polygon, node.position()))
+    }
+
+    /// Returns whether each edge of `graph`, which must have been built from
+    /// `map`, may be used.
+    pub fn edge_filter(&self, map: &Map, graph: &Graph) -> Vec<bool> {
+        let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+        let avoided_nodes: Vec<bool> = graph.nodes.iter().map(|node| self.avoids_node(node)).collect();
+        graph.edges.iter().map(|edge| {
+            !avoided_nodes[edge.from] && !avoided_nodes[edge.to] &&
+                !ways.get(&edge.way_id).is_some_and(|way| self.avoids_way(way))
+        }).collect()
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64> {
+    id.parse().map_err(|_| Error::Parse(format!("invalid id {:?}", id)))
+}
+
+fn parse_key(key: &str) -> Result<&str> {
+    if key.is_empty() {
+        return Err(Error::Parse("empty tag key".to_string()));
+    }
+    Ok(key)
+}
+
+/// Whether a point lies inside a polygon of `(lat, lon)` points, by ray
+/// casting. The polygon may or may not repeat its first point at the end.
+pub fn contains_point(polygon: &[(f64, f64)], point: (f64, f64)) -> bool {
+    let (y, x) = point;
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for i in 0..polygon.len() {
+        let (yi, xi) = polygon[i];
+        let (yj, xj) = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tags::Tags;
+
+    fn way(id: i64, tags: &[(&str, &str)]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: Vec::new(), name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    #[test]
+    fn avoid_classes_and_tags() {
+        let mut avoid = Avoid::new();
+        assert!(avoid.is_empty());
+        avoid.add_list("tolls, ferries,tunnels,highway=trunk,way:7").unwrap();
+        assert!(avoid.avoids_way(&way(1, &[("toll", "yes")])));
+        assert!(avoid.avoids_way(&way(1, &[("route", "ferry")])));
+        assert!(avoid.avoids_way(&way(1, &[("tunnel", "culvert")])));
+        assert!(!avoid.avoids_way(&way(1, &[("tunnel", "no")])));
+        assert!(avoid.avoids_way(&way(1, &[("highway", "trunk")])));
+        assert!(!avoid.avoids_way(&way(1, &[("highway", "primary"), ("toll", "no")])));
+        assert!(avoid.avoids_way(&way(7, &[])));
+
+        avoid.add_list("tag:lit").unwrap();
+        assert!(avoid.avoids_way(&way(1, &[("lit", "yes")])));
+        assert!(avoid.add_list("way:x").is_err());
+        assert!(avoid.add_list("feries").is_err());
+        assert!(avoid.add_list("=yes").is_err());
+        assert!(avoid.add_list("tag:").is_err());
+        assert!(avoid.add_class("hills").is_err());
+    }
+
+    #[test]
+    fn avoid_nodes_and_polygons() {
+        let mut avoid = Avoid::new();
+        avoid.add_list("node:3").unwrap();
+        avoid.add_polygon_str("0,0; 0,1; 1,1; 1,0").unwrap();
+        assert!(avoid.avoids_node(&node(3, 5.0, 5.0)));
+        assert!(avoid.avoids_node(&node(1, 0.5, 0.5)));
+        assert!(!avoid.avoids_node(&node(1, 1.5, 0.5)));
+
+        assert!(avoid.add_polygon_str("0,0;1,1").is_err());
+        assert!(avoid.add_polygon_str("0,0;1,1;2").is_err());
+    }
+
+    #[test]
+    fn point_in_polygon() {
+        let triangle = [(0.0, 0.0), (0.0, 2.0), (2.0, 0.0), (0.0, 0.0)];
+        assert!(contains_point(&triangle, (0.5, 0.5)));
+        assert!(!contains_point(&triangle, (1.5, 1.5)));
+        assert!(!contains_point(&triangle, (-0.5, 0.5)));
+        assert!(!contains_point(&[], (0.0, 0.0)));
+    }
+}
@@ -0,0 +1,383 @@
+// This module manages the binary map cache. Every cache file starts with a
+// header recording the format version, the source file it was built from and
+// the options used to build it, so stale caches can be detected and rebuilt.
+// The header also holds the tombstones of elements deleted by diffs applied
+// with `changes::update_bin`.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use bincode;
+use changes::Tombstones;
+use compression::Compression;
+use entities::Map;
+use loader::{self, Error, Result};
+
+/// Magic bytes at the very beginning of every cache file.
+pub const MAGIC: [u8; 8] = *b"JAMVILLE";
+
+/// Version of the on-disk layout. Bump this whenever the structures in
+/// `entities` change in a way that affects serialization.
+pub const FORMAT_VERSION: u32 = 5;
+
+/// Options that change the contents of the cache. A cache built with
+/// different options than requested is considered stale.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildOptions {
+    /// Only keep ways tagged `highway`, along with the nodes they reference.
+    pub highways_only: bool,
+    /// Compression of the map data following the header. The header itself
+    /// is never compressed, so freshness checks stay cheap.
+    pub compression: Compression
+}
+
+impl BuildOptions {
+    pub fn apply(&self, map: &mut Map) {
+        if self.highways_only {
+            map.ways.retain(|way| way.tags.contains_key("highway"));
+            let mut referenced: Vec<i64> = map.ways.iter().
+                flat_map(|way| way.node_refs.iter().map(|nr| nr.id)).
+                collect();
+            referenced.sort();
+            referenced.dedup();
+            map.nodes.retain(|node| referenced.binary_search(&node.id).is_ok());
+        }
+    }
+}
+
+/// Identifies the source file a cache was built from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub path: String,
+    pub len: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// FNV-1a hash of the file contents.
+    pub hash: u64
+}
+
+impl SourceInfo {
+    pub fn from_path(path: &Path) -> io::Result<SourceInfo> {
+        let metadata = fs::metadata(path)?;
+        Ok(SourceInfo {
+            path: path.to_string_lossy().into_owned(),
+            len: metadata.len(),
+            mtime: mtime_secs(&metadata),
+            hash: hash_file(path)?
+        })
+    }
+
+    /// Checks whether the file on disk still matches. Length and modification
+    /// time are compared first; the contents are only hashed when the
+    /// modification time changed but the length did not.
+    pub fn matches(&self, path: &Path) -> io::Result<bool> {
+        let metadata = fs::metadata(path)?;
+        if metadata.len() != self.len {
+            return Ok(false);
+        }
+        if mtime_secs(&metadata) == self.mtime {
+            return Ok(true);
+        }
+        Ok(hash_file(path)? == self.hash)
+    }
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> u64 {
+    metadata.modified().ok().
+        and_then(|time| time.duration_since(UNIX_EPOCH).ok()).
+        map(|duration| duration.as_secs()).
+        unwrap_or(0)
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = [0u8; 64 * 1024];
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for byte in &buf[..n] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(hash)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub format_version: u32,
+    pub source: Option<SourceInfo>,
+    pub options: BuildOptions,
+    /// Elements deleted by the diffs applied to the cache since it was
+    /// built.
+    pub tombstones: Tombstones
+}
+
+impl Header {
+    pub fn new(source: Option<SourceInfo>, options: BuildOptions) -> Header {
+        Header { magic: MAGIC, format_version: FORMAT_VERSION, source, options, tombstones: Tombstones::default() }
+    }
+
+    /// Reads and verifies the header at the start of a cache file, leaving
+    /// the reader positioned at the map data.
+    pub fn read<R: Read>(mut reader: R) -> Result<Header> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::InvalidCache("not a jamville cache file".to_string()));
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(Error::InvalidCache(format!(
+                "cache format version {} (expected {})", version, FORMAT_VERSION)));
+        }
+        let source = bincode::deserialize_from(&mut reader)?;
+        let options = bincode::deserialize_from(&mut reader)?;
+        let tombstones = bincode::deserialize_from(&mut reader)?;
+        Ok(Header { magic, format_version: version, source, options, tombstones })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(&self.magic)?;
+        writer.write_all(&self.format_version.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &self.source)?;
+        bincode::serialize_into(&mut writer, &self.options)?;
+        bincode::serialize_into(&mut writer, &self.tombstones)?;
+        Ok(())
+    }
+
+    /// Reads the map data following the header, decompressing it as the
+    /// header's options say.
+    pub fn read_map<R: BufRead>(&self, reader: R) -> Result<Map> {
+        loader::read_map(self.options.compression.decoder(reader)?)
+    }
+
+    /// Writes the map data following the header, compressing it as the
+    /// header's options say.
+    pub fn write_map<W: Write>(&self, writer: W, map: &Map) -> Result<()> {
+        let mut encoder = self.options.compression.encoder(writer)?;
+        loader::write_map(&mut encoder, map)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Checks whether the cache can be used for `source` built with
+    /// `options`.
+    pub fn is_fresh(&self, source: &Path, options: &BuildOptions) -> bool {
+        if self.options != *options {
+            return false;
+        }
+        match self.source {
+            Some(ref info) => info.matches(source).unwrap_or(false),
+            None => false
+        }
+    }
+}
+
+/// Outcome of `load_cached`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// The cache was up to date and the map was read from it.
+    Fresh,
+    /// The cache was missing, stale or unreadable and has been rebuilt.
+    Rebuilt
+}
+
+/// Loads the map for `source`, using the cache at `cache_path` when it is up
+/// to date and rebuilding it from the source otherwise.
+pub fn load_cached<P, Q>(source: P, cache_path: Q, options: &BuildOptions) -> Result<(Map, Status)>
+    where P: AsRef<Path>, Q: AsRef<Path>
+{
+    let source = source.as_ref();
+    let cache_path = cache_path.as_ref();
+    if let Ok(file) = File::open(cache_path) {
+        let mut reader = BufReader::new(file);
+        if let Ok(header) = Header::read(&mut reader) {
+            if header.is_fresh(source, options) {
+                if let Ok(map) = header.read_map(reader) {
+                    return Ok((map, Status::Fresh));
+                }
+            }
+        }
+    }
+    let map = rebuild(source, cache_path, options)?;
+    Ok((map, Status::Rebuilt))
+}
+
+/// Opens a cache file directly. If the source it was built from is still
+/// around and has changed since, the cache is rebuilt first.
+pub fn open<P: AsRef<Path>>(cache_path: P) -> Result<(Map, Status)> {
+    let cache_path = cache_path.as_ref();
+    let mut reader = BufReader::new(File::open(cache_path)?);
+    let header = Header::read(&mut reader)?;
+    if let Some(ref info) = header.source {
+        let source = Path::new(&info.path);
+        if source.exists() && !header.is_fresh(source, &header.options) {
+            let map = rebuild(source, cache_path, &header.options)?;
+            return Ok((map, Status::Rebuilt));
+        }
+    }
+    Ok((header.read_map(reader)?, Status::Fresh))
+}
+
+fn rebuild(source: &Path, cache_path: &Path, options: &BuildOptions) -> Result<Map> {
+    let info = SourceInfo::from_path(source)?;
+    let mut map = loader::load(source)?;
+    options.apply(&mut map);
+
+    let mut writer = BufWriter::new(File::create(cache_path)?);
+    let header = Header::new(Some(info), options.clone());
+    header.write(&mut writer)?;
+    header.write_map(&mut writer, &map)?;
+    writer.flush()?;
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const XML: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+        <osm version="0.6" generator="test">
+            <note>test</note>
+            <meta osm_base="2018-05-14T21:45:02Z"/>
+            <bounds minlat="0.0" minlon="0.0" maxlat="1.0" maxlon="1.0"/>
+            <node id="1" lat="0.1" lon="0.1" version="1" timestamp="2018-05-01T12:00:00Z" changeset="1" uid="1" user="viking"/>
+            <node id="2" lat="0.2" lon="0.2" version="1" timestamp="2018-05-01T12:00:00Z" changeset="1" uid="1" user="viking"/>
+            <node id="3" lat="0.3" lon="0.3" version="1" timestamp="2018-05-01T12:00:00Z" changeset="1" uid="1" user="viking"/>
+            <way id="1" version="1" timestamp="2018-05-01T12:00:00Z" changeset="1" uid="1" user="viking">
+                <nd ref="1"/>
+                <nd ref="2"/>
+                <tag k="highway" v="residential"/>
+            </way>
+            <way id="2" version="1" timestamp="2018-05-01T12:00:00Z" changeset="1" uid="1" user="viking">
+                <nd ref="2"/>
+                <nd ref="3"/>
+                <tag k="building" v="yes"/>
+            </way>
+        </osm>
+    "##;
+
+    fn setup(name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("jamville-cache-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("map.xml");
+        let cache = dir.join("map.bin");
+        fs::write(&source, XML).unwrap();
+        let _ = fs::remove_file(&cache);
+        (source, cache)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header::new(None, BuildOptions { highways_only: true, ..BuildOptions::default() });
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(&MAGIC[..], &buf[..8]);
+        assert_eq!(header, Header::read(&buf[..]).unwrap());
+    }
+
+    #[test]
+    fn header_rejects_bad_magic() {
+        match Header::read(&b"NOTJAMVILLE-AT-ALL"[..]) {
+            Err(Error::InvalidCache(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn header_rejects_other_version() {
+        let mut buf = Vec::new();
+        Header::new(None, BuildOptions::default()).write(&mut buf).unwrap();
+        buf[8] = buf[8].wrapping_add(1);
+        match Header::read(&buf[..]) {
+            Err(Error::InvalidCache(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn load_cached_builds_then_reuses() {
+        let (source, cache) = setup("reuse");
+        let options = BuildOptions::default();
+
+        let (map, status) = load_cached(&source, &cache, &options).unwrap();
+        assert_eq!(Status::Rebuilt, status);
+        assert_eq!(3, map.nodes.len());
+
+        let (map, status) = load_cached(&source, &cache, &options).unwrap();
+        assert_eq!(Status::Fresh, status);
+        assert_eq!(3, map.nodes.len());
+    }
+
+    #[test]
+    fn load_cached_rebuilds_when_source_changes() {
+        let (source, cache) = setup("stale");
+        let options = BuildOptions::default();
+        load_cached(&source, &cache, &options).unwrap();
+
+        fs::write(&source, XML.replace(r#"<node id="3""#, r#"<node id="4""#) + " ").unwrap();
+        let (map, status) = load_cached(&source, &cache, &options).unwrap();
+        assert_eq!(Status::Rebuilt, status);
+        assert!(map.find_node(4).is_some());
+    }
+
+    #[test]
+    fn load_cached_rebuilds_when_options_change() {
+        let (source, cache) = setup("options");
+        load_cached(&source, &cache, &BuildOptions::default()).unwrap();
+
+        let options = BuildOptions { highways_only: true, ..BuildOptions::default() };
+        let (map, status) = load_cached(&source, &cache, &options).unwrap();
+        assert_eq!(Status::Rebuilt, status);
+        assert_eq!(1, map.ways.len());
+        assert_eq!(2, map.nodes.len());
+    }
+
+    #[test]
+    fn load_cached_compresses_map_data() {
+        let (source, cache) = setup("compressed");
+        let options = BuildOptions { compression: Compression::Zstd, ..BuildOptions::default() };
+        load_cached(&source, &cache, &options).unwrap();
+
+        let data = fs::read(&cache).unwrap();
+        let mut header = Vec::new();
+        Header::read(&data[..]).unwrap().write(&mut header).unwrap();
+        assert_eq!(Compression::Zstd, Compression::from_magic(&data[header.len()..]));
+
+        let (map, status) = load_cached(&source, &cache, &options).unwrap();
+        assert_eq!(Status::Fresh, status);
+        assert_eq!(3, map.nodes.len());
+        assert_eq!(3, loader::load(&cache).unwrap().nodes.len());
+    }
+
+    #[test]
+    fn load_cached_replaces_headerless_cache() {
+        let (source, cache) = setup("headerless");
+        fs::write(&cache, b"garbage from an older version").unwrap();
+        let (_, status) = load_cached(&source, &cache, &BuildOptions::default()).unwrap();
+        assert_eq!(Status::Rebuilt, status);
+    }
+
+    #[test]
+    fn open_rebuilds_from_recorded_source() {
+        let (source, cache) = setup("open");
+        load_cached(&source, &cache, &BuildOptions::default()).unwrap();
+        assert_eq!(Status::Fresh, open(&cache).unwrap().1);
+
+        fs::write(&source, XML.replace(r#"<node id="3""#, r#"<node id="4""#) + " ").unwrap();
+        let (map, status) = open(&cache).unwrap();
+        assert_eq!(Status::Rebuilt, status);
+        assert!(map.find_node(4).is_some());
+    }
+}
@@ -0,0 +1,443 @@
+// This module keeps a map up to date by applying OsmChange diffs, such as the
+// minutely, hourly or daily replication files published for OpenStreetMap.
+//
+// The `create`, `modify` and `delete` blocks of a diff are applied in the
+// order they appear. An element only replaces the one in the map if its
+// version is newer, so applying the same diff twice, or an older one after a
+// newer one, leaves the map as it is. Modifications of elements the map
+// doesn't have add them, as is usual for diffs.
+//
+// Deleted elements are gone from the map, so it can't tell by itself that an
+// older diff creating them is out of date. `Tombstones` remember the version
+// at which elements were deleted and keep such diffs from bringing them
+// back; `apply_tracked` takes them across diffs, and `update_bin` keeps them
+// in the header of the binary file. Only deletions of elements the map had
+// are remembered, so replication diffs for the whole planet don't fill them
+// up with elements outside the extract.
+//
+// A routing graph borrows the map it was built from and has to be rebuilt
+// after changes. The summary of a change lists the ways it touched, including
+// ways whose nodes were moved, retagged or deleted, so callers can tell
+// whether their graphs, overrides and profiles are affected.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use serde_xml_rs;
+use cache::Header;
+use entities::{Map, Node, Relation, Way};
+use loader::Result;
+use osm;
+
+/// What applying a change did.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Summary {
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    /// Changes left out because the map already had the same or a newer
+    /// version of the element, or didn't have the element to delete.
+    pub skipped: usize,
+    /// Ids of the ways that were created, modified or deleted, or that
+    /// reference a node that was, in ascending order.
+    pub affected_ways: Vec<i64>
+}
+
+impl Summary {
+    /// Whether routing graphs built from the map before the change need to
+    /// be rebuilt.
+    pub fn changes_routing(&self) -> bool {
+        !self.affected_ways.is_empty()
+    }
+}
+
+/// Versions at which elements were deleted, by id.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tombstones {
+    pub nodes: HashMap<i64, u16>,
+    pub ways: HashMap<i64, u16>,
+    pub relations: HashMap<i64, u16>
+}
+
+impl Tombstones {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.ways.is_empty() && self.relations.is_empty()
+    }
+}
+
+trait Element {
+    fn id(&self) -> i64;
+    fn version(&self) -> u16;
+}
+
+impl Element for Node {
+    fn id(&self) -> i64 { self.id }
+    fn version(&self) -> u16 { self.version }
+}
+
+impl Element for Way {
+    fn id(&self) -> i64 { self.id }
+    fn version(&self) -> u16 { self.version }
+}
+
+impl Element for Relation {
+    fn id(&self) -> i64 { self.id }
+    fn version(&self) -> u16 { self.version }
+}
+
+// Elements of one kind, indexed by id. Deleted elements stay in place until
+// `finish`, so the others keep their order.
+struct Table<'m, T: 'm> {
+    items: &'m mut Vec<T>,
+    index: HashMap<i64, usize>,
+    deleted: HashSet<i64>,
+    tombstones: &'m mut HashMap<i64, u16>
+}
+
+enum Outcome {
+    Created,
+    Modified,
+    Deleted,
+    Skipped
+}
+
+impl<'m, T: Element> Table<'m, T> {
+    fn new(items: &'m mut Vec<T>, tombstones: &'m mut HashMap<i64, u16>) -> Table<'m, T> {
+        let index = items.iter().enumerate().map(|(i, item)| (item.id(), i)).collect();
+        Table { items, index, deleted: HashSet::new(), tombstones }
+    }
+
+    fn upsert(&mut self, item: T) -> Outcome {
+        match self.tombstones.get(&item.id()) {
+            Some(&version) if item.version() <= version => return Outcome::Skipped,
+            Some(_) => { self.tombstones.remove(&item.id()); },
+            None => {}
+        }
+        match self.index.get(&item.id()).cloned() {
+            Some(i) if item.version() <= self.items[i].version() => Outcome::Skipped,
+            Some(i) => {
+                let created = self.deleted.remove(&item.id());
+                self.items[i] = item;
+                if created { Outcome::Created } else { Outcome::Modified }
+            },
+            None => {
+                self.index.insert(item.id(), self.items.len());
+                self.items.push(item);
+                Outcome::Created
+            }
+        }
+    }
+
+    fn delete(&mut self, deleted: &osm::Deleted) -> Outcome {
+        match self.index.get(&deleted.id) {
+            Some(&i) if !self.deleted.contains(&deleted.id) && self.items[i].version() <= deleted.version => {
+                self.deleted.insert(deleted.id);
+                self.tombstones.insert(deleted.id, deleted.version);
+                Outcome::Deleted
+            },
+            _ => Outcome::Skipped
+        }
+    }
+
+    fn finish(self) {
+        let deleted = self.deleted;
+        if !deleted.is_empty() {
+            self.items.retain(|item| !deleted.contains(&item.id()));
+        }
+    }
+}
+
+impl Summary {
+    fn count(&mut self, outcome: Outcome) -> bool {
+        match outcome {
+            Outcome::Created => self.created += 1,
+            Outcome::Modified => self.modified += 1,
+            Outcome::Deleted => self.deleted += 1,
+            Outcome::Skipped => {
+                self.skipped += 1;
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses an OsmChange document.
+pub fn read_change<R: Read>(reader: R) -> Result<osm::Change> {
+    Ok(serde_xml_rs::from_reader(reader)?)
+}
+
+/// Applies a parsed OsmChange document to `map`. Deletions are only
+/// remembered within the document; use `apply_tracked` to apply several.
+pub fn apply(map: &mut Map, change: osm::Change) -> Summary {
+    apply_tracked(map, &mut Tombstones::default(), change)
+}
+
+/// Applies a parsed OsmChange document to `map`, skipping elements that
+/// `tombstones` say were deleted at the same or a newer version, and adding
+/// the deletions it makes to them.
+pub fn apply_tracked(map: &mut Map, tombstones: &mut Tombstones, change: osm::Change) -> Summary {
+    let mut summary = Summary::default();
+    let mut changed_nodes = HashSet::new();
+    let mut changed_ways = HashSet::new();
+    {
+        let mut nodes = Table::new(&mut map.nodes, &mut tombstones.nodes);
+        let mut ways = Table::new(&mut map.ways, &mut tombstones.ways);
+        let mut relations = Table::new(&mut map.relations, &mut tombstones.relations);
+        for action in change.actions {
+            match action {
+                osm::Action::Create(elements) | osm::Action::Modify(elements) => {
+                    for node in elements.nodes {
+                        let id = node.id;
+                        if summary.count(nodes.upsert(node.into())) {
+                            changed_nodes.insert(id);
+                        }
+                    }
+                    for way in elements.ways {
+                        let id = way.id;
+                        if summary.count(ways.upsert(way.into())) {
+                            changed_ways.insert(id);
+                        }
+                    }
+                    for relation in elements.relations {
+                        summary.count(relations.upsert(relation.into()));
+                    }
+                },
+                osm::Action::Delete(deletions) => {
+                    for node in &deletions.nodes {
+                        if summary.count(nodes.delete(node)) {
+                            changed_nodes.insert(node.id);
+                        }
+                    }
+                    for way in &deletions.ways {
+                        if summary.count(ways.delete(way)) {
+                            changed_ways.insert(way.id);
+                        }
+                    }
+                    for relation in &deletions.relations {
+                        summary.count(relations.delete(relation));
+                    }
+                }
+            }
+        }
+        nodes.finish();
+        ways.finish();
+        relations.finish();
+    }
+
+    if !changed_nodes.is_empty() {
+        for way in &map.ways {
+            if way.node_refs.iter().any(|node_ref| changed_nodes.contains(&node_ref.id)) {
+                changed_ways.insert(way.id);
+            }
+        }
+    }
+    summary.affected_ways = changed_ways.into_iter().collect();
+    summary.affected_ways.sort();
+    summary
+}
+
+/// Reads an OsmChange file and applies it to `map`.
+pub fn apply_file<P: AsRef<Path>>(map: &mut Map, path: P) -> Result<Summary> {
+    let change = read_change(BufReader::new(File::open(path)?))?;
+    Ok(apply(map, change))
+}
+
+/// Applies an OsmChange file to a binary map file in place. The header is
+/// kept, so a cache stays tied to its source file and is still used by
+/// `cache::load_cached` until the source changes; the cache's build options
+/// are applied to the updated map, and its tombstones are updated.
+pub fn update_bin<P: AsRef<Path>, Q: AsRef<Path>>(bin_path: P, change_path: Q) -> Result<Summary> {
+    let bin_path = bin_path.as_ref();
+    let (mut header, mut map) = {
+        let mut reader = BufReader::new(File::open(bin_path)?);
+        let header = Header::read(&mut reader)?;
+        let map = header.read_map(reader)?;
+        (header, map)
+    };
+    let change = read_change(BufReader::new(File::open(change_path)?))?;
+    let summary = apply_tracked(&mut map, &mut header.tombstones, change);
+    header.options.apply(&mut map);
+
+    // Write next to the file and move it into place, so a failure leaves the
+    // old file intact
+    let temporary = bin_path.with_extension("bin.tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        header.write(&mut writer)?;
+        header.write_map(&mut writer, &map)?;
+        writer.flush()?;
+    }
+    fs::rename(&temporary, bin_path)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use cache::{self, BuildOptions, Status};
+    use graph::Graph;
+    use loader;
+    use pathfinder::find_path_in_graph;
+
+    // A way of three nodes, like the one in `osm::tests::it_works`
+    const MAP: &str = r##"
+        <?xml version="1.0" encoding="UTF-8"?>
+        <osm version="0.6" generator="Overpass API 0.7.55 579b1eec">
+            <note>The data included in this document is from www.openstreetmap.org. The data is made available under ODbL.</note>
+            <meta osm_base="2018-05-14T21:45:02Z"/>
+            <bounds minlat="36.0800000" minlon="-87.0800000" maxlat="36.0900000" maxlon="-87.0700000"/>
+            <node id="37060116" lat="36.0872268" lon="-87.0759046" version="30" timestamp="2012-07-18T16:22:48Z" changeset="12288724" uid="722137" user="OSMF Redaction Account"/>
+            <node id="37060125" lat="36.0873400" lon="-87.0759682" version="1" timestamp="2007-08-30T23:16:11Z" changeset="277489" uid="10759" user="WJHildreth">
+              <tag k="created_by" v="JOSM"/>
+            </node>
+            <node id="37060130" lat="36.0875000" lon="-87.0761000" version="2" timestamp="2007-08-30T23:16:11Z" changeset="277489" uid="10759" user="WJHildreth"/>
+            <way id="5258226" version="5" timestamp="2011-07-28T05:24:26Z" changeset="8850994" uid="207745" user="NE2">
+                <nd ref="37060116"/>
+                <nd ref="37060125"/>
+                <nd ref="37060130"/>
+                <tag k="highway" v="motorway_link"/>
+                <tag k="oneway" v="yes"/>
+            </way>
+            <relation id="23148" version="180" timestamp="2018-05-03T20:43:54Z" changeset="58660134" uid="8214747" user="Pjones">
+                <member type="way" ref="5258226" role="forward"/>
+                <tag k="type" v="route"/>
+            </relation>
+        </osm>
+    "##;
+
+    const CHANGE: &str = r##"
+        <?xml version="1.0" encoding="UTF-8"?>
+        <osmChange version="0.6" generator="osmosis">
+            <modify>
+                <node id="37060125" lat="36.0873500" lon="-87.0759600" version="2" timestamp="2018-06-01T10:00:00Z" changeset="60000000" uid="1" user="viking">
+                    <tag k="highway" v="traffic_signals"/>
+                </node>
+                <node id="37060116" lat="36.0" lon="-87.0" version="29" timestamp="2011-01-01T00:00:00Z" changeset="1"/>
+            </modify>
+            <create>
+                <node id="5000000001" lat="36.0870000" lon="-87.0750000" version="1" timestamp="2018-06-01T10:00:00Z" changeset="60000000"/>
+                <way id="600000001" version="1" timestamp="2018-06-01T10:00:00Z" changeset="60000000">
+                    <nd ref="37060116"/>
+                    <nd ref="5000000001"/>
+                    <tag k="highway" v="service"/>
+                </way>
+            </create>
+            <modify>
+                <way id="5258226" version="6" timestamp="2018-06-01T10:00:00Z" changeset="60000000" uid="1" user="viking">
+                    <nd ref="37060116"/>
+                    <nd ref="37060125"/>
+                    <tag k="highway" v="motorway_link"/>
+                    <tag k="oneway" v="no"/>
+                </way>
+            </modify>
+            <delete>
+                <node id="37060130" version="3"/>
+                <relation id="23148" version="179"/>
+                <way id="1" version="1"/>
+            </delete>
+        </osmChange>
+    "##;
+
+    #[test]
+    fn applies_change_in_order() {
+        let mut map = loader::read_xml(MAP.trim().as_bytes()).unwrap();
+        {
+            let graph = Graph::new(&map);
+            assert_eq!(None, find_path_in_graph(&graph, 37060125, 37060116));
+        }
+
+        let change = read_change(CHANGE.trim().as_bytes()).unwrap();
+        let summary = apply(&mut map, change);
+        assert_eq!(Summary {
+            created: 2, modified: 2, deleted: 1, skipped: 3,
+            affected_ways: vec![5258226, 600000001]
+        }, summary);
+        assert!(summary.changes_routing());
+
+        // The older version of node 37060116 was skipped
+        assert_eq!(vec![37060116, 37060125, 5000000001], map.nodes.iter().map(|n| n.id).collect::<Vec<i64>>());
+        assert_eq!(36.0872268, map.nodes[0].lat);
+        assert_eq!(Some("traffic_signals"), map.nodes[1].tags.get("highway"));
+        assert_eq!(2, map.nodes[1].version);
+        assert_eq!(2, map.ways[0].node_refs.len());
+        assert_eq!(1, map.relations.len());
+
+        // The way is no longer oneway, and the new way connects to it
+        let graph = Graph::new(&map);
+        assert_eq!(Some(vec![37060125, 37060116, 5000000001]), find_path_in_graph(&graph, 37060125, 5000000001));
+    }
+
+    #[test]
+    fn applying_twice_changes_nothing() {
+        let mut map = loader::read_xml(MAP.trim().as_bytes()).unwrap();
+        apply(&mut map, read_change(CHANGE.trim().as_bytes()).unwrap());
+        let summary = apply(&mut map, read_change(CHANGE.trim().as_bytes()).unwrap());
+        assert_eq!(0, summary.created + summary.modified + summary.deleted);
+        assert!(!summary.changes_routing());
+        assert_eq!(3, map.nodes.len());
+    }
+
+    #[test]
+    fn deleted_elements_can_come_back() {
+        let mut map = loader::read_xml(MAP.trim().as_bytes()).unwrap();
+        let change = r##"<osmChange version="0.6">
+            <delete><node id="37060130" version="3"/></delete>
+            <create><node id="37060130" lat="36.1" lon="-87.1" version="4" timestamp="2018-06-01T10:00:00Z" changeset="6"/></create>
+        </osmChange>"##;
+        let summary = apply(&mut map, read_change(change.as_bytes()).unwrap());
+        assert_eq!((1, 1), (summary.deleted, summary.created));
+        assert_eq!(vec![5258226], summary.affected_ways);
+        assert_eq!(3, map.nodes.len());
+        assert_eq!(36.1, map.nodes[2].lat);
+    }
+
+    #[test]
+    fn deleted_elements_stay_deleted() {
+        let mut map = loader::read_xml(MAP.trim().as_bytes()).unwrap();
+        let mut tombstones = Tombstones::default();
+        apply_tracked(&mut map, &mut tombstones, read_change(CHANGE.trim().as_bytes()).unwrap());
+        assert_eq!(Some(&3), tombstones.nodes.get(&37060130));
+
+        // An older diff creating the node again is out of date, a newer one isn't
+        let create = |version| format!(r##"<osmChange version="0.6">
+            <create><node id="37060130" lat="36.1" lon="-87.1" version="{}" timestamp="2018-06-01T10:00:00Z" changeset="6"/></create>
+        </osmChange>"##, version);
+        let summary = apply_tracked(&mut map, &mut tombstones, read_change(create(2).as_bytes()).unwrap());
+        assert_eq!((0, 1), (summary.created, summary.skipped));
+        assert_eq!(3, map.nodes.len());
+        let summary = apply_tracked(&mut map, &mut tombstones, read_change(create(4).as_bytes()).unwrap());
+        assert_eq!(1, summary.created);
+        assert_eq!(4, map.nodes.len());
+        assert!(tombstones.is_empty());
+    }
+
+    #[test]
+    fn update_bin_keeps_cache_fresh() {
+        let dir = env::temp_dir().join("jamville-changes-update");
+        fs::create_dir_all(&dir).unwrap();
+        let (source, bin, diff) = (dir.join("map.xml"), dir.join("map.bin"), dir.join("change.osc"));
+        fs::write(&source, MAP.trim()).unwrap();
+        fs::write(&diff, CHANGE.trim()).unwrap();
+        let _ = fs::remove_file(&bin);
+        let options = BuildOptions::default();
+        cache::load_cached(&source, &bin, &options).unwrap();
+
+        let summary = update_bin(&bin, &diff).unwrap();
+        assert_eq!(vec![5258226, 600000001], summary.affected_ways);
+        let (map, status) = cache::load_cached(&source, &bin, &options).unwrap();
+        assert_eq!(Status::Fresh, status);
+        assert_eq!(2, map.ways.len());
+        assert_eq!(2, map.nodes[1].version);
+
+        // The deletions are kept in the header
+        let header = Header::read(File::open(&bin).unwrap()).unwrap();
+        assert_eq!(Some(&3), header.tombstones.nodes.get(&37060130));
+        fs::write(&diff, r##"<osmChange version="0.6">
+            <create><node id="37060130" lat="36.1" lon="-87.1" version="2" timestamp="2018-06-01T10:00:00Z" changeset="6"/></create>
+        </osmChange>"##).unwrap();
+        assert_eq!(1, update_bin(&bin, &diff).unwrap().skipped);
+    }
+}
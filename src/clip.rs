@@ -0,0 +1,343 @@
+// This module cuts extracts out of a map, the way `osmium extract` does with
+// its `complete_ways` strategy. Every node inside the region is kept, and so
+// is every way with at least one node inside, together with all of its
+// nodes even where they lie outside, so ways are never cut short. Relations
+// are kept if they have a kept member, or a kept relation as a member, but
+// their other members aren't added.
+//
+// Regions are bounding boxes or polygons, read from Osmosis `.poly` files or
+// GeoJSON. Polygons may have several rings; a point is inside when an odd
+// number of rings contain it, so inner rings cut holes.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use serde_json::{self, Value};
+use avoid::contains_point;
+use entities::{Bounds, Map};
+use loader::{Error, Result};
+
+/// Area to cut out of a map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    Bbox { minlat: f64, minlon: f64, maxlat: f64, maxlon: f64 },
+    /// Rings of `(lat, lon)` points, which may or may not repeat their first
+    /// point at the end.
+    Polygon(Vec<Vec<(f64, f64)>>)
+}
+
+impl Region {
+    /// Parses a bounding box given as `minlon,minlat,maxlon,maxlat`, the
+    /// order used by osmium and GeoJSON.
+    pub fn parse_bbox(bbox: &str) -> Result<Region> {
+        let error = || Error::Parse(format!("invalid bounding box {:?}", bbox));
+        let values = bbox.split(',').
+            map(|value| value.trim().parse::<f64>().map_err(|_| error())).
+            collect::<Result<Vec<f64>>>()?;
+        if values.len() != 4 || values[0] > values[2] || values[1] > values[3] {
+            return Err(error());
+        }
+        Ok(Region::Bbox { minlat: values[1], minlon: values[0], maxlat: values[3], maxlon: values[2] })
+    }
+
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        match *self {
+            Region::Bbox { minlat, minlon, maxlat, maxlon } => {
+                point.0 >= minlat && point.0 <= maxlat && point.1 >= minlon && point.1 <= maxlon
+            },
+            Region::Polygon(ref rings) => {
+                rings.iter().filter(|ring| contains_point(ring, point)).count() % 2 == 1
+            }
+        }
+    }
+
+    /// Returns the bounding box of the region.
+    pub fn bounds(&self) -> Bounds {
+        match *self {
+            Region::Bbox { minlat, minlon, maxlat, maxlon } => Bounds { minlat, minlon, maxlat, maxlon },
+            Region::Polygon(ref rings) => {
+                let mut bounds = Bounds {
+                    minlat: f64::INFINITY, minlon: f64::INFINITY,
+                    maxlat: f64::NEG_INFINITY, maxlon: f64::NEG_INFINITY
+                };
+                for &(lat, lon) in rings.iter().flat_map(|ring| ring.iter()) {
+                    bounds.minlat = bounds.minlat.min(lat);
+                    bounds.minlon = bounds.minlon.min(lon);
+                    bounds.maxlat = bounds.maxlat.max(lat);
+                    bounds.maxlon = bounds.maxlon.max(lon);
+                }
+                bounds
+            }
+        }
+    }
+}
+
+/// Reads a polygon in the Osmosis `.poly` format: a name line, then rings of
+/// `lon lat` lines, each with a name line before it and `END` after it, and
+/// a final `END`. Rings whose names start with `!` are holes.
+pub fn read_poly<R: BufRead>(reader: R) -> Result<Region> {
+    let mut rings = Vec::new();
+    let mut ring: Option<Vec<(f64, f64)>> = None;
+    let mut finished = false;
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if n == 0 || line.is_empty() {
+            continue;
+        }
+        if finished {
+            return Err(Error::Parse(format!("line {}: data after the final END", n + 1)));
+        }
+        match ring.take() {
+            Some(points) => {
+                if line == "END" {
+                    if points.len() < 3 {
+                        return Err(Error::Parse(format!("line {}: ring with fewer than 3 points", n + 1)));
+                    }
+                    rings.push(points);
+                } else {
+                    let values: Vec<f64> = line.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+                    if values.len() != 2 {
+                        return Err(Error::Parse(format!("line {}: expected longitude and latitude", n + 1)));
+                    }
+                    let mut points = points;
+                    points.push((values[1], values[0]));
+                    ring = Some(points);
+                }
+            },
+            None if line == "END" => finished = true,
+            None => ring = Some(Vec::new())
+        }
+    }
+    if !finished || rings.is_empty() {
+        return Err(Error::Parse("polygon file ends without a ring and a final END".to_string()));
+    }
+    Ok(Region::Polygon(rings))
+}
+
+/// Reads the polygons of a GeoJSON `Polygon`, `MultiPolygon`, `Feature` or
+/// `FeatureCollection`.
+pub fn read_geojson<R: Read>(reader: R) -> Result<Region> {
+    let value: Value = serde_json::from_reader(reader)?;
+    let mut rings = Vec::new();
+    add_rings(&value, &mut rings)?;
+    if rings.is_empty() {
+        return Err(Error::Parse("GeoJSON without polygons".to_string()));
+    }
+    Ok(Region::Polygon(rings))
+}
+
+fn add_rings(value: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let invalid = |reason: &str| Error::Parse(format!("invalid GeoJSON: {}", reason));
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            let features = value["features"].as_array().ok_or_else(|| invalid("features must be an array"))?;
+            for feature in features {
+                add_rings(feature, rings)?;
+            }
+        },
+        Some("Feature") => add_rings(&value["geometry"], rings)?,
+        Some("Polygon") => add_polygon(&value["coordinates"], rings).ok_or_else(|| invalid("bad Polygon coordinates"))?,
+        Some("MultiPolygon") => {
+            let polygons = value["coordinates"].as_array().ok_or_else(|| invalid("bad MultiPolygon coordinates"))?;
+            for polygon in polygons {
+                add_polygon(polygon, rings).ok_or_else(|| invalid("bad MultiPolygon coordinates"))?;
+            }
+        },
+        // Other geometries don't enclose an area
+        Some(_) => {},
+        None => return Err(invalid("object without a type"))
+    }
+    Ok(())
+}
+
+fn add_polygon(coordinates: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Option<()> {
+    for ring in coordinates.as_array()? {
+        let points = ring.as_array()?.iter().
+            map(|point| Some((point.get(1)?.as_f64()?, point.get(0)?.as_f64()?))).
+            collect::<Option<Vec<(f64, f64)>>>()?;
+        if points.len() < 3 {
+            return None;
+        }
+        rings.push(points);
+    }
+    Some(())
+}
+
+/// Loads a polygon from a `.poly` or `.geojson` / `.json` file.
+pub fn load_region<P: AsRef<Path>>(path: P) -> Result<Region> {
+    let path = path.as_ref();
+    let reader = BufReader::new(File::open(path)?);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("poly") => read_poly(reader),
+        Some("geojson") | Some("json") => read_geojson(reader),
+        _ => Err(Error::UnknownFormat(path.display().to_string()))
+    }
+}
+
+/// Cuts the part of `map` inside `region` into a new map, whose bounds are
+/// those of the region.
+pub fn clip(map: &Map, region: &Region) -> Map {
+    let inside: HashSet<i64> = map.nodes.iter().
+        filter(|node| region.contains(node.position())).
+        map(|node| node.id).
+        collect();
+    let ways: Vec<_> = map.ways.iter().
+        filter(|way| way.node_refs.iter().any(|node_ref| inside.contains(&node_ref.id))).
+        cloned().
+        collect();
+    let mut node_ids = inside;
+    node_ids.extend(ways.iter().flat_map(|way| way.node_refs.iter().map(|node_ref| node_ref.id)));
+    let way_ids: HashSet<i64> = ways.iter().map(|way| way.id).collect();
+
+    // Relations can hold relations, so keep going until no more are added
+    let mut relation_ids = HashSet::new();
+    loop {
+        let count = relation_ids.len();
+        for relation in &map.relations {
+            let kept = relation.members.iter().any(|member| match member.kind.as_str() {
+                "node" => node_ids.contains(&member.id),
+                "way" => way_ids.contains(&member.id),
+                "relation" => relation_ids.contains(&member.id),
+                _ => false
+            });
+            if kept {
+                relation_ids.insert(relation.id);
+            }
+        }
+        if relation_ids.len() == count {
+            break;
+        }
+    }
+
+    Map {
+        version: map.version.clone(),
+        generator: map.generator.clone(),
+        note: map.note.clone(),
+        meta: map.meta.clone(),
+        bounds: region.bounds(),
+        nodes: map.nodes.iter().filter(|node| node_ids.contains(&node.id)).cloned().collect(),
+        ways,
+        relations: map.relations.iter().filter(|relation| relation_ids.contains(&relation.id)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn relation(id: i64, members: &[(&str, i64)]) -> Relation {
+        Relation {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            members: members.iter().map(|&(kind, id)| Member { kind: kind.to_string(), id, role: String::new() }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    // Nodes 1 and 2 inside the unit square, 3 and 4 outside; way 10 crosses
+    // its edge and way 11 stays outside
+    fn create_map() -> Map {
+        Map {
+            version: "0.6".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: -1.0, minlon: -1.0, maxlat: 3.0, maxlon: 3.0 },
+            nodes: vec![node(1, 0.5, 0.5), node(2, 0.2, 0.8), node(3, 2.0, 2.0), node(4, 2.5, 2.0)],
+            ways: vec![way(10, &[1, 3]), way(11, &[3, 4])],
+            relations: vec![
+                relation(20, &[("way", 11), ("node", 4)]),
+                relation(21, &[("way", 10), ("way", 11)]),
+                relation(22, &[("relation", 21)]),
+                relation(23, &[("node", 3)])
+            ]
+        }
+    }
+
+    fn ids<T, F: Fn(&T) -> i64>(items: &[T], id: F) -> Vec<i64> {
+        items.iter().map(id).collect()
+    }
+
+    #[test]
+    fn keeps_complete_ways() {
+        let map = create_map();
+        let region = Region::parse_bbox("0,0,1,1").unwrap();
+        let extract = clip(&map, &region);
+        assert_eq!(vec![1, 2, 3], ids(&extract.nodes, |node| node.id));
+        assert_eq!(vec![10], ids(&extract.ways, |way| way.id));
+        // Node 3 is only there to complete way 10, but still counts
+        assert_eq!(vec![21, 22, 23], ids(&extract.relations, |relation| relation.id));
+        assert_eq!((0.0, 0.0, 1.0, 1.0), (extract.bounds.minlat, extract.bounds.minlon, extract.bounds.maxlat, extract.bounds.maxlon));
+
+        let extract = clip(&map, &Region::parse_bbox("5,5,6,6").unwrap());
+        assert!(extract.nodes.is_empty() && extract.ways.is_empty() && extract.relations.is_empty());
+    }
+
+    #[test]
+    fn parses_bounding_boxes() {
+        assert_eq!(Region::Bbox { minlat: 36.1, minlon: -86.8, maxlat: 36.2, maxlon: -86.7 },
+            Region::parse_bbox("-86.8, 36.1, -86.7, 36.2").unwrap());
+        assert!(Region::parse_bbox("-86.7,36.1,-86.8,36.2").is_err());
+        assert!(Region::parse_bbox("1,2,3").is_err());
+        assert!(Region::parse_bbox("a,b,c,d").is_err());
+    }
+
+    #[test]
+    fn reads_poly_with_holes() {
+        let poly = "extract\n\
+            outer\n   0.0E+00   0.0E+00\n   1.0   0.0\n   1.0   1.0\n   0.0   1.0\nEND\n\
+            !hole\n   0.4   0.4\n   0.6   0.4\n   0.6   0.6\n   0.4   0.6\nEND\n\
+            END\n";
+        let region = read_poly(poly.as_bytes()).unwrap();
+        assert!(region.contains((0.2, 0.8)));
+        assert!(!region.contains((0.5, 0.5)));
+        assert!(!region.contains((2.0, 2.0)));
+        assert_eq!(1.0, region.bounds().maxlon);
+
+        let extract = clip(&create_map(), &region);
+        assert_eq!(vec![2], ids(&extract.nodes, |node| node.id));
+        assert!(extract.ways.is_empty());
+
+        assert!(read_poly("extract\nouter\n0 0\n1 0\nEND\nEND\n".as_bytes()).is_err());
+        assert!(read_poly("extract\nouter\n0 0\n1 0\n1 1\nEND\n".as_bytes()).is_err());
+        match read_poly("extract\nouter\n0 0\n1\n".as_bytes()) {
+            Err(Error::Parse(reason)) => assert_eq!("line 4: expected longitude and latitude", reason),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reads_geojson() {
+        let geojson = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [5, 5]}},
+            {"type": "Feature", "properties": {}, "geometry": {"type": "MultiPolygon", "coordinates": [
+                [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]],
+                [[[1.5, 1.5], [3, 1.5], [3, 3], [1.5, 3], [1.5, 1.5]]]
+            ]}}
+        ]}"#;
+        let region = read_geojson(geojson.as_bytes()).unwrap();
+        assert!(region.contains((0.5, 0.5)));
+        assert!(region.contains((2.0, 2.0)));
+        assert!(!region.contains((1.2, 1.2)));
+        assert_eq!(vec![1, 2, 3, 4], ids(&clip(&create_map(), &region).nodes, |node| node.id));
+
+        assert!(read_geojson(r#"{"type": "Point", "coordinates": [0, 0]}"#.as_bytes()).is_err());
+        assert!(read_geojson(r#"{"type": "Polygon", "coordinates": [[[0, 0], [1]]]}"#.as_bytes()).is_err());
+    }
+}
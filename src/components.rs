@@ -0,0 +1,324 @@
+// This module finds the strongly connected components of a routing graph:
+// sets of nodes that can all be reached from each other. Extracts cut
+// through roads at their edges, and oneway mistakes, private roads and
+// unconnected service roads leave islands that routes can enter but not
+// leave, or not reach at all. Queries from or to such islands fail, so they
+// can be reported, pruned from the map, or left out when snapping positions
+// onto the network.
+//
+// Components are found with Tarjan's algorithm, run iteratively so long
+// roads don't overflow the stack. Closed edges don't connect anything, so
+// `road_graph` closes the edges of buildings, landuse and other ways without
+// a `highway` tag, which would otherwise count as components of their own.
+
+use std::collections::HashSet;
+use entities::Map;
+use graph::{Graph, RoutingGraph};
+
+/// Strongly connected components of a graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Components {
+    /// Component of every node, numbered from the largest down; `None` for
+    /// nodes that no open edge touches.
+    pub labels: Vec<Option<usize>>,
+    /// Number of nodes in each component.
+    pub sizes: Vec<usize>
+}
+
+/// A component other than the largest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Island {
+    pub component: usize,
+    pub size: usize,
+    /// Ids of a few of its nodes, to look it up in an editor.
+    pub sample: Vec<i64>
+}
+
+impl Components {
+    pub fn count(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// Returns the largest component, if the graph has any edges.
+    pub fn largest(&self) -> Option<usize> {
+        if self.sizes.is_empty() { None } else { Some(0) }
+    }
+
+    /// Whether the node at `index` is in the largest component.
+    pub fn in_largest(&self, index: usize) -> bool {
+        self.labels[index] == Some(0)
+    }
+
+    /// Lists every component but the largest, largest first, with up to
+    /// `samples` node ids each.
+    pub fn islands<G: RoutingGraph>(&self, graph: &G, samples: usize) -> Vec<Island> {
+        let mut islands: Vec<Island> = self.sizes.iter().enumerate().skip(1).
+            map(|(component, &size)| Island { component, size, sample: Vec::new() }).
+            collect();
+        for (index, label) in self.labels.iter().enumerate() {
+            if let Some(component) = *label {
+                if component > 0 && islands[component - 1].sample.len() < samples {
+                    islands[component - 1].sample.push(graph.node_id(index));
+                }
+            }
+        }
+        islands
+    }
+
+    /// Returns for every edge of `graph` whether it lies within a component
+    /// of at least `min_size` nodes, for use with
+    /// `spatial::SpatialIndex::retain_edges` so positions aren't snapped onto
+    /// islands.
+    pub fn edge_filter(&self, graph: &Graph, min_size: usize) -> Vec<bool> {
+        graph.edges.iter().map(|edge| match (self.labels[edge.from], self.labels[edge.to]) {
+            (Some(from), Some(to)) => from == to && self.sizes[from] >= min_size,
+            _ => false
+        }).collect()
+    }
+}
+
+/// Builds the graph of `map` with the edges of ways that aren't roads
+/// closed, for finding components of the road network alone.
+pub fn road_graph(map: &Map) -> Graph<'_> {
+    let roads: HashSet<i64> = map.ways.iter().
+        filter(|way| way.tags.contains_key("highway")).
+        map(|way| way.id).
+        collect();
+    let mut graph = Graph::new(map);
+    for edge in 0..graph.edges.len() {
+        if !roads.contains(&graph.edges[edge].way_id) {
+            graph.set_cost(edge, f64::INFINITY);
+        }
+    }
+    graph
+}
+
+/// Finds the strongly connected components of `graph`.
+pub fn strongly_connected_components<G: RoutingGraph>(graph: &G) -> Components {
+    const UNVISITED: usize = usize::MAX;
+    let n = graph.node_count();
+    let mut order = vec![UNVISITED; n];
+    let mut lowlink = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut touched = vec![false; n];
+    let mut stack = Vec::new();
+    let mut labels = vec![UNVISITED; n];
+    let mut count = 0;
+    let mut next = 0;
+
+    // Each frame holds a node, its successors and how many were visited
+    let mut frames: Vec<(usize, Vec<usize>, usize)> = Vec::new();
+    for root in 0..n {
+        if order[root] != UNVISITED {
+            continue;
+        }
+        let mut visit = Some(root);
+        loop {
+            if let Some(v) = visit.take() {
+                order[v] = next;
+                lowlink[v] = next;
+                next += 1;
+                stack.push(v);
+                on_stack[v] = true;
+                let successors: Vec<usize> = graph.neighbors(v).into_iter().map(|(w, _)| w).collect();
+                for &w in &successors {
+                    touched[v] = true;
+                    touched[w] = true;
+                }
+                frames.push((v, successors, 0));
+            }
+            let (v, w) = match frames.last_mut() {
+                Some(frame) if frame.2 < frame.1.len() => {
+                    frame.2 += 1;
+                    (frame.0, Some(frame.1[frame.2 - 1]))
+                },
+                Some(frame) => (frame.0, None),
+                None => break
+            };
+            match w {
+                Some(w) if order[w] == UNVISITED => visit = Some(w),
+                Some(w) => if on_stack[w] {
+                    lowlink[v] = lowlink[v].min(order[w]);
+                },
+                None => {
+                    frames.pop();
+                    if let Some(parent) = frames.last() {
+                        lowlink[parent.0] = lowlink[parent.0].min(lowlink[v]);
+                    }
+                    if lowlink[v] == order[v] {
+                        while let Some(w) = stack.pop() {
+                            on_stack[w] = false;
+                            labels[w] = count;
+                            if w == v {
+                                break;
+                            }
+                        }
+                        count += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Renumber the components touched by edges by size, largest first,
+    // breaking ties by their first node
+    let mut sizes = vec![0; count];
+    let mut first = vec![UNVISITED; count];
+    for index in (0..n).filter(|&index| touched[index]) {
+        sizes[labels[index]] += 1;
+        first[labels[index]] = first[labels[index]].min(index);
+    }
+    let mut components: Vec<usize> = (0..count).filter(|&c| sizes[c] > 0).collect();
+    components.sort_by_key(|&c| (usize::MAX - sizes[c], first[c]));
+    let mut renumbered = vec![0; count];
+    for (new, &old) in components.iter().enumerate() {
+        renumbered[old] = new;
+    }
+    Components {
+        labels: (0..n).map(|index| if touched[index] { Some(renumbered[labels[index]]) } else { None }).collect(),
+        sizes: components.iter().map(|&c| sizes[c]).collect()
+    }
+}
+
+/// Removes everything outside the largest component of the map's road
+/// network: roads are cut back to the run of nodes inside it, roads left
+/// with fewer than two nodes and nodes no remaining way uses are dropped, and
+/// relations lose the members that are gone. Ways that aren't roads are
+/// kept as they are. Returns the number of nodes removed.
+pub fn prune_to_largest(map: &mut Map) -> usize {
+    let keep: HashSet<i64> = {
+        let graph = road_graph(map);
+        let components = strongly_connected_components(&graph);
+        (0..graph.nodes.len()).
+            filter(|&index| components.in_largest(index)).
+            map(|index| graph.nodes[index].id).
+            collect()
+    };
+
+    // Nodes between two nodes of a component on the same way are in it too,
+    // so cutting the ends of ways is enough
+    for way in map.ways.iter_mut().filter(|way| way.tags.contains_key("highway")) {
+        let start = way.node_refs.iter().position(|node_ref| keep.contains(&node_ref.id));
+        let end = way.node_refs.iter().rposition(|node_ref| keep.contains(&node_ref.id));
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                way.node_refs.truncate(end + 1);
+                way.node_refs.drain(..start);
+            },
+            _ => way.node_refs.clear()
+        }
+    }
+    map.ways.retain(|way| way.node_refs.len() >= 2);
+    let count = map.nodes.len();
+    let used: HashSet<i64> = map.ways.iter().
+        flat_map(|way| way.node_refs.iter().map(|node_ref| node_ref.id)).
+        collect();
+    map.nodes.retain(|node| used.contains(&node.id));
+
+    let way_ids: HashSet<i64> = map.ways.iter().map(|way| way.id).collect();
+    for relation in &mut map.relations {
+        relation.members.retain(|member| match member.kind.as_str() {
+            "node" => used.contains(&member.id),
+            "way" => way_ids.contains(&member.id),
+            _ => true
+        });
+    }
+    map.relations.retain(|relation| !relation.members.is_empty());
+    count - map.nodes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use spatial::SpatialIndex;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64], tags: &[(&str, &str)]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    // A loop of nodes 1-4, with a oneway spur from it through 5 to 6, a
+    // separate road from 7 to 8, a lone node 9 and a building of nodes
+    // 10-14 next to the loop
+    fn create_map() -> Map {
+        Map {
+            version: "0.6".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 36.0, minlon: -86.0, maxlat: 36.01, maxlon: -85.99 },
+            nodes: vec![
+                node(1, 36.0, -86.0), node(2, 36.0, -85.999), node(3, 36.001, -85.999), node(4, 36.001, -86.0),
+                node(5, 36.002, -85.998), node(6, 36.003, -85.998),
+                node(7, 36.009, -85.991), node(8, 36.009, -85.990), node(9, 36.005, -85.995),
+                node(10, 35.9995, -86.0), node(11, 35.9995, -85.999), node(12, 35.999, -85.999),
+                node(13, 35.999, -85.9995), node(14, 35.999, -86.0)
+            ],
+            ways: vec![
+                way(1, &[1, 2, 3, 4, 1], &[("highway", "residential")]),
+                way(2, &[3, 5, 6], &[("highway", "service"), ("oneway", "yes")]),
+                way(3, &[7, 8], &[("highway", "residential")]),
+                way(4, &[10, 11, 12, 13, 14, 10], &[("building", "yes")])
+            ],
+            relations: vec![Relation {
+                id: 10, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                members: vec![
+                    Member { kind: "way".to_string(), id: 1, role: String::new() },
+                    Member { kind: "way".to_string(), id: 3, role: String::new() }
+                ],
+                name: None, tags: Tags::new()
+            }]
+        }
+    }
+
+    #[test]
+    fn finds_components_and_islands() {
+        let map = create_map();
+        let graph = road_graph(&map);
+        let components = strongly_connected_components(&graph);
+        let label = |id| components.labels[graph.node_index(id).unwrap()];
+
+        assert_eq!(vec![4, 2, 1, 1], components.sizes);
+        assert_eq!(Some(0), components.largest());
+        assert!((1..5).all(|id| label(id) == Some(0)));
+        assert_eq!(Some(1), label(7));
+        assert_eq!(label(7), label(8));
+        assert_ne!(label(5), label(6));
+        assert_eq!(None, label(9));
+        assert!((10..15).all(|id| label(id).is_none()));
+
+        let islands = components.islands(&graph, 1);
+        assert_eq!(3, islands.len());
+        assert_eq!(Island { component: 1, size: 2, sample: vec![7] }, islands[0]);
+
+        // Snapping next to the separate road ends up on the loop instead
+        let mut index = SpatialIndex::new(&graph, 100.0);
+        let allowed = components.edge_filter(&graph, 3);
+        index.retain_edges(|edge| allowed[edge]);
+        let snap = index.nearest(&graph, (36.0085, -85.9905), 2000.0).unwrap();
+        assert_eq!(1, graph.edges[snap.edge].way_id);
+    }
+
+    #[test]
+    fn prunes_to_largest_component() {
+        let mut map = create_map();
+        assert_eq!(5, prune_to_largest(&mut map));
+        assert_eq!(vec![1, 2, 3, 4, 10, 11, 12, 13, 14], map.nodes.iter().map(|node| node.id).collect::<Vec<i64>>());
+        assert_eq!(vec![1, 4], map.ways.iter().map(|way| way.id).collect::<Vec<i64>>());
+        assert_eq!(6, map.ways[1].node_refs.len());
+        assert_eq!(1, map.relations[0].members.len());
+
+        let graph = road_graph(&map);
+        assert_eq!(1, strongly_connected_components(&graph).count());
+    }
+}
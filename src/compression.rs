@@ -0,0 +1,173 @@
+// This module reads and writes compressed files. OSM extracts are usually
+// distributed as `.osm.gz` or `.osm.bz2`, and caches of large maps shrink a
+// lot with zstd, which decompresses fast enough not to slow loading down.
+//
+// Input is decompressed on the fly, so a compressed extract never has to be
+// unpacked on disk. The compression is detected from the magic bytes at the
+// start of the data, which also works when the extension is missing or
+// wrong; extensions only matter for telling the format underneath.
+
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use bzip2;
+use flate2;
+use zstd;
+use loader::Error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Bzip2,
+    Zstd
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Compression, Error> {
+        match name {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "bzip2" | "bz2" => Ok(Compression::Bzip2),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => Err(Error::UnknownFormat(format!("compression {}", name)))
+        }
+    }
+}
+
+impl Compression {
+    /// Tells the compression by the extension of `path`.
+    pub fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("bz2") => Compression::Bzip2,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None
+        }
+    }
+
+    /// Tells the compression by the magic bytes at the start of `data`.
+    pub fn from_magic(data: &[u8]) -> Compression {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if data.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Tells the compression of the data `reader` is about to return,
+    /// without consuming any of it.
+    pub fn detect<R: BufRead>(reader: &mut R) -> io::Result<Compression> {
+        Ok(Compression::from_magic(reader.fill_buf()?))
+    }
+
+    /// Returns `path` without the extension of its compression, if any.
+    pub fn strip_extension(path: &Path) -> PathBuf {
+        match Compression::from_path(path) {
+            Compression::None => path.to_path_buf(),
+            _ => path.with_extension("")
+        }
+    }
+
+    /// Wraps `reader` so it returns the decompressed data.
+    pub fn decoder<'a, R: BufRead + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match *self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?)
+        })
+    }
+
+    /// Wraps `writer` so the data written to it gets compressed. The
+    /// encoder must be finished with `Encoder::finish`.
+    pub fn encoder<W: Write>(&self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match *self {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Bzip2 => Encoder::Bzip2(bzip2::write::BzEncoder::new(writer, bzip2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?)
+        })
+    }
+}
+
+/// Writer compressing its data, returned by `Compression::encoder`.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>)
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed data and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(writer) => Ok(writer),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish()
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::None(ref mut writer) => writer.write(buf),
+            Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+            Encoder::Bzip2(ref mut encoder) => encoder.write(buf),
+            Encoder::Zstd(ref mut encoder) => encoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::None(ref mut writer) => writer.flush(),
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            Encoder::Bzip2(ref mut encoder) => encoder.flush(),
+            Encoder::Zstd(ref mut encoder) => encoder.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn round_trip() {
+        let data = b"<osm version=\"0.6\"></osm>".repeat(100);
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Bzip2, Compression::Zstd] {
+            let mut encoder = compression.encoder(Vec::new()).unwrap();
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let mut reader = &compressed[..];
+            assert_eq!(compression, Compression::detect(&mut reader).unwrap());
+
+            let mut decompressed = Vec::new();
+            compression.decoder(reader).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(data, decompressed);
+        }
+    }
+
+    #[test]
+    fn from_path() {
+        assert_eq!(Compression::Bzip2, Compression::from_path(Path::new("tennessee.osm.bz2")));
+        assert_eq!(Compression::Gzip, Compression::from_path(Path::new("tennessee.osm.gz")));
+        assert_eq!(Compression::Zstd, Compression::from_path(Path::new("tennessee.bin.zst")));
+        assert_eq!(Compression::None, Compression::from_path(Path::new("tennessee.osm")));
+        assert_eq!(Path::new("tennessee.osm"), Compression::strip_extension(Path::new("tennessee.osm.gz")));
+        assert_eq!(Path::new("tennessee.osm"), Compression::strip_extension(Path::new("tennessee.osm")));
+        assert_eq!(Compression::Zstd, "zstd".parse().unwrap());
+        assert!("lzma".parse::<Compression>().is_err());
+    }
+}
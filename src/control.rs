@@ -0,0 +1,326 @@
+// This module recognizes intersections and the devices controlling them from
+// node tags: traffic signals, stop and give way signs, and pedestrian
+// crossings. It turns them into routing penalties and describes the
+// fixed-time plans that the simulation runs at signalised junctions.
+//
+// Stop and give way signs only affect routing; the simulation doesn't model
+// priority between conflicting streams of traffic.
+
+use std::collections::{HashMap, HashSet};
+use entities::{Map, Node, Way};
+use geo;
+use graph::Graph;
+use roads;
+
+/// Device controlling traffic at a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    TrafficSignals,
+    Stop,
+    GiveWay,
+    Crossing
+}
+
+impl Control {
+    /// Reads the control device from the `highway` tag of a node.
+    pub fn from_node(node: &Node) -> Option<Control> {
+        match node.tags.get("highway") {
+            Some("traffic_signals") => Some(Control::TrafficSignals),
+            Some("stop") => Some(Control::Stop),
+            Some("give_way") => Some(Control::GiveWay),
+            Some("crossing") => Some(Control::Crossing),
+            _ => None
+        }
+    }
+
+    /// Mean time in seconds lost passing the device.
+    pub fn delay(&self) -> f64 {
+        match *self {
+            Control::TrafficSignals => SignalPlan::default().mean_delay(),
+            Control::Stop => 6.0,
+            Control::GiveWay => 3.0,
+            Control::Crossing => 2.0
+        }
+    }
+}
+
+/// Whether the control device at `node` applies to traffic arriving along a
+/// way in the given direction. Signs and signals tagged with `direction` (or
+/// `traffic_signals:direction`) of `forward` or `backward` only apply to
+/// traffic in that direction of the way.
+pub fn applies(node: &Node, forward: bool) -> bool {
+    let direction = node.tags.get("direction").or_else(|| node.tags.get("traffic_signals:direction"));
+    match direction {
+        Some("forward") => forward,
+        Some("backward") => !forward,
+        _ => true
+    }
+}
+
+/// A node where roads meet or traffic is controlled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Intersection {
+    /// Index of the node in the graph.
+    pub node: usize,
+    /// Number of distinct nodes it is directly connected to.
+    pub legs: usize,
+    pub control: Option<Control>
+}
+
+/// Finds the nodes of `graph` that join three or more road segments or carry
+/// a control device.
+pub fn intersections(graph: &Graph) -> Vec<Intersection> {
+    let mut adjacent: Vec<HashSet<usize>> = vec![HashSet::new(); graph.nodes.len()];
+    for edge in &graph.edges {
+        adjacent[edge.from].insert(edge.to);
+        adjacent[edge.to].insert(edge.from);
+    }
+    adjacent.iter().enumerate().filter_map(|(node, adjacent)| {
+        let control = Control::from_node(graph.nodes[node]);
+        if adjacent.len() >= 3 || (control.is_some() && !adjacent.is_empty()) {
+            Some(Intersection { node, legs: adjacent.len(), control })
+        } else {
+            None
+        }
+    }).collect()
+}
+
+/// Returns the control device at the end of each edge of `graph` that
+/// applies to traffic arriving along it.
+pub fn edge_controls(graph: &Graph) -> Vec<Option<Control>> {
+    graph.edges.iter().map(|edge| {
+        let node = graph.nodes[edge.to];
+        Control::from_node(node).filter(|_| applies(node, edge.forward))
+    }).collect()
+}
+
+/// Adds the delay of control devices to the cost of the edges leading to
+/// them, converted to meters at the speed limit of each edge's way. `graph`
+/// must have been built from `map`. The delays become part of the base cost
+/// of the edges, so `Overrides::apply` keeps them. Calling this again sets
+/// the same costs, whether before or after applying overrides.
+pub fn add_penalties(graph: &mut Graph, map: &Map) {
+    let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+    let controls = edge_controls(graph);
+    for (e, control) in controls.into_iter().enumerate() {
+        let penalty = control.map_or(0.0, |control| {
+            let speed = ways.get(&graph.edges[e].way_id).map_or(roads::default_speed(""), |way| roads::max_speed(way));
+            control.delay() * speed / 3.6
+        });
+        let cost = graph.edges[e].distance + penalty;
+        graph.set_base_cost(e, cost);
+    }
+}
+
+/// Builds the routing graph of `map` with the delays of control devices
+/// added to its costs, as `pathfinder::find_path` and the simulation use.
+pub fn routing_graph(map: &Map) -> Graph<'_> {
+    let mut graph = Graph::new(map);
+    add_penalties(&mut graph, map);
+    graph
+}
+
+/// Fixed-time plan of a signalised junction. The cycle is split evenly
+/// between the phases; each phase is green for its share of the cycle less
+/// `clearance` seconds of red in every direction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignalPlan {
+    /// Length of a full cycle in seconds.
+    pub cycle: f64,
+    /// Start of the first phase in seconds from time zero.
+    pub offset: f64,
+    pub clearance: f64,
+    pub phases: usize
+}
+
+impl Default for SignalPlan {
+    fn default() -> SignalPlan {
+        SignalPlan { cycle: 90.0, offset: 0.0, clearance: 4.0, phases: 2 }
+    }
+}
+
+impl SignalPlan {
+    /// Whether the plan can be run: it needs at least one phase, a positive
+    /// cycle and finite, non-negative clearance.
+    pub fn is_valid(&self) -> bool {
+        self.phases > 0 && self.cycle.is_finite() && self.cycle > 0.0 &&
+            self.offset.is_finite() && self.clearance.is_finite() && self.clearance >= 0.0
+    }
+
+    /// Green time of each phase in seconds.
+    pub fn green_time(&self) -> f64 {
+        (self.cycle / self.phases as f64 - self.clearance).max(0.0)
+    }
+
+    pub fn is_green(&self, phase: usize, time: f64) -> bool {
+        let slot = self.cycle / self.phases as f64;
+        let start = (phase % self.phases) as f64 * slot;
+        let t = (time - self.offset).rem_euclid(self.cycle);
+        t >= start && t < start + self.green_time()
+    }
+
+    /// Mean wait in seconds for vehicles arriving at random in one phase,
+    /// ignoring queues.
+    pub fn mean_delay(&self) -> f64 {
+        let red = self.cycle - self.green_time();
+        red * red / (2.0 * self.cycle)
+    }
+}
+
+/// Assigns each edge arriving at a signalised node to a signal phase, or
+/// `None` if it doesn't end at signals. Approaches are grouped by their
+/// axis: those within 45° of the first approach's axis, in either direction,
+/// share phase 0 and all others get phase 1. At signals on a single road
+/// phase 1 is left for pedestrians.
+pub fn signal_phases(graph: &Graph) -> Vec<Option<usize>> {
+    let controls = edge_controls(graph);
+    let mut reference: HashMap<usize, f64> = HashMap::new();
+    graph.edges.iter().zip(controls).map(|(edge, control)| {
+        if control != Some(Control::TrafficSignals) {
+            return None;
+        }
+        let bearing = geo::final_bearing(graph.nodes[edge.from].position(), graph.nodes[edge.to].position());
+        let axis = bearing % 180.0;
+        let first = *reference.entry(edge.to).or_insert(axis);
+        let difference = (axis - first).abs();
+        Some(if difference.min(180.0 - difference) < 45.0 { 0 } else { 1 })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::{Bounds, Meta, NodeRef};
+    use overrides::Overrides;
+    use pathfinder::{find_path, find_path_in_graph};
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: tags.iter().cloned().collect::<Tags>()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: vec![("maxspeed", "36")].into_iter().collect()
+        }
+    }
+
+    // A crossroads at node 1 with signals, a stop sign at the end of way 1 and a
+    // crossing at the end of way 2
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: -0.01, minlon: -0.01, maxlat: 0.01, maxlon: 0.01 },
+            nodes: vec![
+                node(1, 0.0, 0.0, &[("highway", "traffic_signals")]),
+                node(2, 0.0, -0.005, &[]),
+                node(3, 0.0, 0.005, &[("highway", "stop"), ("direction", "forward")]),
+                node(4, -0.005, 0.0, &[]),
+                node(5, 0.005, 0.0, &[("highway", "crossing")])
+            ],
+            ways: vec![way(1, &[2, 1, 3]), way(2, &[4, 1, 5])],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn controls_from_tags() {
+        let map = create_map();
+        assert_eq!(Some(Control::TrafficSignals), Control::from_node(&map.nodes[0]));
+        assert_eq!(Some(Control::Stop), Control::from_node(&map.nodes[2]));
+        assert_eq!(None, Control::from_node(&map.nodes[1]));
+        assert!(applies(&map.nodes[2], true));
+        assert!(!applies(&map.nodes[2], false));
+        assert!(applies(&map.nodes[0], false));
+    }
+
+    #[test]
+    fn finds_intersections() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let found: Vec<(i64, usize, Option<Control>)> = intersections(&graph).iter().
+            map(|i| (graph.nodes[i.node].id, i.legs, i.control)).
+            collect();
+        assert_eq!(vec![
+            (1, 4, Some(Control::TrafficSignals)),
+            (3, 1, Some(Control::Stop)),
+            (5, 1, Some(Control::Crossing))
+        ], found);
+    }
+
+    #[test]
+    fn penalties_follow_direction() {
+        let map = create_map();
+        let graph = routing_graph(&map);
+        let extra: Vec<f64> = graph.edges.iter().map(|edge| edge.cost - edge.distance).collect();
+        // Way 1 runs 2 -> 1 -> 3: the stop sign only applies in the forward
+        // direction, the signals in both
+        let signals = SignalPlan::default().mean_delay() * 10.0;
+        assert!((extra[0] - signals).abs() < 1e-9);
+        assert_eq!(0.0, extra[1]);
+        assert!((extra[2] - 60.0).abs() < 1e-9);
+        assert!((extra[3] - signals).abs() < 1e-9);
+        // Way 2 ends at the crossing in the forward direction
+        assert!((extra[6] - 20.0).abs() < 1e-9);
+
+        // Routes take the delays into account
+        assert_eq!(Some(vec![2, 1, 3]), find_path(&map, 2, 3));
+        assert_eq!(Some(vec![3, 1, 2]), find_path_in_graph(&graph, 3, 2));
+    }
+
+    #[test]
+    fn penalties_are_added_once() {
+        let map = create_map();
+        let penalized: Vec<f64> = routing_graph(&map).edges.iter().map(|edge| edge.cost).collect();
+        let mut graph = routing_graph(&map);
+        add_penalties(&mut graph, &map);
+        assert_eq!(penalized, graph.edges.iter().map(|edge| edge.cost).collect::<Vec<f64>>());
+
+        // Overrides applied first are kept, scaled onto the delays
+        let mut graph = Graph::new(&map);
+        let mut overrides = Overrides::new();
+        overrides.set_speed(1, 18.0);
+        overrides.close(2);
+        overrides.apply(&mut graph, &map);
+        add_penalties(&mut graph, &map);
+        for (edge, &cost) in graph.edges.iter().zip(&penalized) {
+            let expected = if edge.way_id == 1 { cost * 2.0 } else { f64::INFINITY };
+            assert!(edge.cost == expected || (edge.cost - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn fixed_time_plan() {
+        let plan = SignalPlan { cycle: 60.0, offset: 10.0, clearance: 5.0, phases: 2 };
+        assert_eq!(25.0, plan.green_time());
+        assert!(!plan.is_green(0, 5.0));
+        assert!(plan.is_green(0, 10.0));
+        assert!(plan.is_green(0, 34.9));
+        assert!(!plan.is_green(0, 35.0));
+        assert!(!plan.is_green(1, 35.0));
+        assert!(plan.is_green(1, 40.0));
+        assert!(plan.is_green(1, -1.0));
+        assert!(plan.is_green(0, 70.0));
+        assert!((plan.mean_delay() - 35.0 * 35.0 / 120.0).abs() < 1e-9);
+        assert!(plan.is_valid());
+        assert!(!SignalPlan { phases: 0, ..plan.clone() }.is_valid());
+        assert!(!SignalPlan { cycle: 0.0, ..plan.clone() }.is_valid());
+        assert!(!SignalPlan { clearance: f64::NAN, ..plan }.is_valid());
+    }
+
+    #[test]
+    fn phases_by_approach_axis() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let phases: Vec<(i64, i64, usize)> = graph.edges.iter().zip(signal_phases(&graph)).
+            filter_map(|(edge, phase)| phase.map(|phase| (graph.nodes[edge.from].id, graph.nodes[edge.to].id, phase))).
+            collect();
+        assert_eq!(vec![(2, 1, 0), (3, 1, 0), (4, 1, 1), (5, 1, 1)], phases);
+    }
+}
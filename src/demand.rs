@@ -0,0 +1,551 @@
+// This module generates the trips driven in a simulation, either from an
+// origin-destination matrix or from the land use found in the map, and reads
+// and writes them as CSV or JSON trip files.
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use serde_json;
+use entities::Map;
+use graph::Graph;
+use loader::{Error, Result};
+use spatial::SpatialIndex;
+use tags::Tags;
+
+/// A vehicle trip between two nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub id: usize,
+    pub origin: i64,
+    pub destination: i64,
+    /// Departure time in seconds from the start of the simulation.
+    pub departure: f64
+}
+
+/// Small, seedable random number generator (SplitMix64), so that generated
+/// demand is the same on every run with the same seed.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Normally distributed number (Box-Muller transform).
+    pub fn normal(&mut self, mean: f64, std_dev: f64) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        mean + std_dev * (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+    }
+
+    /// Picks an index with probability proportional to its weight. Returns
+    /// `None` if no weight is positive.
+    pub fn choose_weighted(&mut self, weights: &[f64]) -> Option<usize> {
+        let total: f64 = weights.iter().filter(|&&w| w > 0.0).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut target = self.next_f64() * total;
+        let mut last = None;
+        for (i, &weight) in weights.iter().enumerate() {
+            if weight <= 0.0 {
+                continue;
+            }
+            if target < weight {
+                return Some(i);
+            }
+            target -= weight;
+            last = Some(i);
+        }
+        last
+    }
+}
+
+/// Distribution of departure times, in seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Departures {
+    Uniform { start: f64, end: f64 },
+    /// Normal distribution, cut off at zero.
+    Normal { mean: f64, std_dev: f64 },
+    /// Mixture of normal distributions, e.g. a morning and an evening peak,
+    /// given as `(mean, std_dev, weight)`.
+    Peaks(Vec<(f64, f64, f64)>)
+}
+
+impl Departures {
+    pub fn sample(&self, rng: &mut Rng) -> f64 {
+        match *self {
+            Departures::Uniform { start, end } => start + rng.next_f64() * (end - start),
+            Departures::Normal { mean, std_dev } => rng.normal(mean, std_dev).max(0.0),
+            Departures::Peaks(ref peaks) => {
+                let weights: Vec<f64> = peaks.iter().map(|peak| peak.2).collect();
+                match rng.choose_weighted(&weights) {
+                    Some(i) => rng.normal(peaks[i].0, peaks[i].1).max(0.0),
+                    None => 0.0
+                }
+            }
+        }
+    }
+}
+
+/// Number of trips between pairs of nodes.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OdMatrix {
+    /// `(origin, destination, trips)`; fractional trip counts are rounded
+    /// randomly when generating trips.
+    pub pairs: Vec<(i64, i64, f64)>
+}
+
+impl OdMatrix {
+    pub fn new() -> OdMatrix {
+        OdMatrix::default()
+    }
+
+    pub fn add(&mut self, origin: i64, destination: i64, trips: f64) {
+        self.pairs.push((origin, destination, trips));
+    }
+
+    /// Counts the trips between each pair of nodes.
+    pub fn from_trips(trips: &[Trip]) -> OdMatrix {
+        let mut counts: HashMap<(i64, i64), f64> = HashMap::new();
+        for trip in trips {
+            *counts.entry((trip.origin, trip.destination)).or_insert(0.0) += 1.0;
+        }
+        let mut pairs: Vec<(i64, i64, f64)> = counts.into_iter().map(|((o, d), n)| (o, d, n)).collect();
+        pairs.sort_by_key(|&(o, d, _)| (o, d));
+        OdMatrix { pairs }
+    }
+
+    /// Reads `origin,destination,trips` lines. A header line and lines
+    /// starting with `#` are skipped.
+    pub fn read_csv<R: Read>(reader: R) -> Result<OdMatrix> {
+        let mut matrix = OdMatrix::new();
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (number == 0 && line.starts_with("origin")) {
+                continue;
+            }
+            let fields = split_fields(line, 3, number)?;
+            matrix.add(
+                parse_field(fields[0], number)?,
+                parse_field(fields[1], number)?,
+                parse_field(fields[2], number)?);
+        }
+        Ok(matrix)
+    }
+
+    /// Generates the trips of the matrix with departure times drawn from
+    /// `departures`, ordered by departure.
+    pub fn generate(&self, departures: &Departures, rng: &mut Rng) -> Vec<Trip> {
+        let mut trips = Vec::new();
+        for &(origin, destination, count) in &self.pairs {
+            let mut whole = count.max(0.0).floor() as usize;
+            if rng.next_f64() < count.max(0.0).fract() {
+                whole += 1;
+            }
+            for _ in 0..whole {
+                trips.push(Trip { id: 0, origin, destination, departure: departures.sample(rng) });
+            }
+        }
+        number_trips(trips)
+    }
+}
+
+/// Trip origins and destinations estimated from the `building`, `amenity`
+/// and `landuse` tags of a map. Each tagged node or way is attached to the
+/// nearest node of a `highway` way with outgoing edges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LandUse {
+    /// `(node id, weight)` of places where trips start, such as homes.
+    pub origins: Vec<(i64, f64)>,
+    /// `(node id, weight)` of places where trips end, such as shops and
+    /// workplaces.
+    pub destinations: Vec<(i64, f64)>
+}
+
+impl LandUse {
+    pub fn from_map(map: &Map, graph: &Graph) -> LandUse {
+        let positions: HashMap<i64, (f64, f64)> = map.nodes.iter().
+            map(|node| (node.id, node.position())).
+            collect();
+        let mut features: Vec<((f64, f64), &Tags)> = map.nodes.iter().
+            map(|node| (node.position(), &node.tags)).
+            collect();
+        for way in &map.ways {
+            let points: Vec<(f64, f64)> = way.node_refs.iter().
+                filter_map(|node_ref| positions.get(&node_ref.id).cloned()).
+                collect();
+            if !points.is_empty() {
+                let n = points.len() as f64;
+                let lat = points.iter().map(|p| p.0).sum::<f64>() / n;
+                let lon = points.iter().map(|p| p.1).sum::<f64>() / n;
+                features.push(((lat, lon), &way.tags));
+            }
+        }
+
+        // Only attach features to roads, not to the outlines of buildings
+        let roads: HashSet<i64> = map.ways.iter().
+            filter(|way| way.tags.contains_key("highway")).
+            map(|way| way.id).
+            collect();
+        let mut index = SpatialIndex::new(graph, SEARCH_RADIUS);
+        index.retain_edges(|e| roads.contains(&graph.edges[e].way_id));
+        let bounds = &map.bounds;
+        let max_radius = graph.metric.distance((bounds.minlat, bounds.minlon), (bounds.maxlat, bounds.maxlon)).
+            max(SEARCH_RADIUS);
+
+        let mut origins: HashMap<i64, f64> = HashMap::new();
+        let mut destinations: HashMap<i64, f64> = HashMap::new();
+        for (position, tags) in features {
+            let (production, attraction) = land_use_weights(tags);
+            if production == 0.0 && attraction == 0.0 {
+                continue;
+            }
+            if let Some(node) = nearest_node(graph, &index, position, max_radius) {
+                if production > 0.0 {
+                    *origins.entry(node).or_insert(0.0) += production;
+                }
+                if attraction > 0.0 {
+                    *destinations.entry(node).or_insert(0.0) += attraction;
+                }
+            }
+        }
+
+        let sorted = |weights: HashMap<i64, f64>| {
+            let mut weights: Vec<(i64, f64)> = weights.into_iter().collect();
+            weights.sort_by_key(|&(id, _)| id);
+            weights
+        };
+        LandUse { origins: sorted(origins), destinations: sorted(destinations) }
+    }
+
+    /// Generates `count` trips, picking origins and destinations in
+    /// proportion to their weights. Returns no trips if there is nowhere to
+    /// start or end.
+    pub fn generate(&self, count: usize, departures: &Departures, rng: &mut Rng) -> Vec<Trip> {
+        let origin_weights: Vec<f64> = self.origins.iter().map(|o| o.1).collect();
+        let destination_weights: Vec<f64> = self.destinations.iter().map(|d| d.1).collect();
+        let mut trips = Vec::with_capacity(count);
+        for _ in 0..count {
+            let origin = match rng.choose_weighted(&origin_weights) {
+                Some(i) => self.origins[i].0,
+                None => break
+            };
+            // Try a few times to avoid trips that start where they end
+            let mut destination = None;
+            for _ in 0..10 {
+                match rng.choose_weighted(&destination_weights) {
+                    Some(i) if self.destinations[i].0 != origin => {
+                        destination = Some(self.destinations[i].0);
+                        break;
+                    },
+                    Some(_) => continue,
+                    None => break
+                }
+            }
+            if let Some(destination) = destination {
+                trips.push(Trip { id: 0, origin, destination, departure: departures.sample(rng) });
+            }
+        }
+        number_trips(trips)
+    }
+}
+
+/// Trip production and attraction weights for a feature with these tags.
+pub fn land_use_weights(tags: &Tags) -> (f64, f64) {
+    let mut production = 0.0;
+    let mut attraction = 0.0;
+    match tags.get("building") {
+        Some("apartments") => production += 4.0,
+        Some("house") | Some("detached") | Some("residential") | Some("terrace") |
+            Some("semidetached_house") => production += 1.0,
+        Some("commercial") | Some("retail") | Some("office") | Some("industrial") |
+            Some("warehouse") => attraction += 2.0,
+        Some(_) => {
+            production += 0.5;
+            attraction += 0.5;
+        },
+        None => {}
+    }
+    match tags.get("amenity") {
+        Some("school") | Some("university") | Some("college") | Some("hospital") => attraction += 3.0,
+        Some(_) => attraction += 1.0,
+        None => {}
+    }
+    match tags.get("landuse") {
+        Some("residential") => production += 5.0,
+        Some("commercial") | Some("retail") => attraction += 5.0,
+        Some("industrial") => attraction += 3.0,
+        _ => {}
+    }
+    (production, attraction)
+}
+
+// Meters around a feature first searched for road nodes, and the cell size
+// of the road grid.
+const SEARCH_RADIUS: f64 = 250.0;
+
+// Finds the nearest node with outgoing edges at the ends of the edges in
+// `index`, widening the search until one turns up within the searched radius,
+// since a node at that distance has its edges within it too. Positions that
+// aren't finite have no nearest node.
+fn nearest_node(graph: &Graph, index: &SpatialIndex, position: (f64, f64), max_radius: f64) -> Option<i64> {
+    if !position.0.is_finite() || !position.1.is_finite() {
+        return None;
+    }
+    let mut radius = SEARCH_RADIUS;
+    loop {
+        let nearest = index.edges_within(graph, position, radius).into_iter().
+            flat_map(|snap| vec![graph.edges[snap.edge].from, graph.edges[snap.edge].to]).
+            filter(|&i| !graph.outgoing(i).is_empty()).
+            map(|i| (i, graph.metric.distance(position, graph.nodes[i].position()))).
+            min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        match nearest {
+            Some((i, distance)) if distance <= radius || radius >= max_radius => return Some(graph.nodes[i].id),
+            None if radius >= max_radius => return None,
+            _ => radius *= 2.0
+        }
+    }
+}
+
+// Sorts trips by departure and numbers them in that order. Departures that
+// aren't numbers sort last.
+fn number_trips(mut trips: Vec<Trip>) -> Vec<Trip> {
+    trips.sort_by(|a, b| a.departure.total_cmp(&b.departure));
+    for (id, trip) in trips.iter_mut().enumerate() {
+        trip.id = id;
+    }
+    trips
+}
+
+/// Writes trips as CSV with an `id,origin,destination,departure` header.
+pub fn write_csv<W: Write>(mut writer: W, trips: &[Trip]) -> Result<()> {
+    writeln!(writer, "id,origin,destination,departure")?;
+    for trip in trips {
+        writeln!(writer, "{},{},{},{}", trip.id, trip.origin, trip.destination, trip.departure)?;
+    }
+    Ok(())
+}
+
+/// Reads trips written by `write_csv`.
+pub fn read_csv<R: Read>(reader: R) -> Result<Vec<Trip>> {
+    let mut trips = Vec::new();
+    for (number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (number == 0 && line.starts_with("id")) {
+            continue;
+        }
+        let fields = split_fields(line, 4, number)?;
+        trips.push(Trip {
+            id: parse_field(fields[0], number)?,
+            origin: parse_field(fields[1], number)?,
+            destination: parse_field(fields[2], number)?,
+            departure: parse_field(fields[3], number)?
+        });
+    }
+    Ok(trips)
+}
+
+pub fn write_json<W: Write>(writer: W, trips: &[Trip]) -> Result<()> {
+    Ok(serde_json::to_writer_pretty(writer, trips)?)
+}
+
+pub fn read_json<R: Read>(reader: R) -> Result<Vec<Trip>> {
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Saves trips to a file, as JSON if its extension is `json` and as CSV
+/// otherwise.
+pub fn save_trips<P: AsRef<Path>>(path: P, trips: &[Trip]) -> Result<()> {
+    let path = path.as_ref();
+    let mut writer = BufWriter::new(File::create(path)?);
+    if is_json(path) {
+        write_json(&mut writer, trips)?;
+    } else {
+        write_csv(&mut writer, trips)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads trips saved by `save_trips`.
+pub fn load_trips<P: AsRef<Path>>(path: P) -> Result<Vec<Trip>> {
+    let path = path.as_ref();
+    let reader = BufReader::new(File::open(path)?);
+    if is_json(path) { read_json(reader) } else { read_csv(reader) }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("json")
+}
+
+fn split_fields(line: &str, count: usize, number: usize) -> Result<Vec<&str>> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if fields.len() != count {
+        return Err(Error::Parse(format!(
+            "line {}: expected {} fields, found {}", number + 1, count, fields.len())));
+    }
+    Ok(fields)
+}
+
+fn parse_field<T: ::std::str::FromStr>(field: &str, number: usize) -> Result<T> {
+    field.parse().map_err(|_| Error::Parse(format!("line {}: invalid value {:?}", number + 1, field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+
+    fn node(id: i64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64], tags: &[(&str, &str)]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: tags.iter().cloned().collect()
+        }
+    }
+
+    // A road from node 1 to 3 with a house near node 1 and a shop near node 3
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.01, maxlon: 0.01 },
+            nodes: vec![
+                node(1, 0.0, 0.0, &[]), node(2, 0.005, 0.0, &[]), node(3, 0.01, 0.0, &[]),
+                node(4, 0.0101, 0.0001, &[("amenity", "cafe")]),
+                node(5, 0.0, 0.0002, &[]), node(6, 0.0001, 0.0002, &[]), node(7, 0.0001, 0.0003, &[])
+            ],
+            ways: vec![
+                way(1, &[1, 2, 3], &[("highway", "residential")]),
+                way(2, &[5, 6, 7, 5], &[("building", "house")])
+            ],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn rng_is_reproducible() {
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        for _ in 0..100 {
+            let x = a.next_f64();
+            assert_eq!(x, b.next_f64());
+            assert!((0.0..1.0).contains(&x));
+        }
+        assert!(Rng::new(1).next_u64() != Rng::new(2).next_u64());
+        assert_eq!(None, a.choose_weighted(&[0.0, -1.0]));
+        assert_eq!(Some(1), a.choose_weighted(&[0.0, 2.0]));
+    }
+
+    #[test]
+    fn departure_distributions() {
+        let mut rng = Rng::new(7);
+        let uniform = Departures::Uniform { start: 100.0, end: 200.0 };
+        assert!((0..1000).map(|_| uniform.sample(&mut rng)).all(|t| (100.0..200.0).contains(&t)));
+
+        let normal = Departures::Normal { mean: 3600.0, std_dev: 600.0 };
+        let mean = (0..10000).map(|_| normal.sample(&mut rng)).sum::<f64>() / 10000.0;
+        assert!((mean - 3600.0).abs() < 30.0);
+
+        let peaks = Departures::Peaks(vec![(1000.0, 1.0, 1.0), (5000.0, 1.0, 0.0)]);
+        assert!((peaks.sample(&mut rng) - 1000.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn od_matrix_trips() {
+        let csv = "origin,destination,trips\n1,3,2\n# comment\n3,1,0.5\n";
+        let matrix = OdMatrix::read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(vec![(1, 3, 2.0), (3, 1, 0.5)], matrix.pairs);
+
+        let departures = Departures::Uniform { start: 0.0, end: 60.0 };
+        let trips = matrix.generate(&departures, &mut Rng::new(1));
+        assert_eq!(trips, matrix.generate(&departures, &mut Rng::new(1)));
+        assert_eq!(2, trips.iter().filter(|t| t.origin == 1 && t.destination == 3).count());
+        assert!(trips.len() == 2 || trips.len() == 3);
+        assert!(trips.windows(2).all(|w| w[0].departure <= w[1].departure));
+        assert!(trips.iter().enumerate().all(|(i, t)| t.id == i));
+
+        let counted = OdMatrix::from_trips(&trips);
+        assert_eq!((1, 3, 2.0), counted.pairs[0]);
+        assert_eq!(trips.len() as f64, counted.pairs.iter().map(|p| p.2).sum::<f64>());
+
+        assert!(OdMatrix::read_csv("1,2".as_bytes()).is_err());
+        assert!(OdMatrix::read_csv("1,2,x".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn land_use_from_tags() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let land_use = LandUse::from_map(&map, &graph);
+        assert_eq!(vec![(1, 1.0)], land_use.origins);
+        assert_eq!(vec![(3, 1.0)], land_use.destinations);
+
+        let trips = land_use.generate(5, &Departures::Uniform { start: 0.0, end: 10.0 }, &mut Rng::new(3));
+        assert_eq!(5, trips.len());
+        assert!(trips.iter().all(|t| t.origin == 1 && t.destination == 3));
+
+        // Features far from any road still find the nearest node
+        let mut map = create_map();
+        map.nodes.push(node(8, 0.005, 0.006, &[("building", "office")]));
+        let graph = Graph::new(&map);
+        assert_eq!(vec![(2, 2.0), (3, 1.0)], LandUse::from_map(&map, &graph).destinations);
+
+        // Features without a valid position are left out
+        map.nodes.push(node(9, f64::NAN, 0.0, &[("shop", "bakery")]));
+        let graph = Graph::new(&map);
+        assert_eq!(vec![(2, 2.0), (3, 1.0)], LandUse::from_map(&map, &graph).destinations);
+    }
+
+    #[test]
+    fn trips_without_departure_sort_last() {
+        let trips = number_trips(vec![
+            Trip { id: 0, origin: 1, destination: 3, departure: f64::NAN },
+            Trip { id: 0, origin: 3, destination: 1, departure: 12.0 }
+        ]);
+        assert_eq!(vec![(0, 3), (1, 1)], trips.iter().map(|trip| (trip.id, trip.origin)).collect::<Vec<(usize, i64)>>());
+        assert!(trips[1].departure.is_nan());
+    }
+
+    #[test]
+    fn trip_files_round_trip() {
+        let trips = vec![
+            Trip { id: 0, origin: 1, destination: 3, departure: 0.5 },
+            Trip { id: 1, origin: 3, destination: 1, departure: 12.0 }
+        ];
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &trips).unwrap();
+        assert_eq!(trips, read_csv(&csv[..]).unwrap());
+
+        let mut json = Vec::new();
+        write_json(&mut json, &trips).unwrap();
+        assert_eq!(trips, read_json(&json[..]).unwrap());
+    }
+}
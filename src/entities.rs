@@ -1,8 +1,9 @@
-use std::collections::HashMap;
 use std::convert::From;
+use geo::{self, DistanceMetric};
 use osm;
+use tags::Tags;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Map {
     pub version: String,
     pub generator: String,
@@ -41,7 +42,7 @@ impl Map {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Meta {
     pub osm_base: String
 }
@@ -52,7 +53,7 @@ impl From<osm::Meta> for Meta {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Bounds {
     pub minlat: f64,
     pub minlon: f64,
@@ -71,26 +72,33 @@ impl From<osm::Bounds> for Bounds {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+impl Bounds {
+    /// Picks the distance formula for a map covering these bounds.
+    pub fn distance_metric(&self) -> DistanceMetric {
+        DistanceMetric::for_bounds(self.minlat, self.minlon, self.maxlat, self.maxlon)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Node {
     pub id: i64,
     pub lat: f64,
     pub lon: f64,
     pub version: u16,
-    pub timestamp: String,
+    pub timestamp: Option<i64>,
     pub changeset: u64,
     pub uid: Option<i64>,
     pub user: Option<String>,
 
     // tags
     pub name: Option<String>,
-    pub tags: HashMap<String, String>
+    pub tags: Tags
 }
 
 impl From<osm::Node> for Node {
     fn from(node: osm::Node) -> Node {
         let mut name = None;
-        let mut tags = HashMap::new();
+        let mut tags = Tags::new();
         for tag in node.tags {
             if tag.k == "name" {
                 name = Some(tag.v);
@@ -104,7 +112,7 @@ impl From<osm::Node> for Node {
             lat: node.lat,
             lon: node.lon,
             version: node.version,
-            timestamp: node.timestamp,
+            timestamp: parse_timestamp(&node.timestamp),
             changeset: node.changeset,
             uid: node.uid,
             user: node.user,
@@ -116,17 +124,65 @@ impl From<osm::Node> for Node {
 
 impl Node {
     pub fn haversine_distance(&self, other: &Node) -> f64 {
-        let r = 6371e3; // meters
-        let phi_1 = self.lat.to_radians();
-        let phi_2 = other.lat.to_radians();
-        let delta_phi = (other.lat - self.lat).to_radians();
-        let delta_lambda = (other.lon - self.lon).to_radians();
-
-        let a = (delta_phi / 2.0).sin().powi(2) * phi_1.cos() * phi_2.cos() *
-            (delta_lambda / 2.0).sin().powi(2);
-        let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
-        return r * c;
+        geo::haversine_distance(self.position(), other.position())
     }
+
+    /// Returns the `(lat, lon)` position of the node.
+    pub fn position(&self) -> (f64, f64) {
+        (self.lat, self.lon)
+    }
+}
+
+/// Parses an OSM timestamp such as `2018-05-14T21:45:02Z` into seconds since
+/// the Unix epoch. Entities store timestamps this way; missing or unparseable
+/// ones become `None`.
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let bytes = s.as_bytes();
+    if !s.is_ascii() || bytes.len() != 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' ||
+        bytes[13] != b':' || bytes[16] != b':' || bytes[19] != b'Z' {
+        return None;
+    }
+    let field = |start: usize, end: usize| s[start..end].parse::<i64>().ok();
+    let (year, month, day) = (field(0, 4)?, field(5, 7)?, field(8, 10)?);
+    let (hour, minute, second) = (field(11, 13)?, field(14, 16)?, field(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) ||
+        hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Formats seconds since the Unix epoch as an OSM timestamp.
+pub fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+// Date conversions from Howard Hinnant's "chrono-Compatible Low-Level Date
+// Algorithms".
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
 
 enum WayDirection {
@@ -135,11 +191,11 @@ enum WayDirection {
     Reverse
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Way {
     pub id: i64,
     pub version: u16,
-    pub timestamp: String,
+    pub timestamp: Option<i64>,
     pub changeset: u64,
     pub uid: Option<i64>,
     pub user: Option<String>,
@@ -147,13 +203,13 @@ pub struct Way {
 
     // tags
     pub name: Option<String>,
-    pub tags: HashMap<String, String>
+    pub tags: Tags
 }
 
 impl From<osm::Way> for Way {
     fn from(way: osm::Way) -> Way {
         let mut name = None;
-        let mut tags = HashMap::new();
+        let mut tags = Tags::new();
         for tag in way.tags {
             if tag.k == "name" {
                 name = Some(tag.v);
@@ -168,7 +224,7 @@ impl From<osm::Way> for Way {
         Way {
             id: way.id,
             version: way.version,
-            timestamp: way.timestamp,
+            timestamp: parse_timestamp(&way.timestamp),
             changeset: way.changeset,
             uid: way.uid,
             user: way.user,
@@ -261,7 +317,7 @@ impl Way {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct NodeRef {
     pub id: i64
 }
@@ -272,11 +328,11 @@ impl From<osm::NodeRef> for NodeRef {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Relation {
     pub id: i64,
     pub version: u16,
-    pub timestamp: String,
+    pub timestamp: Option<i64>,
     pub changeset: u64,
     pub uid: Option<i64>,
     pub user: Option<String>,
@@ -284,13 +340,13 @@ pub struct Relation {
 
     // tags
     pub name: Option<String>,
-    pub tags: HashMap<String, String>
+    pub tags: Tags
 }
 
 impl From<osm::Relation> for Relation {
     fn from(relation: osm::Relation) -> Relation {
         let mut name = None;
-        let mut tags = HashMap::new();
+        let mut tags = Tags::new();
         for tag in relation.tags {
             if tag.k == "name" {
                 name = Some(tag.v);
@@ -305,7 +361,7 @@ impl From<osm::Relation> for Relation {
         Relation {
             id: relation.id,
             version: relation.version,
-            timestamp: relation.timestamp,
+            timestamp: parse_timestamp(&relation.timestamp),
             changeset: relation.changeset,
             uid: relation.uid,
             user: relation.user,
@@ -316,7 +372,7 @@ impl From<osm::Relation> for Relation {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Member {
     pub kind: String,
     pub id: i64,
@@ -344,7 +400,7 @@ mod tests {
             lat: 1.0,
             lon: 1.0,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: "2018-05-03T20:43:54Z".to_string(),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -354,14 +410,14 @@ mod tests {
             ]
         };
 
-        let mut tags = HashMap::new();
-        tags.insert("baz".to_string(), "qux".to_string());
+        let mut tags = Tags::new();
+        tags.insert("baz", "qux");
         let expected = Node {
             id: 1,
             lat: 1.0,
             lon: 1.0,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: Some(1525380234),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -377,7 +433,7 @@ mod tests {
         let way = osm::Way {
             id: 1,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: "2018-05-03T20:43:54Z".to_string(),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -392,12 +448,12 @@ mod tests {
             ]
         };
 
-        let mut tags = HashMap::new();
-        tags.insert("baz".to_string(), "qux".to_string());
+        let mut tags = Tags::new();
+        tags.insert("baz", "qux");
         let expected = Way {
             id: 1,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: Some(1525380234),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -418,7 +474,7 @@ mod tests {
         let relation = osm::Relation {
             id: 1,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: "2018-05-03T20:43:54Z".to_string(),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -433,12 +489,12 @@ mod tests {
             ]
         };
 
-        let mut tags = HashMap::new();
-        tags.insert("baz".to_string(), "qux".to_string());
+        let mut tags = Tags::new();
+        tags.insert("baz", "qux");
         let expected = Relation {
             id: 1,
             version: 123,
-            timestamp: "foo".to_string(),
+            timestamp: Some(1525380234),
             changeset: 123,
             uid: Some(123),
             user: Some("dude".to_string()),
@@ -454,17 +510,32 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn node_haversine_distance() {
+        let mut a = Node {
+            id: 1, lat: 36.0, lon: -86.0, version: 1, timestamp: None, changeset: 1,
+            uid: None, user: None, name: None, tags: Tags::new()
+        };
+        let b = Node {
+            id: 2, lat: 36.0, lon: -87.0, version: 1, timestamp: None, changeset: 1,
+            uid: None, user: None, name: None, tags: Tags::new()
+        };
+        assert!((a.haversine_distance(&b) - 89959.0).abs() < 1.0);
+        a.lon = -87.0;
+        assert_eq!(0.0, a.haversine_distance(&b));
+    }
+
     #[test]
     fn way_contains_node_id() {
         let way = Way {
-            id: 1, version: 123, timestamp: "foo".to_string(), changeset: 123,
+            id: 1, version: 123, timestamp: None, changeset: 123,
             uid: Some(123), user: Some("dude".to_string()),
             node_refs: vec![
                 NodeRef { id: 1 },
                 NodeRef { id: 2 },
                 NodeRef { id: 3 }
             ],
-            name: Some("foo".to_string()), tags: HashMap::new()
+            name: Some("foo".to_string()), tags: Tags::new()
         };
         assert!(way.contains_node_id(1));
         assert!(!way.contains_node_id(4));
@@ -473,14 +544,14 @@ mod tests {
     #[test]
     fn way_find_path_non_existent_ids() {
         let way = Way {
-            id: 1, version: 123, timestamp: "foo".to_string(), changeset: 123,
+            id: 1, version: 123, timestamp: None, changeset: 123,
             uid: Some(123), user: Some("dude".to_string()),
             node_refs: vec![
                 NodeRef { id: 1 },
                 NodeRef { id: 2 },
                 NodeRef { id: 3 }
             ],
-            name: Some("foo".to_string()), tags: HashMap::new()
+            name: Some("foo".to_string()), tags: Tags::new()
         };
         assert!(way.find_path(1, 4).is_none());
         assert!(way.find_path(0, 3).is_none());
@@ -490,14 +561,14 @@ mod tests {
     #[test]
     fn way_find_path_same_node() {
         let way = Way {
-            id: 1, version: 123, timestamp: "foo".to_string(), changeset: 123,
+            id: 1, version: 123, timestamp: None, changeset: 123,
             uid: Some(123), user: Some("dude".to_string()),
             node_refs: vec![
                 NodeRef { id: 1 },
                 NodeRef { id: 2 },
                 NodeRef { id: 3 }
             ],
-            name: Some("foo".to_string()), tags: HashMap::new()
+            name: Some("foo".to_string()), tags: Tags::new()
         };
 
         let expected = vec![1];
@@ -508,14 +579,14 @@ mod tests {
     #[test]
     fn way_find_path_forward() {
         let way = Way {
-            id: 1, version: 123, timestamp: "foo".to_string(), changeset: 123,
+            id: 1, version: 123, timestamp: None, changeset: 123,
             uid: Some(123), user: Some("dude".to_string()),
             node_refs: vec![
                 NodeRef { id: 1 },
                 NodeRef { id: 2 },
                 NodeRef { id: 3 }
             ],
-            name: Some("foo".to_string()), tags: HashMap::new()
+            name: Some("foo".to_string()), tags: Tags::new()
         };
 
         let expected = vec![1, 2, 3];
@@ -526,18 +597,35 @@ mod tests {
     #[test]
     fn way_find_path_reverse() {
         let way = Way {
-            id: 1, version: 123, timestamp: "foo".to_string(), changeset: 123,
+            id: 1, version: 123, timestamp: None, changeset: 123,
             uid: Some(123), user: Some("dude".to_string()),
             node_refs: vec![
                 NodeRef { id: 1 },
                 NodeRef { id: 2 },
                 NodeRef { id: 3 }
             ],
-            name: Some("foo".to_string()), tags: HashMap::new()
+            name: Some("foo".to_string()), tags: Tags::new()
         };
 
         let expected = vec![3, 2, 1];
         let actual = way.find_path(3, 1).expect("couldn't find path");
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn timestamp_round_trip() {
+        assert_eq!(Some(0), parse_timestamp("1970-01-01T00:00:00Z"));
+        assert_eq!(Some(1525380234), parse_timestamp("2018-05-03T20:43:54Z"));
+        assert_eq!(Some(951868799), parse_timestamp("2000-02-29T23:59:59Z"));
+        assert_eq!(Some(-1), parse_timestamp("1969-12-31T23:59:59Z"));
+        assert_eq!("2012-07-18T16:22:48Z", format_timestamp(1342628568));
+        assert_eq!("1969-12-31T23:59:59Z", format_timestamp(-1));
+    }
+
+    #[test]
+    fn timestamp_rejects_garbage() {
+        assert_eq!(None, parse_timestamp("foo"));
+        assert_eq!(None, parse_timestamp("2018-13-03T20:43:54Z"));
+        assert_eq!(None, parse_timestamp("2018-05-03 20:43:54Z"));
+    }
 }
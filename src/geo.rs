@@ -0,0 +1,392 @@
+// This module contains geodesy helpers. Positions are `(lat, lon)` pairs in
+// degrees, distances are in meters and bearings are in degrees clockwise from
+// north, normalized to [0, 360).
+
+/// Mean radius of the earth in meters, used by the spherical formulas.
+pub const EARTH_RADIUS: f64 = 6371e3;
+
+/// Semi-major axis of the WGS84 ellipsoid in meters.
+pub const WGS84_A: f64 = 6378137.0;
+/// Flattening of the WGS84 ellipsoid.
+pub const WGS84_F: f64 = 1.0 / 298.257223563;
+
+/// Great-circle distance using the haversine formula.
+pub fn haversine_distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let phi_1 = from.0.to_radians();
+    let phi_2 = to.0.to_radians();
+    let delta_phi = (to.0 - from.0).to_radians();
+    let delta_lambda = (to.1 - from.1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2) +
+        phi_1.cos() * phi_2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+    EARTH_RADIUS * c
+}
+
+/// Initial bearing of the great circle from `from` to `to`.
+pub fn initial_bearing(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let phi_1 = from.0.to_radians();
+    let phi_2 = to.0.to_radians();
+    let delta_lambda = (to.1 - from.1).to_radians();
+
+    let y = delta_lambda.sin() * phi_2.cos();
+    let x = phi_1.cos() * phi_2.sin() - phi_1.sin() * phi_2.cos() * delta_lambda.cos();
+    normalize_bearing(y.atan2(x).to_degrees())
+}
+
+/// Bearing on arrival at `to` when following the great circle from `from`.
+pub fn final_bearing(from: (f64, f64), to: (f64, f64)) -> f64 {
+    normalize_bearing(initial_bearing(to, from) + 180.0)
+}
+
+/// Position reached by travelling `distance` along a great circle starting
+/// at `from` with the given initial bearing.
+pub fn destination(from: (f64, f64), bearing: f64, distance: f64) -> (f64, f64) {
+    let phi_1 = from.0.to_radians();
+    let lambda_1 = from.1.to_radians();
+    let theta = bearing.to_radians();
+    let delta = distance / EARTH_RADIUS;
+
+    let phi_2 = (phi_1.sin() * delta.cos() + phi_1.cos() * delta.sin() * theta.cos()).asin();
+    let lambda_2 = lambda_1 + (theta.sin() * delta.sin() * phi_1.cos()).
+        atan2(delta.cos() - phi_1.sin() * phi_2.sin());
+    (phi_2.to_degrees(), normalize_longitude(lambda_2.to_degrees()))
+}
+
+/// Distance of `point` from the great circle through `path_start` and
+/// `path_end`. Positive values are to the right of the path, negative ones
+/// to the left.
+pub fn cross_track_distance(point: (f64, f64), path_start: (f64, f64), path_end: (f64, f64)) -> f64 {
+    let delta_13 = haversine_distance(path_start, point) / EARTH_RADIUS;
+    let theta_13 = initial_bearing(path_start, point).to_radians();
+    let theta_12 = initial_bearing(path_start, path_end).to_radians();
+    (delta_13.sin() * (theta_13 - theta_12).sin()).asin() * EARTH_RADIUS
+}
+
+/// Result of `vincenty_inverse`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Inverse {
+    pub distance: f64,
+    pub initial_bearing: f64,
+    pub final_bearing: f64
+}
+
+/// Distance and bearings between two points on the WGS84 ellipsoid using
+/// Vincenty's inverse formula. Accurate to within a millimeter, but returns
+/// `None` for nearly antipodal points where the iteration fails to converge.
+pub fn vincenty_inverse(from: (f64, f64), to: (f64, f64)) -> Option<Inverse> {
+    let (a, f) = (WGS84_A, WGS84_F);
+    let b = a * (1.0 - f);
+
+    let l = (to.1 - from.1).to_radians();
+    let u_1 = ((1.0 - f) * from.0.to_radians().tan()).atan();
+    let u_2 = ((1.0 - f) * to.0.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u_1.sin_cos();
+    let (sin_u2, cos_u2) = u_2.sin_cos();
+
+    let mut lambda = l;
+    let mut iterations = 0;
+    loop {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2) +
+            (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2)).sqrt();
+        if sin_sigma == 0.0 {
+            // Coincident points
+            return Some(Inverse { distance: 0.0, initial_bearing: 0.0, final_bearing: 0.0 });
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+        let cos_2sigma_m = if cos_sq_alpha != 0.0 {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_sq_alpha
+        } else {
+            // Both points on the equator
+            0.0
+        };
+        let c = f / 16.0 * cos_sq_alpha * (4.0 + f * (4.0 - 3.0 * cos_sq_alpha));
+        let previous = lambda;
+        lambda = l + (1.0 - c) * f * sin_alpha * (sigma + c * sin_sigma *
+            (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)));
+
+        iterations += 1;
+        if (lambda - previous).abs() > 1e-12 {
+            if iterations >= 200 {
+                return None;
+            }
+            continue;
+        }
+
+        let u_sq = cos_sq_alpha * (a * a - b * b) / (b * b);
+        let big_a = 1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+        let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+        let delta_sigma = big_b * sin_sigma * (cos_2sigma_m + big_b / 4.0 *
+            (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m) -
+             big_b / 6.0 * cos_2sigma_m * (-3.0 + 4.0 * sin_sigma * sin_sigma) *
+             (-3.0 + 4.0 * cos_2sigma_m * cos_2sigma_m)));
+
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let alpha_1 = (cos_u2 * sin_lambda).atan2(cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda);
+        let alpha_2 = (cos_u1 * sin_lambda).atan2(-sin_u1 * cos_u2 + cos_u1 * sin_u2 * cos_lambda);
+        return Some(Inverse {
+            distance: b * big_a * (sigma - delta_sigma),
+            initial_bearing: normalize_bearing(alpha_1.to_degrees()),
+            final_bearing: normalize_bearing(alpha_2.to_degrees())
+        });
+    }
+}
+
+/// Ellipsoidal distance using Vincenty's formula.
+pub fn vincenty_distance(from: (f64, f64), to: (f64, f64)) -> Option<f64> {
+    vincenty_inverse(from, to).map(|inverse| inverse.distance)
+}
+
+/// Flat-earth distance approximation after Mapbox's cheap-ruler. Longitude
+/// and latitude differences are scaled by factors derived from the WGS84
+/// ellipsoid at a reference latitude, so a distance costs a multiplication and
+/// a square root instead of a handful of trigonometric calls.
+///
+/// The error grows with how far the points stray from the reference latitude;
+/// see `cheap_ruler_error_bound`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheapRuler {
+    kx: f64,
+    ky: f64
+}
+
+impl CheapRuler {
+    /// Creates a ruler for distances around latitude `lat`.
+    pub fn new(lat: f64) -> CheapRuler {
+        let e2 = WGS84_F * (2.0 - WGS84_F);
+        let m = WGS84_A.to_radians();
+        let cos_lat = lat.to_radians().cos();
+        let w2 = 1.0 / (1.0 - e2 * (1.0 - cos_lat * cos_lat));
+        let w = w2.sqrt();
+        CheapRuler { kx: m * w * cos_lat, ky: m * w * w2 * (1.0 - e2) }
+    }
+
+    /// Creates a ruler from its meters-per-degree factors, as returned by
+    /// `factors`.
+    pub fn from_factors(kx: f64, ky: f64) -> CheapRuler {
+        CheapRuler { kx, ky }
+    }
+
+    /// Returns meters per degree of longitude and of latitude.
+    pub fn factors(&self) -> (f64, f64) {
+        (self.kx, self.ky)
+    }
+
+    pub fn distance(&self, from: (f64, f64), to: (f64, f64)) -> f64 {
+        let dx = normalize_longitude(to.1 - from.1) * self.kx;
+        let dy = (to.0 - from.0) * self.ky;
+        (dx * dx + dy * dy).sqrt()
+    }
+}
+
+/// Formula used for edge weights and search heuristics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceMetric {
+    Haversine,
+    Cheap(CheapRuler)
+}
+
+/// Upper bound on the relative error of `CheapRuler::distance` compared to
+/// `vincenty_distance`, for a ruler created at `lat` and two points at most
+/// `delta` degrees of latitude away from it:
+///
+/// ```text
+/// tan(|lat| + delta) * delta (in radians) + 0.01%
+/// ```
+///
+/// This holds for distances up to 100 km at latitudes up to 70 degrees. For
+/// example, a city extract spanning half a degree of latitude around 36N stays
+/// within 0.33%.
+pub fn cheap_ruler_error_bound(lat: f64, delta: f64) -> f64 {
+    (lat.abs() + delta).to_radians().tan() * delta.to_radians() + 1e-4
+}
+
+/// Largest relative error `DistanceMetric::for_bounds` accepts for the cheap
+/// ruler.
+pub const MAX_CHEAP_ERROR: f64 = 0.005;
+/// Largest diagonal in meters for which `DistanceMetric::for_bounds` picks
+/// the cheap ruler.
+pub const MAX_CHEAP_EXTENT: f64 = 100e3;
+/// Latitude beyond which `DistanceMetric::for_bounds` always picks haversine.
+pub const MAX_CHEAP_LAT: f64 = 70.0;
+
+impl DistanceMetric {
+    /// Picks the cheap ruler, centered on the bounds, when the whole area
+    /// stays within `MAX_CHEAP_ERROR` of the precise distance, and haversine
+    /// otherwise.
+    pub fn for_bounds(minlat: f64, minlon: f64, maxlat: f64, maxlon: f64) -> DistanceMetric {
+        let mid_lat = (minlat + maxlat) / 2.0;
+        let delta = (maxlat - minlat).abs() / 2.0;
+        let extent = haversine_distance((minlat, minlon), (maxlat, maxlon));
+        if extent <= MAX_CHEAP_EXTENT && minlat.abs().max(maxlat.abs()) <= MAX_CHEAP_LAT &&
+            cheap_ruler_error_bound(mid_lat, delta) <= MAX_CHEAP_ERROR {
+            DistanceMetric::Cheap(CheapRuler::new(mid_lat))
+        } else {
+            DistanceMetric::Haversine
+        }
+    }
+
+    pub fn distance(&self, from: (f64, f64), to: (f64, f64)) -> f64 {
+        match *self {
+            DistanceMetric::Haversine => haversine_distance(from, to),
+            DistanceMetric::Cheap(ref ruler) => ruler.distance(from, to)
+        }
+    }
+}
+
+fn normalize_bearing(degrees: f64) -> f64 {
+    (degrees % 360.0 + 360.0) % 360.0
+}
+
+fn normalize_longitude(degrees: f64) -> f64 {
+    (degrees + 540.0) % 360.0 - 180.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dms(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+        degrees.signum() * (degrees.abs() + minutes / 60.0 + seconds / 3600.0)
+    }
+
+    fn assert_close(expected: f64, actual: f64, tolerance: f64) {
+        assert!((expected - actual).abs() <= tolerance,
+                "expected {} but got {} (tolerance {})", expected, actual, tolerance);
+    }
+
+    // Reference values from Chris Veness, "Calculate distance, bearing and
+    // more between Latitude/Longitude points" (movable-type.co.uk).
+    const LANDS_END: (f64, f64) = (50.066389, -5.714722);
+    const JOHN_O_GROATS: (f64, f64) = (58.643889, -3.07);
+
+    #[test]
+    fn haversine_lands_end_to_john_o_groats() {
+        assert_close(968.9e3, haversine_distance(LANDS_END, JOHN_O_GROATS), 100.0);
+    }
+
+    #[test]
+    fn haversine_one_degree_of_longitude_at_equator() {
+        // 2 * pi * R / 360
+        assert_close(111194.93, haversine_distance((0.0, 0.0), (0.0, 1.0)), 0.01);
+        assert_close(111194.93, haversine_distance((0.0, 0.0), (1.0, 0.0)), 0.01);
+        assert_eq!(0.0, haversine_distance((36.1, -86.8), (36.1, -86.8)));
+    }
+
+    #[test]
+    fn bearings_lands_end_to_john_o_groats() {
+        assert_close(dms(9.0, 7.0, 11.0), initial_bearing(LANDS_END, JOHN_O_GROATS), 0.01);
+        assert_close(dms(11.0, 16.0, 31.0), final_bearing(LANDS_END, JOHN_O_GROATS), 0.01);
+    }
+
+    #[test]
+    fn destination_point() {
+        let start = (dms(53.0, 19.0, 14.0), -dms(1.0, 43.0, 47.0));
+        let (lat, lon) = destination(start, dms(96.0, 1.0, 18.0), 124.8e3);
+        assert_close(dms(53.0, 11.0, 18.0), lat, 0.001);
+        assert_close(dms(0.0, 8.0, 0.0), lon, 0.001);
+    }
+
+    #[test]
+    fn destination_wraps_antimeridian() {
+        let (lat, lon) = destination((0.0, 179.5), 90.0, 111194.93);
+        assert_close(0.0, lat, 1e-9);
+        assert_close(-179.5, lon, 1e-6);
+    }
+
+    #[test]
+    fn cross_track_along_equator() {
+        let start = (0.0, 0.0);
+        let end = (0.0, 10.0);
+        // Heading east along the equator, north is to the left
+        assert_close(-111194.93, cross_track_distance((1.0, 5.0), start, end), 0.01);
+        assert_close(111194.93, cross_track_distance((-1.0, 5.0), start, end), 0.01);
+        assert_close(0.0, cross_track_distance((0.0, 5.0), start, end), 1e-6);
+    }
+
+    // Flinders Peak to Buninyong, the worked example from T. Vincenty, "Direct
+    // and Inverse Solutions of Geodesics on the Ellipsoid" (1975), as
+    // published by Geoscience Australia.
+    #[test]
+    fn vincenty_flinders_peak_to_buninyong() {
+        let flinders_peak = (-dms(37.0, 57.0, 3.72030), dms(144.0, 25.0, 29.52440));
+        let buninyong = (-dms(37.0, 39.0, 10.15610), dms(143.0, 55.0, 35.38390));
+        let inverse = vincenty_inverse(flinders_peak, buninyong).unwrap();
+        assert_close(54972.271, inverse.distance, 0.001);
+        assert_close(dms(306.0, 52.0, 5.37), inverse.initial_bearing, 1e-5);
+        assert_close(dms(127.0, 10.0, 25.07) + 180.0, inverse.final_bearing, 1e-5);
+    }
+
+    #[test]
+    fn vincenty_along_equator() {
+        // One degree along the equator is a / 180 * pi on the ellipsoid
+        assert_close(111319.49, vincenty_distance((0.0, 0.0), (0.0, 1.0)).unwrap(), 0.01);
+        assert_eq!(Some(0.0), vincenty_distance((36.1, -86.8), (36.1, -86.8)));
+    }
+
+    #[test]
+    fn vincenty_nearly_antipodal() {
+        assert_eq!(None, vincenty_distance((0.0, 0.0), (0.5, 179.7)));
+    }
+
+    fn relative_error(ruler: &CheapRuler, from: (f64, f64), to: (f64, f64)) -> f64 {
+        let precise = vincenty_distance(from, to).unwrap();
+        (ruler.distance(from, to) - precise).abs() / precise
+    }
+
+    #[test]
+    fn cheap_ruler_within_error_bound() {
+        for &lat in &[0.0, 20.0, 36.0, 50.0, 65.0, 70.0, -45.0] {
+            let ruler = CheapRuler::new(lat);
+            for &offset in &[-0.5, 0.0, 0.25] {
+                let from = (lat + offset, 10.0);
+                for &distance in &[10.0, 1e3, 10e3, 100e3] {
+                    for bearing in 0..12 {
+                        let to = destination(from, f64::from(bearing) * 30.0, distance);
+                        let delta = (from.0 - lat).abs().max((to.0 - lat).abs());
+                        let error = relative_error(&ruler, from, to);
+                        assert!(error <= cheap_ruler_error_bound(lat, delta),
+                                "lat {} offset {} distance {} bearing {}: error {}",
+                                lat, offset, distance, bearing * 30, error);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cheap_ruler_within_city() {
+        // Nashville, the points stay within a quarter degree of latitude
+        let ruler = CheapRuler::new(36.15);
+        let center = (36.15, -86.8);
+        for &distance in &[50.0, 1e3, 10e3] {
+            for bearing in 0..8 {
+                let to = destination(center, f64::from(bearing) * 45.0, distance);
+                assert!(relative_error(&ruler, center, to) < 0.002);
+            }
+        }
+    }
+
+    #[test]
+    fn cheap_ruler_across_antimeridian() {
+        let ruler = CheapRuler::new(0.0);
+        let distance = ruler.distance((0.0, 179.9), (0.0, -179.9));
+        assert_close(vincenty_distance((0.0, 179.9), (0.0, -179.9)).unwrap(), distance, 0.01);
+    }
+
+    #[test]
+    fn metric_for_bounds() {
+        match DistanceMetric::for_bounds(35.9717, -87.0639, 36.3594, -86.4627) {
+            DistanceMetric::Cheap(ruler) => assert_eq!(CheapRuler::new(36.16555), ruler),
+            other => panic!("unexpected metric {:?}", other)
+        }
+        // Too large
+        assert_eq!(DistanceMetric::Haversine, DistanceMetric::for_bounds(30.0, -90.0, 40.0, -80.0));
+        // Too far north
+        assert_eq!(DistanceMetric::Haversine, DistanceMetric::for_bounds(78.0, 15.0, 78.2, 15.5));
+    }
+}
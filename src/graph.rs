@@ -0,0 +1,266 @@
+// This module builds a routing graph out of the ways in a map.
+
+use std::collections::HashMap;
+use std::mem;
+use entities::*;
+use geo::DistanceMetric;
+use roads::{self, Oneway};
+
+/// A directed edge following one segment of a way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub way_id: i64,
+    /// Whether the edge runs in the order of the way's nodes.
+    pub forward: bool,
+    /// Length of the segment in meters.
+    pub distance: f64,
+    /// Cost of the edge for routing. Equal to `distance` unless changed with
+    /// `Graph::set_cost` or `Graph::set_base_cost`; infinite for closed edges.
+    pub cost: f64
+}
+
+/// Read-only view of a routing graph, shared by the in-memory `Graph` and
+/// `mapped::MappedGraph` so the pathfinder can run on either.
+pub trait RoutingGraph {
+    fn node_count(&self) -> usize;
+    fn node_index(&self, node_id: i64) -> Option<usize>;
+    fn node_id(&self, index: usize) -> i64;
+    /// Returns the `(lat, lon)` position of a node.
+    fn position(&self, index: usize) -> (f64, f64);
+    /// Straight-line distance between two nodes, measured the same way as
+    /// the edges, so it never exceeds the length of a path between them.
+    fn distance(&self, from: usize, to: usize) -> f64;
+    /// Returns `(neighbor index, cost)` for every edge leaving a node.
+    fn neighbors(&self, index: usize) -> Vec<(usize, f64)>;
+}
+
+/// Adjacency structure over the nodes of a `Map`.
+///
+/// Nodes are addressed by their index into `nodes`; use `node_index` to look
+/// up the index for an OSM node id. Every segment of every way yields an edge
+/// in each direction it may be travelled, according to its `oneway` tags.
+#[derive(Debug)]
+pub struct Graph<'a> {
+    pub nodes: Vec<&'a Node>,
+    pub edges: Vec<Edge>,
+    pub metric: DistanceMetric,
+    index: HashMap<i64, usize>,
+    outgoing: Vec<Vec<usize>>,
+    // Cost of each edge that `reset_costs` goes back to
+    base_costs: Vec<f64>
+}
+
+impl<'a> Graph<'a> {
+    /// Builds the graph for all nodes and ways in `map`. Way segments that
+    /// reference nodes missing from the map are left out. Edge lengths use
+    /// the distance metric suited to the map's bounds.
+    pub fn new(map: &'a Map) -> Graph<'a> {
+        Graph::with_metric(map, map.bounds.distance_metric())
+    }
+
+    /// Same as `new`, but measures edges with the given metric.
+    pub fn with_metric(map: &'a Map, metric: DistanceMetric) -> Graph<'a> {
+        let mut index = HashMap::with_capacity(map.nodes.len());
+        for (i, node) in map.nodes.iter().enumerate() {
+            index.insert(node.id, i);
+        }
+
+        let mut graph = Graph {
+            nodes: map.nodes.iter().collect(),
+            edges: Vec::new(),
+            metric,
+            index,
+            outgoing: vec![Vec::new(); map.nodes.len()],
+            base_costs: Vec::new()
+        };
+        for way in &map.ways {
+            let oneway = roads::oneway(way);
+            for pair in way.node_refs.windows(2) {
+                let from = graph.index.get(&pair[0].id).cloned();
+                let to = graph.index.get(&pair[1].id).cloned();
+                if let (Some(from), Some(to)) = (from, to) {
+                    if oneway != Oneway::Reverse {
+                        graph.add_edge(from, to, way.id, true);
+                    }
+                    if oneway != Oneway::Forward {
+                        graph.add_edge(to, from, way.id, false);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, way_id: i64, forward: bool) {
+        let distance = self.metric.distance(self.nodes[from].position(), self.nodes[to].position());
+        self.outgoing[from].push(self.edges.len());
+        self.edges.push(Edge { from, to, way_id, forward, distance, cost: distance });
+        self.base_costs.push(distance);
+    }
+
+    /// Changes the routing cost of an edge. Costs below the edge's distance
+    /// are raised to it, so that the straight-line distance stays a valid
+    /// lower bound for A*. Use an infinite cost to close the edge.
+    pub fn set_cost(&mut self, edge: usize, cost: f64) {
+        let edge = &mut self.edges[edge];
+        edge.cost = cost.max(edge.distance);
+    }
+
+    /// Cost of an edge before any changes made with `set_cost`: its
+    /// distance, unless changed with `set_base_cost`.
+    pub fn base_cost(&self, edge: usize) -> f64 {
+        self.base_costs[edge]
+    }
+
+    /// Changes the cost of an edge that `reset_costs` restores, for lasting
+    /// changes such as delays at control devices. If the edge's cost was
+    /// changed with `set_cost`, it is scaled along with the base cost, so
+    /// closed edges stay closed.
+    pub fn set_base_cost(&mut self, edge: usize, cost: f64) {
+        let cost = cost.max(self.edges[edge].distance);
+        let old = mem::replace(&mut self.base_costs[edge], cost);
+        let edge = &mut self.edges[edge];
+        edge.cost = if edge.cost == old { cost } else { (edge.cost / old * cost).max(edge.distance) };
+    }
+
+    /// Restores the cost of every edge to its base cost.
+    pub fn reset_costs(&mut self) {
+        for (edge, &cost) in self.edges.iter_mut().zip(&self.base_costs) {
+            edge.cost = cost;
+        }
+    }
+
+    pub fn node(&self, index: usize) -> &'a Node {
+        self.nodes[index]
+    }
+
+    /// Returns the edges leaving the node at `index`.
+    pub fn edges_from(&self, index: usize) -> Vec<&Edge> {
+        self.outgoing[index].iter().map(|&e| &self.edges[e]).collect()
+    }
+
+    /// Returns the indices into `edges` of the edges leaving the node at
+    /// `index`.
+    pub fn outgoing(&self, index: usize) -> &[usize] {
+        &self.outgoing[index]
+    }
+}
+
+impl<'a> RoutingGraph for Graph<'a> {
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn node_index(&self, node_id: i64) -> Option<usize> {
+        self.index.get(&node_id).cloned()
+    }
+
+    fn node_id(&self, index: usize) -> i64 {
+        self.nodes[index].id
+    }
+
+    fn position(&self, index: usize) -> (f64, f64) {
+        (self.nodes[index].lat, self.nodes[index].lon)
+    }
+
+    fn distance(&self, from: usize, to: usize) -> f64 {
+        self.metric.distance(self.nodes[from].position(), self.nodes[to].position())
+    }
+
+    fn neighbors(&self, index: usize) -> Vec<(usize, f64)> {
+        self.outgoing[index].iter().
+            filter(|&&e| self.edges[e].cost.is_finite()).
+            map(|&e| (self.edges[e].to, self.edges[e].cost)).
+            collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None,
+            changeset: 1, uid: Some(1), user: Some("viking".to_string()),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1,
+            uid: Some(1), user: Some("viking".to_string()),
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 10.0, maxlon: 10.0 },
+            nodes: vec![node(1, 5.0, 5.0), node(2, 5.1, 5.1), node(3, 5.2, 5.2)],
+            ways: vec![way(1, &[1, 2, 3])],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn graph_edges_in_both_directions() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        assert_eq!(4, graph.edges.len());
+
+        let middle = graph.node_index(2).unwrap();
+        let mut neighbors: Vec<i64> = graph.edges_from(middle).iter().
+            map(|e| graph.node(e.to).id).
+            collect();
+        neighbors.sort();
+        assert_eq!(vec![1, 3], neighbors);
+    }
+
+    #[test]
+    fn graph_follows_oneway() {
+        let mut map = create_map();
+        map.ways[0].tags.insert("oneway", "yes");
+        map.ways.push(way(2, &[3, 4]));
+        map.ways[1].tags.insert("oneway", "-1");
+        map.nodes.push(node(4, 5.3, 5.3));
+        let graph = Graph::new(&map);
+
+        let edges: Vec<(i64, i64, bool)> = graph.edges.iter().
+            map(|e| (graph.node(e.from).id, graph.node(e.to).id, e.forward)).
+            collect();
+        assert_eq!(vec![(1, 2, true), (2, 3, true), (4, 3, false)], edges);
+    }
+
+    #[test]
+    fn graph_uses_metric_for_bounds() {
+        let mut map = create_map();
+        let graph = Graph::new(&map);
+        assert_eq!(DistanceMetric::Haversine, graph.metric);
+
+        map.bounds = Bounds { minlat: 5.0, minlon: 5.0, maxlat: 5.2, maxlon: 5.2 };
+        let graph = Graph::new(&map);
+        match graph.metric {
+            DistanceMetric::Cheap(_) => {},
+            other => panic!("unexpected metric {:?}", other)
+        }
+        let (a, b) = (graph.node_index(1).unwrap(), graph.node_index(2).unwrap());
+        assert_eq!(graph.distance(a, b), graph.edges_from(a)[0].distance);
+    }
+
+    #[test]
+    fn graph_skips_missing_nodes() {
+        let mut map = create_map();
+        map.ways.push(way(2, &[3, 4]));
+        let graph = Graph::new(&map);
+        assert_eq!(4, graph.edges.len());
+        assert!(graph.node_index(4).is_none());
+    }
+}
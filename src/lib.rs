@@ -0,0 +1,108 @@
+//! Jamville loads OpenStreetMap data and finds routes over its road network.
+//!
+//! The usual flow is:
+//!
+//! 1. Load a map with `cache::load_cached`, which parses OSM XML or Overpass
+//!    JSON once and keeps a binary cache next to it, or with `loader::load` /
+//!    `loader::read_xml` when no cache is wanted.
+//! 2. Build a routing graph with `graph::Graph::new`.
+//! 3. Query it with `pathfinder::find_path_in_graph`, or use
+//!    `pathfinder::find_path` for one-off queries straight on the map.
+//! 4. Export the map with `loader::save_bin` to hand a binary copy to
+//!    another process, with `loader::save_xml` to open a filtered or updated
+//!    map in other OSM tools, or export just its routing graph with
+//!    `mapped::save`. Routing-only services can open that file instantly with
+//!    `mapped::MappedGraph::open` and query it like an in-memory graph, but
+//!    its edges cost their plain length: control delays, overrides and avoid
+//!    lists only apply to in-memory graphs.
+//!
+//! ```no_run
+//! extern crate jamville;
+//!
+//! use jamville::Graph;
+//! use jamville::cache::{self, BuildOptions};
+//! use jamville::pathfinder::find_path_in_graph;
+//!
+//! fn main() {
+//!     let options = BuildOptions::default();
+//!     let (map, _) = cache::load_cached("nashville.xml", "nashville.bin", &options).unwrap();
+//!
+//!     let graph = Graph::new(&map);
+//!     let path = find_path_in_graph(&graph, 37060116, 37060125);
+//!     println!("{:?}", path);
+//! }
+//! ```
+
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate lazy_static;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_xml_rs;
+extern crate bincode;
+extern crate memmap;
+extern crate flate2;
+extern crate bzip2;
+extern crate zstd;
+
+/// Structures mirroring the OSM XML format.
+pub mod osm;
+/// Areas assembled from closed ways and multipolygon relations.
+pub mod areas;
+/// Ways, nodes, areas and road classes to keep routes away from.
+pub mod avoid;
+/// Static traffic assignment.
+pub mod assignment;
+/// Versioned binary cache of parsed maps.
+pub mod cache;
+/// Applying OsmChange diffs to maps.
+pub mod changes;
+/// Cutting bounding box and polygon extracts out of maps.
+pub mod clip;
+/// Strongly connected components of routing graphs.
+pub mod components;
+/// Reading and writing gzip, bzip2 and zstd compressed files.
+pub mod compression;
+/// Intersections, traffic signals and other control devices.
+pub mod control;
+/// Trip generation for the simulation.
+pub mod demand;
+/// Map entities used by the rest of the crate.
+pub mod entities;
+/// Distances, bearings and other geodesy helpers.
+pub mod geo;
+/// Routing graph built from the ways of a map.
+pub mod graph;
+/// Reading and writing map files.
+pub mod loader;
+/// Memory-mapped routing graph files.
+pub mod mapped;
+/// Matching GPS traces onto the road network.
+pub mod matching;
+/// Time series, trajectories and reports from simulations.
+pub mod output;
+/// Live speed and closure overrides for routing.
+pub mod overrides;
+/// Structures mirroring the Overpass API JSON format.
+pub mod overpass;
+/// Shortest path search.
+pub mod pathfinder;
+/// Time-dependent speed profiles.
+pub mod profiles;
+/// Interpretation of road tags such as lanes and speed limits.
+pub mod roads;
+/// Microscopic traffic simulation.
+pub mod simulation;
+/// Grid index for finding the roads near a point.
+pub mod spatial;
+/// Travel speed estimation from matched GPS traces.
+pub mod speeds;
+/// Compact, interned storage for OSM tags.
+pub mod tags;
+/// Turn penalties and the edge-based graph they need.
+pub mod turns;
+/// Referential integrity checks of imported maps.
+pub mod validate;
+
+pub use entities::Map;
+pub use graph::{Graph, RoutingGraph};
+pub use pathfinder::find_path;
@@ -0,0 +1,297 @@
+// This module reads and writes maps in the supported file formats.
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::result;
+use bincode;
+use serde_json;
+use serde_xml_rs;
+use cache::{BuildOptions, Header};
+use compression::Compression;
+use entities::{format_timestamp, Map};
+use osm;
+use overpass;
+use tags::Tags;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Xml(serde_xml_rs::Error),
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    /// A malformed line in a text input file.
+    Parse(String),
+    InvalidCache(String),
+    UnknownFormat(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Xml(ref err) => write!(f, "XML error: {}", err),
+            Error::Bincode(ref err) => write!(f, "binary format error: {}", err),
+            Error::Json(ref err) => write!(f, "JSON error: {}", err),
+            Error::Parse(ref reason) => write!(f, "parse error: {}", reason),
+            Error::InvalidCache(ref reason) => write!(f, "invalid cache file: {}", reason),
+            Error::UnknownFormat(ref name) => write!(f, "unknown file format: {}", name)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_xml_rs::Error> for Error {
+    fn from(err: serde_xml_rs::Error) -> Error {
+        Error::Xml(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::Bincode(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
+
+/// Supported map file formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// OSM XML, as exported by the OSM website or Overpass.
+    Xml,
+    /// Overpass API JSON, as returned for `[out:json]` queries.
+    Json,
+    /// Binary cache written by `write_bin` or the `cache` module.
+    Bin,
+    /// Memory-mappable routing graph written by `mapped::write`. It holds no
+    /// map, so it can't be read with `load`.
+    Graph
+}
+
+impl Format {
+    /// Guesses the format from the file extension of `path`, looking past
+    /// the extension of a compressed file such as `.osm.bz2`.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match Compression::strip_extension(path).extension().and_then(|ext| ext.to_str()) {
+            Some("xml") | Some("osm") => Some(Format::Xml),
+            Some("json") => Some(Format::Json),
+            Some("bin") => Some(Format::Bin),
+            Some("graph") => Some(Format::Graph),
+            _ => None
+        }
+    }
+}
+
+/// Parses an OSM XML document.
+pub fn read_xml<R: Read>(reader: R) -> Result<Map> {
+    let osm_map: osm::Map = serde_xml_rs::from_reader(reader)?;
+    Ok(osm_map.into())
+}
+
+/// Parses an Overpass API JSON response.
+pub fn read_json<R: Read>(reader: R) -> Result<Map> {
+    let response: overpass::Response = serde_json::from_reader(reader)?;
+    let osm_map: osm::Map = response.into();
+    Ok(osm_map.into())
+}
+
+/// Reads a map from the binary cache format, verifying its header first.
+pub fn read_bin<R: Read>(mut reader: R) -> Result<Map> {
+    Header::read(&mut reader)?.read_map(BufReader::new(reader))
+}
+
+/// Writes a map in the binary cache format. The header records no source
+/// file; use `cache::load_cached` to get a cache that tracks its source.
+pub fn write_bin<W: Write>(mut writer: W, map: &Map) -> Result<()> {
+    Header::new(None, BuildOptions::default()).write(&mut writer)?;
+    write_map(writer, map)
+}
+
+/// Reads the map data that follows the header of a cache file.
+pub fn read_map<R: Read>(reader: R) -> Result<Map> {
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+/// Writes the map data that follows the header of a cache file.
+pub fn write_map<W: Write>(writer: W, map: &Map) -> Result<()> {
+    Ok(bincode::serialize_into(writer, map)?)
+}
+
+/// Loads a map from a file, picking the format by its extension. Files
+/// compressed with gzip, bzip2 or zstd are decompressed on the fly.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Map> {
+    let path = path.as_ref();
+    let format = Format::from_path(path).
+        ok_or_else(|| Error::UnknownFormat(path.display().to_string()))?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let compression = Compression::detect(&mut reader)?;
+    let reader = BufReader::new(compression.decoder(reader)?);
+    match format {
+        Format::Xml => read_xml(reader),
+        Format::Json => read_json(reader),
+        Format::Bin => read_bin(reader),
+        Format::Graph => Err(Error::UnknownFormat(format!(
+            "{} is a routing graph, not a map", path.display())))
+    }
+}
+
+/// Saves a map to a file in the binary cache format.
+pub fn save_bin<P: AsRef<Path>>(path: P, map: &Map) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_bin(&mut writer, map)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Saves a map to a file as OSM XML.
+pub fn save_xml<P: AsRef<Path>>(path: P, map: &Map) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_xml(&mut writer, map)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a map as an OSM XML document that `read_xml`, JOSM and other OSM
+/// tools can read. Names go back into the tags, which are written sorted by
+/// key.
+pub fn write_xml<W: Write>(mut writer: W, map: &Map) -> Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<osm version="{}" generator="{}">"#, escape(&map.version), escape(&map.generator))?;
+    writeln!(writer, "  <note>{}</note>", escape(&map.note))?;
+    writeln!(writer, r#"  <meta osm_base="{}"/>"#, escape(&map.meta.osm_base))?;
+    let bounds = &map.bounds;
+    writeln!(writer, r#"  <bounds minlat="{}" minlon="{}" maxlat="{}" maxlon="{}"/>"#,
+        bounds.minlat, bounds.minlon, bounds.maxlat, bounds.maxlon)?;
+
+    for node in &map.nodes {
+        write!(writer, r#"  <node id="{}" lat="{}" lon="{}""#, node.id, node.lat, node.lon)?;
+        write_attributes(&mut writer, node.version, node.timestamp, node.changeset, node.uid, &node.user)?;
+        let tags = sorted_tags(&node.name, &node.tags);
+        if tags.is_empty() {
+            writeln!(writer, "/>")?;
+        } else {
+            writeln!(writer, ">")?;
+            write_tags(&mut writer, &tags)?;
+            writeln!(writer, "  </node>")?;
+        }
+    }
+    for way in &map.ways {
+        write!(writer, r#"  <way id="{}""#, way.id)?;
+        write_attributes(&mut writer, way.version, way.timestamp, way.changeset, way.uid, &way.user)?;
+        writeln!(writer, ">")?;
+        for node_ref in &way.node_refs {
+            writeln!(writer, r#"    <nd ref="{}"/>"#, node_ref.id)?;
+        }
+        write_tags(&mut writer, &sorted_tags(&way.name, &way.tags))?;
+        writeln!(writer, "  </way>")?;
+    }
+    for relation in &map.relations {
+        write!(writer, r#"  <relation id="{}""#, relation.id)?;
+        write_attributes(&mut writer, relation.version, relation.timestamp, relation.changeset, relation.uid, &relation.user)?;
+        writeln!(writer, ">")?;
+        for member in &relation.members {
+            writeln!(writer, r#"    <member type="{}" ref="{}" role="{}"/>"#,
+                escape(&member.kind), member.id, escape(&member.role))?;
+        }
+        write_tags(&mut writer, &sorted_tags(&relation.name, &relation.tags))?;
+        writeln!(writer, "  </relation>")?;
+    }
+    writeln!(writer, "</osm>")?;
+    Ok(())
+}
+
+fn write_attributes<W: Write>(writer: &mut W, version: u16, timestamp: Option<i64>, changeset: u64, uid: Option<i64>, user: &Option<String>) -> Result<()> {
+    write!(writer, r#" version="{}""#, version)?;
+    if let Some(timestamp) = timestamp {
+        write!(writer, r#" timestamp="{}""#, format_timestamp(timestamp))?;
+    }
+    write!(writer, r#" changeset="{}""#, changeset)?;
+    if let Some(uid) = uid {
+        write!(writer, r#" uid="{}""#, uid)?;
+    }
+    if let Some(ref user) = *user {
+        write!(writer, r#" user="{}""#, escape(user))?;
+    }
+    Ok(())
+}
+
+fn sorted_tags<'a>(name: &'a Option<String>, tags: &Tags) -> Vec<(&'a str, &'a str)> {
+    let mut sorted = tags.sorted();
+    if let Some(ref name) = *name {
+        sorted.push(("name", name.as_str()));
+        sorted.sort_by_key(|&(key, _)| key);
+    }
+    sorted
+}
+
+fn write_tags<W: Write>(writer: &mut W, tags: &[(&str, &str)]) -> Result<()> {
+    for &(key, value) in tags {
+        writeln!(writer, r#"    <tag k="{}" v="{}"/>"#, escape(key), escape(value))?;
+    }
+    Ok(())
+}
+
+/// Escapes text for use in XML attributes and elements. Tabs and line
+/// breaks are escaped too, since parsers normalize them in attributes.
+fn escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(&['&', '<', '>', '"', '\'', '\t', '\n', '\r'][..]) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c => escaped.push(c)
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Some(Format::Xml), Format::from_path(Path::new("foo.xml")));
+        assert_eq!(Some(Format::Xml), Format::from_path(Path::new("foo.osm")));
+        assert_eq!(Some(Format::Json), Format::from_path(Path::new("foo.json")));
+        assert_eq!(Some(Format::Xml), Format::from_path(Path::new("foo.osm.bz2")));
+        assert_eq!(None, Format::from_path(Path::new("foo.gz")));
+        assert_eq!(Some(Format::Bin), Format::from_path(Path::new("foo.bin")));
+        assert_eq!(Some(Format::Graph), Format::from_path(Path::new("foo.graph")));
+        assert_eq!(None, Format::from_path(Path::new("foo.txt")));
+        assert_eq!(None, Format::from_path(Path::new("foo")));
+    }
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!("Main Street", escape("Main Street"));
+        assert_eq!("Tom &amp; Jerry&apos;s &lt;&quot;Café&quot;&gt;&#10;", escape("Tom & Jerry's <\"Café\">\n"));
+    }
+}
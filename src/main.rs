@@ -1,57 +1,101 @@
-#[macro_use] extern crate serde_derive;
-extern crate serde_xml_rs;
-extern crate bincode;
-mod osm;
-mod entities;
-mod pathfinder;
+extern crate jamville;
 
 use std::env;
-use std::fs::File;
 use std::path::Path;
-use pathfinder::find_path;
+use jamville::avoid::Avoid;
+use jamville::cache::{self, BuildOptions, Status};
+use jamville::compression::Compression;
+use jamville::loader::Format;
+use jamville::mapped::{self, MappedGraph};
+use jamville::pathfinder::{find_path_avoiding, find_path_in_graph};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = Vec::new();
+    let mut avoid = Avoid::new();
+    let mut build_options = BuildOptions::default();
+    let mut graph_path: Option<String> = None;
+    let mut options = env::args();
+    while let Some(arg) = options.next() {
+        let result = match arg.as_str() {
+            "--avoid" => options.next().map(|list| avoid.add_list(&list)),
+            "--avoid-polygon" => options.next().map(|polygon| avoid.add_polygon_str(&polygon)),
+            "--compress" => options.next().map(|name| name.parse().map(|compression| build_options.compression = compression)),
+            "--save-graph" => options.next().map(|path| {
+                graph_path = Some(path);
+                Ok(())
+            }),
+            _ => {
+                args.push(arg);
+                continue
+            }
+        };
+        match result {
+            Some(Ok(())) => {},
+            Some(Err(err)) => {
+                println!("{}", err);
+                return
+            },
+            None => args.clear()
+        }
+    }
     if args.len() != 4 {
-        println!("Syntax: {} <filename> <start-id> <end-id>", args[0]);
+        println!("Syntax: {} [--avoid <list>] [--avoid-polygon <lat,lon;...>] [--compress <method>] [--save-graph <file>] <filename> <start-id> <end-id>",
+            env::args().next().unwrap_or_default());
+        println!("  <list> holds tolls, ferries, motorways, tunnels, bridges, unpaved,");
+        println!("  key=value tags, tag:<key>, way:<id> or node:<id>, separated by commas");
+        println!("  <method> is gzip, bzip2 or zstd, for compressing the binary cache");
+        println!("  --save-graph exports the routing graph for opening later; routes over a");
+        println!("  graph file only follow distances, without signal delays or avoid lists");
         return
     }
     let start_id = args[2].parse().unwrap();
     let end_id = args[3].parse().unwrap();
 
     let path = Path::new(&args[1]);
-    let ext = path.extension().unwrap().to_str().unwrap();
-    let map = match ext {
-        "xml" => {
-            let infile = File::open(path).unwrap();
-            println!("Importing XML data...");
-            let osm_map: osm::Map = serde_xml_rs::from_reader(infile).unwrap();
-
-            println!("Exporting binary data for later use...");
-            let stem = path.file_stem().unwrap().to_str().unwrap();
+    let source = Compression::strip_extension(path);
+    let stem = source.file_stem().unwrap().to_str().unwrap();
+    let (map, status) = match Format::from_path(path) {
+        Some(Format::Xml) | Some(Format::Json) => {
             let filename = format!("{}.bin", stem);
-            let outfile = File::create(filename).unwrap();
-            let result: entities::Map = osm_map.into();
-            bincode::serialize_into(outfile, &result).unwrap();
-            result
+            cache::load_cached(path, filename, &build_options).unwrap()
         },
-        "bin" => {
-            let infile = File::open(path).unwrap();
-            println!("Importing binary data...");
-            let result: entities::Map = bincode::deserialize_from(infile).unwrap();
-            result
-        }
-        _ => {
+        Some(Format::Bin) => {
+            cache::open(path).unwrap()
+        },
+        Some(Format::Graph) => {
+            if !avoid.is_empty() {
+                println!("Routing graph files don't keep the tags needed to avoid roads, use a map file");
+                return
+            }
+            println!("Opening routing graph...");
+            let graph = MappedGraph::open(path).unwrap();
+            print_path(start_id, end_id, find_path_in_graph(&graph, start_id, end_id));
+            return
+        },
+        None => {
             panic!("Invalid input file!");
         }
     };
+    match status {
+        Status::Fresh => println!("Imported binary data"),
+        Status::Rebuilt => println!("Imported map data and exported binary data for later use")
+    }
+    if let Some(graph_path) = graph_path {
+        match mapped::save(&graph_path, &map) {
+            Ok(()) => println!("Exported routing graph to {}", graph_path),
+            Err(err) => println!("Couldn't export routing graph: {}", err)
+        }
+    }
 
     println!("Data summary:");
     println!("Number of nodes: {}", map.nodes.len());
     println!("Number of ways: {}", map.ways.len());
     println!("Number of relations: {}", map.relations.len());
 
-    let path = find_path(&map, start_id, end_id);
+    print_path(start_id, end_id, find_path_avoiding(&map, start_id, end_id, &avoid));
+}
+
+fn print_path(start_id: i64, end_id: i64, path: Option<Vec<i64>>) {
     match path {
         Some(path) => {
             println!("=== Path between {} and {} ===", start_id, end_id);
@@ -0,0 +1,418 @@
+// This module writes the routing graph in a fixed-size on-disk layout that can
+// be memory mapped and queried without deserializing anything up front. The
+// operating system shares the mapped pages between processes that open the
+// same file. Edges cost their length: delays at control devices, overrides
+// and profiles need an in-memory `Graph`.
+//
+// Layout (all integers little-endian):
+//
+//   header   magic "JAMVGRPH", format version (u32), node count (u32),
+//            edge count (u32), string table length (u32), cheap ruler
+//            factors kx and ky (f64, both zero for haversine)
+//   nodes    node count x { id: i64, lat: f64, lon: f64 }, sorted by id
+//   offsets  (node count + 1) x u32, index of each node's first edge
+//   edges    edge count x { way_id: i64, to: u32, name: u32, distance: f64 }
+//   strings  way names, each as a u32 byte length followed by UTF-8 bytes
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str;
+use memmap::Mmap;
+use entities::Map;
+use geo::{CheapRuler, DistanceMetric};
+use graph::{Graph, RoutingGraph};
+use loader::{Error, Result};
+
+pub const MAGIC: [u8; 8] = *b"JAMVGRPH";
+pub const FORMAT_VERSION: u32 = 3;
+
+const HEADER_SIZE: usize = 40;
+const NODE_SIZE: usize = 24;
+const OFFSET_SIZE: usize = 4;
+const EDGE_SIZE: usize = 24;
+const NO_NAME: u32 = u32::MAX;
+
+/// Writes the routing graph of `map` in the memory-mappable layout.
+pub fn write<W: Write>(mut writer: W, map: &Map) -> Result<()> {
+    let graph = Graph::new(map);
+
+    // Nodes are stored sorted by id so lookups can binary search the file.
+    let mut order: Vec<usize> = (0..graph.node_count()).collect();
+    order.sort_by_key(|&i| graph.node_id(i));
+    let mut position = vec![0u32; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        position[old] = new as u32;
+    }
+
+    let mut strings = Vec::new();
+    let mut name_offsets = Vec::with_capacity(map.ways.len());
+    for way in &map.ways {
+        let offset = match way.name {
+            Some(ref name) => {
+                let offset = strings.len() as u32;
+                strings.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                strings.extend_from_slice(name.as_bytes());
+                offset
+            },
+            None => NO_NAME
+        };
+        name_offsets.push((way.id, offset));
+    }
+    name_offsets.sort();
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&(order.len() as u32).to_le_bytes())?;
+    writer.write_all(&(graph.edges.len() as u32).to_le_bytes())?;
+    writer.write_all(&(strings.len() as u32).to_le_bytes())?;
+    let (kx, ky) = match graph.metric {
+        DistanceMetric::Haversine => (0.0, 0.0),
+        DistanceMetric::Cheap(ruler) => ruler.factors()
+    };
+    writer.write_all(&kx.to_bits().to_le_bytes())?;
+    writer.write_all(&ky.to_bits().to_le_bytes())?;
+
+    for &i in &order {
+        let node = graph.node(i);
+        writer.write_all(&node.id.to_le_bytes())?;
+        writer.write_all(&node.lat.to_bits().to_le_bytes())?;
+        writer.write_all(&node.lon.to_bits().to_le_bytes())?;
+    }
+
+    let mut offset = 0u32;
+    for &i in &order {
+        writer.write_all(&offset.to_le_bytes())?;
+        offset += graph.edges_from(i).len() as u32;
+    }
+    writer.write_all(&offset.to_le_bytes())?;
+
+    for &i in &order {
+        for edge in graph.edges_from(i) {
+            let name = name_offsets.binary_search_by_key(&edge.way_id, |&(id, _)| id).
+                map(|j| name_offsets[j].1).
+                unwrap_or(NO_NAME);
+            writer.write_all(&edge.way_id.to_le_bytes())?;
+            writer.write_all(&position[edge.to].to_le_bytes())?;
+            writer.write_all(&name.to_le_bytes())?;
+            writer.write_all(&edge.distance.to_bits().to_le_bytes())?;
+        }
+    }
+
+    writer.write_all(&strings)?;
+    Ok(())
+}
+
+/// Writes the routing graph of `map` to a file.
+pub fn save<P: AsRef<Path>>(path: P, map: &Map) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, map)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A routing graph backed by a memory-mapped file written by `write`.
+pub struct MappedGraph {
+    mmap: Mmap,
+    metric: DistanceMetric,
+    node_count: usize,
+    edge_count: usize,
+    nodes_start: usize,
+    offsets_start: usize,
+    edges_start: usize,
+    strings_start: usize
+}
+
+impl MappedGraph {
+    /// Maps the file at `path` and checks that its header and size are
+    /// consistent and that the edges only point at nodes, edges and names
+    /// inside the file, so that queries can't read past its end. Node
+    /// positions and names are only read when queried.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedGraph> {
+        let file = File::open(path)?;
+        // The mapping is read-only; it is only invalidated if another process
+        // truncates the file while it is open.
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < HEADER_SIZE || mmap[0..8] != MAGIC {
+            return Err(Error::InvalidCache("not a jamville graph file".to_string()));
+        }
+
+        let version = read_u32(&mmap, 8);
+        if version != FORMAT_VERSION {
+            return Err(Error::InvalidCache(format!(
+                "graph format version {} (expected {})", version, FORMAT_VERSION)));
+        }
+        let node_count = read_u32(&mmap, 12) as usize;
+        let edge_count = read_u32(&mmap, 16) as usize;
+        let strings_len = read_u32(&mmap, 20) as usize;
+        let (kx, ky) = (read_f64(&mmap, 24), read_f64(&mmap, 32));
+        let metric = if kx == 0.0 && ky == 0.0 {
+            DistanceMetric::Haversine
+        } else {
+            DistanceMetric::Cheap(CheapRuler::from_factors(kx, ky))
+        };
+
+        let nodes_start = HEADER_SIZE;
+        let offsets_start = nodes_start + node_count * NODE_SIZE;
+        let edges_start = offsets_start + (node_count + 1) * OFFSET_SIZE;
+        let strings_start = edges_start + edge_count * EDGE_SIZE;
+        if mmap.len() != strings_start + strings_len {
+            return Err(Error::InvalidCache("graph file has the wrong size".to_string()));
+        }
+
+        let graph = MappedGraph {
+            mmap, metric, node_count, edge_count, nodes_start, offsets_start, edges_start, strings_start
+        };
+        graph.validate(strings_len)?;
+        Ok(graph)
+    }
+
+    fn validate(&self, strings_len: usize) -> Result<()> {
+        let invalid = |reason: String| Err(Error::InvalidCache(reason));
+        let mut previous = 0;
+        for index in 0..=self.node_count {
+            let offset = read_u32(&self.mmap, self.offsets_start + index * OFFSET_SIZE) as usize;
+            if offset < previous || offset > self.edge_count || (index == 0 && offset != 0) {
+                return invalid(format!("graph file has an invalid edge offset for node {}", index));
+            }
+            previous = offset;
+        }
+        if previous != self.edge_count {
+            return invalid("graph file edge offsets don't cover all edges".to_string());
+        }
+        for e in 0..self.edge_count {
+            let start = self.edges_start + e * EDGE_SIZE;
+            if read_u32(&self.mmap, start + 8) as usize >= self.node_count {
+                return invalid(format!("graph file edge {} leads to a missing node", e));
+            }
+            let name = read_u32(&self.mmap, start + 12);
+            if name != NO_NAME {
+                let name = name as usize;
+                let valid = name + 4 <= strings_len &&
+                    name + 4 + read_u32(&self.mmap, self.strings_start + name) as usize <= strings_len;
+                if !valid {
+                    return invalid(format!("graph file edge {} has an invalid name offset", e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edge_count
+    }
+
+    /// Returns the way id and name of every edge leaving a node, in the same
+    /// order as `neighbors`.
+    pub fn edge_ways(&self, index: usize) -> Vec<(i64, Option<&str>)> {
+        self.edge_range(index).map(|e| {
+            let start = self.edges_start + e * EDGE_SIZE;
+            (read_i64(&self.mmap, start), self.name(read_u32(&self.mmap, start + 12)))
+        }).collect()
+    }
+
+    fn edge_range(&self, index: usize) -> ::std::ops::Range<usize> {
+        let start = self.offsets_start + index * OFFSET_SIZE;
+        read_u32(&self.mmap, start) as usize..read_u32(&self.mmap, start + OFFSET_SIZE) as usize
+    }
+
+    fn name(&self, offset: u32) -> Option<&str> {
+        if offset == NO_NAME {
+            return None;
+        }
+        let start = self.strings_start + offset as usize;
+        let len = read_u32(&self.mmap, start) as usize;
+        str::from_utf8(&self.mmap[start + 4..start + 4 + len]).ok()
+    }
+}
+
+impl RoutingGraph for MappedGraph {
+    fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    fn node_index(&self, node_id: i64) -> Option<usize> {
+        let (mut low, mut high) = (0, self.node_count);
+        while low < high {
+            let mid = (low + high) / 2;
+            let id = self.node_id(mid);
+            if id < node_id {
+                low = mid + 1;
+            } else if id > node_id {
+                high = mid;
+            } else {
+                return Some(mid);
+            }
+        }
+        None
+    }
+
+    fn node_id(&self, index: usize) -> i64 {
+        read_i64(&self.mmap, self.nodes_start + index * NODE_SIZE)
+    }
+
+    fn position(&self, index: usize) -> (f64, f64) {
+        let start = self.nodes_start + index * NODE_SIZE;
+        (read_f64(&self.mmap, start + 8), read_f64(&self.mmap, start + 16))
+    }
+
+    fn distance(&self, from: usize, to: usize) -> f64 {
+        self.metric.distance(self.position(from), self.position(to))
+    }
+
+    fn neighbors(&self, index: usize) -> Vec<(usize, f64)> {
+        self.edge_range(index).map(|e| {
+            let start = self.edges_start + e * EDGE_SIZE;
+            (read_u32(&self.mmap, start + 8) as usize, read_f64(&self.mmap, start + 16))
+        }).collect()
+    }
+}
+
+fn read_u32(bytes: &[u8], start: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[start..start + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_i64(bytes: &[u8], start: usize) -> i64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[start..start + 8]);
+    i64::from_le_bytes(buf)
+}
+
+fn read_f64(bytes: &[u8], start: usize) -> f64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[start..start + 8]);
+    f64::from_bits(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tags::Tags;
+    use std::env;
+    use std::fs;
+    use entities::*;
+    use pathfinder::find_path_in_graph;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None,
+            changeset: 1, uid: Some(1), user: Some("viking".to_string()),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, name: Option<&str>, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1,
+            uid: Some(1), user: Some("viking".to_string()),
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: name.map(|n| n.to_string()), tags: Tags::new()
+        }
+    }
+
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 10.0, maxlon: 10.0 },
+            nodes: vec![node(30, 5.2, 5.2), node(10, 5.0, 5.0), node(20, 5.1, 5.1), node(40, 6.0, 6.0)],
+            ways: vec![way(1, Some("Main Street"), &[10, 20]), way(2, None, &[20, 30])],
+            relations: Vec::new()
+        }
+    }
+
+    fn write_temp(name: &str, map: &Map) -> MappedGraph {
+        let path = env::temp_dir().join(format!("jamville-mapped-{}.graph", name));
+        save(&path, map).unwrap();
+        MappedGraph::open(&path).unwrap()
+    }
+
+    #[test]
+    fn mapped_graph_matches_in_memory_graph() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let mapped = write_temp("matches", &map);
+
+        assert_eq!(graph.node_count(), mapped.node_count());
+        assert_eq!(graph.edges.len(), mapped.edge_count());
+        for i in 0..graph.node_count() {
+            let id = graph.node_id(i);
+            let j = mapped.node_index(id).expect("missing node");
+            assert_eq!(id, mapped.node_id(j));
+            assert_eq!(graph.position(i), mapped.position(j));
+
+            let mut expected: Vec<(i64, f64)> = graph.neighbors(i).into_iter().
+                map(|(n, cost)| (graph.node_id(n), cost)).collect();
+            let mut actual: Vec<(i64, f64)> = mapped.neighbors(j).into_iter().
+                map(|(n, cost)| (mapped.node_id(n), cost)).collect();
+            expected.sort_by_key(|&(id, _)| id);
+            actual.sort_by_key(|&(id, _)| id);
+            assert_eq!(expected, actual);
+        }
+        assert_eq!(None, mapped.node_index(50));
+
+        let (a, b) = (mapped.node_index(10).unwrap(), mapped.node_index(40).unwrap());
+        assert_eq!(graph.distance(graph.node_index(10).unwrap(), graph.node_index(40).unwrap()),
+                   mapped.distance(a, b));
+    }
+
+    #[test]
+    fn mapped_graph_way_names() {
+        let map = create_map();
+        let mapped = write_temp("names", &map);
+        let index = mapped.node_index(20).unwrap();
+        let mut ways = mapped.edge_ways(index);
+        ways.sort();
+        assert_eq!(vec![(1, Some("Main Street")), (2, None)], ways);
+    }
+
+    #[test]
+    fn mapped_graph_find_path() {
+        let map = create_map();
+        let mapped = write_temp("find-path", &map);
+        assert_eq!(Some(vec![10, 20, 30]), find_path_in_graph(&mapped, 10, 30));
+        assert_eq!(None, find_path_in_graph(&mapped, 10, 40));
+    }
+
+    #[test]
+    fn mapped_graph_rejects_other_files() {
+        let path = env::temp_dir().join("jamville-mapped-garbage.graph");
+        fs::write(&path, b"definitely not a graph file").unwrap();
+        match MappedGraph::open(&path) {
+            Err(Error::InvalidCache(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("garbage accepted")
+        }
+    }
+
+    #[test]
+    fn mapped_graph_rejects_corrupt_edges() {
+        let mut data = Vec::new();
+        write(&mut data, &create_map()).unwrap();
+        let node_count = read_u32(&data, 12) as usize;
+        let offsets_start = HEADER_SIZE + node_count * NODE_SIZE;
+        let edges_start = offsets_start + (node_count + 1) * OFFSET_SIZE;
+
+        let corruptions: Vec<(usize, u32)> = vec![
+            // An edge leading past the last node
+            (edges_start + 8, node_count as u32),
+            // Offsets running backwards
+            (offsets_start + OFFSET_SIZE, u32::MAX),
+            // A name past the end of the string table
+            (edges_start + 12, 1 << 20)
+        ];
+        for (i, &(position, value)) in corruptions.iter().enumerate() {
+            let mut corrupt = data.clone();
+            corrupt[position..position + 4].copy_from_slice(&value.to_le_bytes());
+            let path = env::temp_dir().join(format!("jamville-mapped-corrupt-{}.graph", i));
+            fs::write(&path, &corrupt).unwrap();
+            match MappedGraph::open(&path) {
+                Err(Error::InvalidCache(_)) => {},
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("corruption {} accepted", i)
+            }
+        }
+    }
+}
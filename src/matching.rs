@@ -0,0 +1,507 @@
+// This module matches GPS traces onto the road network with the hidden Markov
+// model of Newson & Krumm ("Hidden Markov Map Matching Through Noise and
+// Sparseness", 2009). Every point of a trace may lie on any road segment
+// near it; a candidate is more likely the closer it is to the point, and a
+// move between candidates is more likely the closer the route between them
+// comes to the straight-line distance between the points. The Viterbi
+// algorithm picks the most likely sequence of candidates, and the routes
+// between them make up the matched path.
+//
+// Traces are read from GPX track points or from CSV files with one
+// `time,lat,lon` line per point, where the time is either an OSM-style
+// timestamp or seconds since the Unix epoch.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use serde_xml_rs;
+use entities::parse_timestamp;
+use graph::{Graph, RoutingGraph};
+use loader::{Error, Result};
+use pathfinder::shortest_paths_within;
+use spatial::{Snap, SpatialIndex};
+
+/// A timestamped GPS fix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpsPoint {
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    pub lat: f64,
+    pub lon: f64
+}
+
+impl GpsPoint {
+    pub fn position(&self) -> (f64, f64) {
+        (self.lat, self.lon)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Standard deviation of GPS noise in meters.
+    pub sigma: f64,
+    /// Scale in meters of the difference between route and straight-line
+    /// distance that makes a move `e` times less likely.
+    pub beta: f64,
+    /// Roads further than this many meters from a point aren't candidates.
+    pub search_radius: f64,
+    pub max_candidates: usize,
+    /// Moves that would need a higher speed in m/s are impossible.
+    pub max_speed: f64
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { sigma: 4.07, beta: 5.0, search_radius: 50.0, max_candidates: 8, max_speed: 60.0 }
+    }
+}
+
+/// A stretch of matched path.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Route {
+    /// Ids of the nodes driven through, starting and ending with the ends of
+    /// the first and last matched edges.
+    pub nodes: Vec<i64>,
+    /// Ids of the ways driven along, in order, without repeats in a row.
+    pub ways: Vec<i64>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matching {
+    /// Where each point of the trace was matched, or `None` for points that
+    /// were left out, being too close to the previous point or too far from
+    /// any road.
+    pub points: Vec<Option<Snap>>,
+    /// For every matched point but the first of each route, the indices of
+    /// the edges driven from the edge of the previous matched point to its
+    /// own, leaving both out; `None` for the other points.
+    pub legs: Vec<Option<Vec<usize>>>,
+    /// The matched path. It is broken into several routes where the trace
+    /// can't be followed along the roads, for example after a gap.
+    pub routes: Vec<Route>
+}
+
+// Candidates of one matched point, with the Viterbi scores and, for every
+// candidate, the edges driven to it from its best predecessor
+struct Layer {
+    point: usize,
+    candidates: Vec<Snap>,
+    scores: Vec<f64>,
+    back: Vec<usize>,
+    legs: Vec<Vec<usize>>
+}
+
+/// Routes between candidates are searched no further than this many times
+/// the straight-line distance between their points, plus twice the search
+/// radius, when the time between the points doesn't limit them.
+const MAX_DETOUR: f64 = 4.0;
+
+/// Map matcher for one routing graph.
+#[derive(Debug)]
+pub struct Matcher<'g, 'a: 'g> {
+    pub config: Config,
+    graph: &'g Graph<'a>,
+    index: SpatialIndex
+}
+
+impl<'g, 'a> Matcher<'g, 'a> {
+    pub fn new(graph: &'g Graph<'a>, config: Config) -> Matcher<'g, 'a> {
+        let index = SpatialIndex::new(graph, config.search_radius.max(10.0));
+        Matcher { config, graph, index }
+    }
+
+    pub fn graph(&self) -> &'g Graph<'a> {
+        self.graph
+    }
+
+    /// Matches a trace, whose points must be in time order.
+    pub fn match_trace(&self, trace: &[GpsPoint]) -> Matching {
+        let mut matching = Matching { points: vec![None; trace.len()], legs: vec![None; trace.len()], routes: Vec::new() };
+        let mut layers: Vec<Layer> = Vec::new();
+        let mut last: Option<usize> = None;
+
+        for (i, point) in trace.iter().enumerate() {
+            // Points within two standard deviations of the previous one add
+            // nothing but noise
+            if let Some(last) = last {
+                if self.graph.metric.distance(trace[last].position(), point.position()) < 2.0 * self.config.sigma {
+                    continue;
+                }
+            }
+            let candidates: Vec<Snap> = self.index.edges_within(self.graph, point.position(), self.config.search_radius).
+                into_iter().
+                filter(|snap| self.graph.edges[snap.edge].cost.is_finite()).
+                take(self.config.max_candidates).
+                collect();
+            if candidates.is_empty() {
+                continue;
+            }
+            last = Some(i);
+            let emissions: Vec<f64> = candidates.iter().
+                map(|snap| -0.5 * (snap.distance / self.config.sigma).powi(2)).
+                collect();
+
+            let layer = match layers.last() {
+                None => None,
+                Some(previous) => {
+                    let layer = self.next_layer(previous, trace, i, candidates.clone(), &emissions);
+                    if layer.scores.iter().all(|score| !score.is_finite()) { None } else { Some(layer) }
+                }
+            };
+            let layer = match layer {
+                Some(layer) => layer,
+                None => {
+                    // Start over from this point
+                    self.finish(&layers, &mut matching);
+                    layers.clear();
+                    let legs = vec![Vec::new(); candidates.len()];
+                    Layer { point: i, candidates, scores: emissions, back: Vec::new(), legs }
+                }
+            };
+            layers.push(layer);
+        }
+        self.finish(&layers, &mut matching);
+        matching
+    }
+
+    fn next_layer(&self, previous: &Layer, trace: &[GpsPoint], point: usize, candidates: Vec<Snap>, emissions: &[f64]) -> Layer {
+        let from = &trace[previous.point];
+        let to = &trace[point];
+        let straight = self.graph.metric.distance(from.position(), to.position());
+        let elapsed = (to.time - from.time) as f64;
+        let limit = if elapsed > 0.0 {
+            elapsed * self.config.max_speed
+        } else {
+            MAX_DETOUR * straight + 2.0 * self.config.search_radius
+        };
+
+        let mut best = vec![(f64::NEG_INFINITY, 0, Vec::new()); candidates.len()];
+        for (a, origin) in previous.candidates.iter().enumerate() {
+            if !previous.scores[a].is_finite() {
+                continue;
+            }
+            let routes = self.routes(origin, &candidates, limit);
+            for (b, route) in routes.into_iter().enumerate() {
+                let (edges, distance) = match route {
+                    Some(route) => route,
+                    None => continue
+                };
+                let score = previous.scores[a] - (distance - straight).abs() / self.config.beta;
+                if score > best[b].0 {
+                    best[b] = (score, a, edges);
+                }
+            }
+        }
+
+        let mut scores = Vec::with_capacity(candidates.len());
+        let mut back = Vec::with_capacity(candidates.len());
+        let mut legs = Vec::with_capacity(candidates.len());
+        for ((score, a, edges), emission) in best.into_iter().zip(emissions) {
+            scores.push(score + emission);
+            back.push(a);
+            legs.push(edges);
+        }
+        Layer { point, candidates, scores, back, legs }
+    }
+
+    // Returns, for every candidate, the edges driven to it from `from`
+    // between the two matched edges and the distance driven, searching the
+    // roads no further than `limit` meters. Closed edges aren't driven on.
+    fn routes(&self, from: &Snap, candidates: &[Snap], limit: f64) -> Vec<Option<(Vec<usize>, f64)>> {
+        let edges = &self.graph.edges;
+        let a = &edges[from.edge];
+        let offset = (1.0 - from.fraction) * a.distance;
+        let reached = shortest_paths_within(self.graph, a.to, limit - offset, |e| {
+            if edges[e].cost.is_finite() { edges[e].distance } else { f64::INFINITY }
+        });
+        candidates.iter().map(|to| {
+            if from.edge == to.edge && to.fraction >= from.fraction {
+                return Some((Vec::new(), (to.fraction - from.fraction) * a.distance));
+            }
+            let b = &edges[to.edge];
+            let &(length, _) = reached.get(&b.from)?;
+            let distance = offset + length + to.fraction * b.distance;
+            if distance > limit {
+                return None;
+            }
+            let mut path = Vec::new();
+            let mut node = b.from;
+            while let Some(&(_, Some(e))) = reached.get(&node) {
+                path.push(e);
+                node = edges[e].from;
+            }
+            path.reverse();
+            Some((path, distance))
+        }).collect()
+    }
+
+    // Picks the most likely candidates of a stretch of layers and adds them
+    // and the route through them to `matching`.
+    fn finish(&self, layers: &[Layer], matching: &mut Matching) {
+        let last = match layers.last() {
+            Some(last) => last,
+            None => return
+        };
+        let mut chosen = vec![0; layers.len()];
+        chosen[layers.len() - 1] = (0..last.scores.len()).
+            max_by(|&a, &b| last.scores[a].partial_cmp(&last.scores[b]).unwrap()).
+            unwrap_or(0);
+        for l in (1..layers.len()).rev() {
+            chosen[l - 1] = layers[l].back[chosen[l]];
+        }
+
+        let graph = self.graph;
+        let snaps: Vec<&Snap> = layers.iter().zip(&chosen).map(|(layer, &c)| &layer.candidates[c]).collect();
+        let first = &graph.edges[snaps[0].edge];
+        let mut nodes = vec![first.from, first.to];
+        for (l, pair) in snaps.windows(2).enumerate() {
+            if pair[0].edge == pair[1].edge && pair[1].fraction >= pair[0].fraction {
+                continue;
+            }
+            let leg = &layers[l + 1].legs[chosen[l + 1]];
+            nodes.extend(leg.iter().map(|&e| graph.edges[e].to));
+            nodes.push(graph.edges[pair[1].edge].to);
+        }
+        // Leave out the ends of edges the trace starts at the end of or
+        // stops at the start of
+        if snaps[0].fraction > 1.0 - 1e-6 {
+            nodes.remove(0);
+        }
+        if snaps[snaps.len() - 1].fraction < 1e-6 && nodes.len() > 1 {
+            nodes.pop();
+        }
+        let mut ways: Vec<i64> = Vec::new();
+        for pair in nodes.windows(2) {
+            let way = graph.outgoing(pair[0]).iter().
+                map(|&e| &graph.edges[e]).
+                filter(|edge| edge.to == pair[1]).
+                min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap()).
+                map(|edge| edge.way_id);
+            if let Some(way) = way {
+                if ways.last() != Some(&way) {
+                    ways.push(way);
+                }
+            }
+        }
+
+        for (l, (layer, snap)) in layers.iter().zip(snaps).enumerate() {
+            matching.points[layer.point] = Some(snap.clone());
+            if l > 0 {
+                matching.legs[layer.point] = Some(layer.legs[chosen[l]].clone());
+            }
+        }
+        let nodes = nodes.into_iter().map(|node| graph.node_id(node)).collect();
+        matching.routes.push(Route { nodes, ways });
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename = "gpx")]
+struct Gpx {
+    #[serde(rename = "trk", default)]
+    tracks: Vec<Track>
+}
+
+#[derive(Debug, Deserialize)]
+struct Track {
+    #[serde(rename = "trkseg", default)]
+    segments: Vec<TrackSegment>
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackSegment {
+    #[serde(rename = "trkpt", default)]
+    points: Vec<TrackPoint>
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackPoint {
+    lat: f64,
+    lon: f64,
+    time: Option<String>
+}
+
+/// Reads the track points of a GPX file, all tracks and segments in a row.
+/// Every point needs a time.
+pub fn read_gpx<R: Read>(reader: R) -> Result<Vec<GpsPoint>> {
+    let gpx: Gpx = serde_xml_rs::from_reader(reader)?;
+    let points = gpx.tracks.iter().flat_map(|track| &track.segments).flat_map(|segment| &segment.points);
+    points.enumerate().map(|(i, point)| {
+        let time = point.time.as_ref().and_then(|time| parse_time(time)).
+            ok_or_else(|| Error::Parse(format!("track point {}: missing or invalid time", i + 1)))?;
+        Ok(GpsPoint { time, lat: point.lat, lon: point.lon })
+    }).collect()
+}
+
+/// Reads a trace in the CSV format described at the top of this module. A
+/// header line and lines starting with `#` are skipped.
+pub fn read_csv<R: Read>(reader: R) -> Result<Vec<GpsPoint>> {
+    let mut points = Vec::new();
+    for (number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (number == 0 && line.starts_with("time")) {
+            continue;
+        }
+        let error = |reason: &str| Error::Parse(format!("line {}: {}", number + 1, reason));
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 3 {
+            return Err(error("expected time,lat,lon"));
+        }
+        let time = parse_time(fields[0]).ok_or_else(|| error("invalid time"))?;
+        let lat = fields[1].parse().map_err(|_| error("invalid latitude"))?;
+        let lon = fields[2].parse().map_err(|_| error("invalid longitude"))?;
+        points.push(GpsPoint { time, lat, lon });
+    }
+    Ok(points)
+}
+
+/// Loads a trace from a GPX file if its name ends in `.gpx`, and from CSV
+/// otherwise.
+pub fn load_trace<P: AsRef<Path>>(path: P) -> Result<Vec<GpsPoint>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "gpx") {
+        read_gpx(file)
+    } else {
+        read_csv(file)
+    }
+}
+
+// Parses seconds since the epoch or a timestamp, dropping fractions of a
+// second and accepting the `+00:00` form of UTC that GPS loggers often write.
+fn parse_time(value: &str) -> Option<i64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(seconds.floor() as i64);
+    }
+    let value = value.strip_suffix("+00:00").unwrap_or_else(|| value.strip_suffix('Z').unwrap_or(value));
+    let seconds = value.split('.').next()?;
+    parse_timestamp(&format!("{}Z", seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use geo;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    // A main road 1 running east through nodes 1, 2 and 3, a side road 2
+    // branching north at node 2, and a parallel road 3 about 60 m north of
+    // the first half of the main road
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 36.0, minlon: -86.0, maxlat: 36.01, maxlon: -85.99 },
+            nodes: vec![
+                node(1, 36.0, -86.0), node(2, 36.0, -85.995), node(3, 36.0, -85.99),
+                node(4, 36.005, -85.995), node(5, 36.00054, -86.0), node(6, 36.00054, -85.997)
+            ],
+            ways: vec![way(1, &[1, 2, 3]), way(2, &[2, 4]), way(3, &[5, 6])],
+            relations: Vec::new()
+        }
+    }
+
+    // Points every 10 seconds along a line, shifted sideways by `noise`
+    // meters alternately to the left and right
+    fn trace(from: (f64, f64), to: (f64, f64), count: usize, start: i64, noise: f64) -> Vec<GpsPoint> {
+        let bearing = geo::initial_bearing(from, to);
+        let length = geo::haversine_distance(from, to);
+        (0..count).map(|i| {
+            let along = geo::destination(from, bearing, length * i as f64 / (count - 1) as f64);
+            let side = if i % 2 == 0 { 90.0 } else { -90.0 };
+            let (lat, lon) = geo::destination(along, bearing + side, noise);
+            GpsPoint { time: start + 10 * i as i64, lat, lon }
+        }).collect()
+    }
+
+    #[test]
+    fn matches_turn_onto_side_road() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let matcher = Matcher::new(&graph, Config::default());
+        let mut points = trace((36.0, -86.0), (36.0, -85.995), 6, 0, 8.0);
+        points.extend(trace((36.0, -85.995), (36.005, -85.995), 6, 60, 8.0).into_iter().skip(1));
+
+        let matching = matcher.match_trace(&points);
+        assert_eq!(1, matching.routes.len());
+        assert_eq!(vec![1, 2], matching.routes[0].ways);
+        assert_eq!(vec![1, 2, 4], matching.routes[0].nodes);
+        assert!(matching.points.iter().all(|snap| snap.is_some()));
+        assert!(matching.points.iter().all(|snap| snap.as_ref().unwrap().distance < 12.0));
+        assert_eq!(None, matching.legs[0]);
+        assert!(matching.legs[1..].iter().all(|leg| leg.is_some()));
+    }
+
+    #[test]
+    fn prefers_connected_road_over_closer_one() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let matcher = Matcher::new(&graph, Config::default());
+
+        // Halfway between the main road and the unconnected parallel road,
+        // then clearly on the main road
+        let mut points = trace((36.00027, -86.0), (36.00027, -85.9975), 4, 0, 0.0);
+        points.extend(trace((36.0, -85.996), (36.0, -85.991), 4, 40, 3.0));
+        let matching = matcher.match_trace(&points);
+        assert_eq!(1, matching.routes.len());
+        assert_eq!(vec![1], matching.routes[0].ways);
+        assert_eq!(vec![1, 2, 3], matching.routes[0].nodes);
+    }
+
+    #[test]
+    fn breaks_at_unreachable_points() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let matcher = Matcher::new(&graph, Config::default());
+
+        // Along the parallel road, then jumping onto the main road
+        let mut points = trace((36.00054, -86.0), (36.00054, -85.9975), 3, 0, 0.0);
+        points.extend(trace((36.0, -85.994), (36.0, -85.991), 3, 30, 0.0));
+        points.push(GpsPoint { time: 60, lat: 36.2, lon: -85.99 });
+        let matching = matcher.match_trace(&points);
+        assert_eq!(2, matching.routes.len());
+        assert_eq!(vec![3], matching.routes[0].ways);
+        assert_eq!(vec![1], matching.routes[1].ways);
+        assert_eq!(None, matching.points[6]);
+    }
+
+    #[test]
+    fn read_traces() {
+        let csv = "time,lat,lon\n2018-05-01T12:00:00Z,36.1,-86.8\n# pause\n1525176010.5,36.1001,-86.8\n";
+        let points = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(vec![
+            GpsPoint { time: 1525176000, lat: 36.1, lon: -86.8 },
+            GpsPoint { time: 1525176010, lat: 36.1001, lon: -86.8 }
+        ], points);
+        assert!(read_csv("noon,36.1,-86.8".as_bytes()).is_err());
+        assert!(read_csv("0,36.1".as_bytes()).is_err());
+
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <gpx version="1.1" creator="test">
+              <trk><name>Morning drive</name><trkseg>
+                <trkpt lat="36.1" lon="-86.8"><ele>150.0</ele><time>2018-05-01T12:00:00.250Z</time></trkpt>
+                <trkpt lat="36.1001" lon="-86.8"><time>2018-05-01T12:00:10+00:00</time></trkpt>
+              </trkseg></trk>
+            </gpx>"#;
+        assert_eq!(points, read_gpx(gpx.as_bytes()).unwrap());
+        let untimed = r#"<gpx><trk><trkseg><trkpt lat="36.1" lon="-86.8"/></trkseg></trk></gpx>"#;
+        assert!(read_gpx(untimed.as_bytes()).is_err());
+    }
+}
@@ -38,6 +38,7 @@ pub struct Node {
     pub lat: f64,
     pub lon: f64,
     pub version: u16,
+    #[serde(default)]
     pub timestamp: String,
     pub changeset: u64,
     pub uid: Option<i64>,
@@ -58,6 +59,7 @@ pub struct Tag {
 pub struct Way {
     pub id: i64,
     pub version: u16,
+    #[serde(default)]
     pub timestamp: String,
     pub changeset: u64,
     pub uid: Option<i64>,
@@ -80,6 +82,7 @@ pub struct NodeRef {
 pub struct Relation {
     pub id: i64,
     pub version: u16,
+    #[serde(default)]
     pub timestamp: String,
     pub changeset: u64,
     pub uid: Option<i64>,
@@ -100,6 +103,55 @@ pub struct Member {
     pub role: String
 }
 
+/// An OsmChange document, holding blocks of changes to apply in order.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "osmChange")]
+pub struct Change {
+    pub version: String,
+    pub generator: Option<String>,
+    #[serde(rename = "$value", default)]
+    pub actions: Vec<Action>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Action {
+    #[serde(rename = "create")]
+    Create(Elements),
+    #[serde(rename = "modify")]
+    Modify(Elements),
+    #[serde(rename = "delete")]
+    Delete(Deletions)
+}
+
+/// Elements created or modified by one block of an OsmChange document.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Elements {
+    #[serde(rename = "node", default)]
+    pub nodes: Vec<Node>,
+    #[serde(rename = "way", default)]
+    pub ways: Vec<Way>,
+    #[serde(rename = "relation", default)]
+    pub relations: Vec<Relation>
+}
+
+/// Elements deleted by one block of an OsmChange document. Only their ids
+/// and versions matter; the other attributes are often left out.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Deletions {
+    #[serde(rename = "node", default)]
+    pub nodes: Vec<Deleted>,
+    #[serde(rename = "way", default)]
+    pub ways: Vec<Deleted>,
+    #[serde(rename = "relation", default)]
+    pub relations: Vec<Deleted>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Deleted {
+    pub id: i64,
+    pub version: u16
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -729,4 +781,44 @@ mod tests {
         let map: Map = from_str(s).unwrap();
         println!("{:#?}", map);
     }
+
+    #[test]
+    fn change_blocks_in_order() {
+        let s = r##"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <osmChange version="0.6" generator="osmosis">
+                <modify>
+                    <node id="1" lat="36.1" lon="-86.8" version="2" timestamp="2018-05-02T12:00:00Z" changeset="3" uid="1" user="viking">
+                        <tag k="highway" v="stop"/>
+                    </node>
+                </modify>
+                <create>
+                    <node id="-1" lat="36.2" lon="-86.7" version="1" timestamp="2018-05-02T12:00:00Z" changeset="3"/>
+                    <way id="7" version="1" timestamp="2018-05-02T12:00:00Z" changeset="3">
+                        <nd ref="1"/>
+                        <nd ref="-1"/>
+                    </way>
+                </create>
+                <delete>
+                    <way id="5" version="3"/>
+                    <relation id="9" version="2" timestamp="2018-05-02T12:00:00Z" changeset="3"/>
+                </delete>
+                <modify>
+                    <way id="7" version="2" timestamp="2018-05-02T12:01:00Z" changeset="4"/>
+                </modify>
+            </osmChange>
+        "##;
+        let change: Change = from_str(s).unwrap();
+        assert_eq!(4, change.actions.len());
+        match (&change.actions[0], &change.actions[1], &change.actions[2]) {
+            (Action::Modify(modified), Action::Create(created), Action::Delete(deleted)) => {
+                assert_eq!(1, modified.nodes.len());
+                assert_eq!(2, created.nodes.len() + created.ways.len());
+                assert_eq!(2, created.ways[0].node_refs.len());
+                assert_eq!((5, 3), (deleted.ways[0].id, deleted.ways[0].version));
+                assert_eq!(9, deleted.relations[0].id);
+            },
+            other => panic!("unexpected actions {:?}", other)
+        }
+    }
 }
@@ -0,0 +1,403 @@
+// This module writes the results of a simulation: time series of the traffic
+// on every way as CSV or as a compact columnar binary file, vehicle
+// trajectories that can be replayed in kepler.gl, and a report of the ways
+// with the worst delays.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use serde_json;
+use entities::{format_timestamp, Map};
+use loader::{Error, Result};
+use simulation::{Simulation, TrajectoryPoint};
+
+/// Magic bytes at the start of a binary time series file.
+pub const SERIES_MAGIC: [u8; 8] = *b"JAMVSERS";
+/// Version of the binary time series layout.
+pub const SERIES_VERSION: u32 = 1;
+
+/// Traffic along one direction of a way at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WayRecord {
+    /// Seconds since the start of the simulation.
+    pub time: f64,
+    pub way_id: i64,
+    /// Whether the traffic runs in the order of the way's nodes.
+    pub forward: bool,
+    pub vehicles: u32,
+    /// Vehicles per hour passing a segment of the way, averaged over its
+    /// segments, since the previous record.
+    pub flow: f64,
+    /// Mean speed in km/h of the vehicles on the way.
+    pub speed: f64,
+    /// Vehicles per km.
+    pub density: f64
+}
+
+/// Sums up the link snapshots of a simulation per way and direction.
+pub fn way_series(simulation: &Simulation<'_>) -> Vec<WayRecord> {
+    let edges = &simulation.graph().edges;
+    let links = simulation.links();
+    // Length and number of segments of each way, per direction
+    let mut ways: HashMap<(i64, bool), (f64, usize)> = HashMap::new();
+    for (link, edge) in links.iter().zip(edges) {
+        let way = ways.entry((edge.way_id, edge.forward)).or_insert((0.0, 0));
+        way.0 += link.length;
+        way.1 += 1;
+    }
+
+    let interval = simulation.config.record_interval;
+    let mut records = Vec::new();
+    for snapshot in simulation.history() {
+        // (vehicles, sum of speeds, exits)
+        let mut totals: BTreeMap<(i64, bool), (usize, f64, usize)> = BTreeMap::new();
+        for state in &snapshot.links {
+            let edge = &edges[state.link];
+            let total = totals.entry((edge.way_id, !edge.forward)).or_insert((0, 0.0, 0));
+            total.0 += state.vehicles;
+            total.1 += state.mean_speed * state.vehicles as f64;
+            total.2 += state.exits;
+        }
+        for ((way_id, backward), (vehicles, speeds, exits)) in totals {
+            let (length, segments) = ways[&(way_id, !backward)];
+            records.push(WayRecord {
+                time: snapshot.time,
+                way_id,
+                forward: !backward,
+                vehicles: vehicles as u32,
+                flow: exits as f64 / segments as f64 * 3600.0 / interval,
+                speed: if vehicles > 0 { speeds / vehicles as f64 * 3.6 } else { 0.0 },
+                density: if length > 0.0 { vehicles as f64 / length * 1000.0 } else { 0.0 }
+            });
+        }
+    }
+    records
+}
+
+pub fn write_series_csv<W: Write>(mut writer: W, records: &[WayRecord]) -> Result<()> {
+    writeln!(writer, "time,way_id,direction,vehicles,flow,speed,density")?;
+    for record in records {
+        writeln!(writer, "{},{},{},{},{:.1},{:.1},{:.2}",
+            record.time, record.way_id, direction(record.forward), record.vehicles,
+            record.flow, record.speed, record.density)?;
+    }
+    Ok(())
+}
+
+/// Writes records as a binary file with one column after the other: a
+/// header of `SERIES_MAGIC`, `SERIES_VERSION` (u32) and the record count
+/// (u64), then the times (f64), way ids (i64), directions (u8, 1 for
+/// forward), vehicles (u32), flows, speeds and densities (f32), all little
+/// endian.
+pub fn write_series_bin<W: Write>(mut writer: W, records: &[WayRecord]) -> Result<()> {
+    writer.write_all(&SERIES_MAGIC)?;
+    writer.write_all(&SERIES_VERSION.to_le_bytes())?;
+    writer.write_all(&(records.len() as u64).to_le_bytes())?;
+    for record in records {
+        writer.write_all(&record.time.to_bits().to_le_bytes())?;
+    }
+    for record in records {
+        writer.write_all(&record.way_id.to_le_bytes())?;
+    }
+    for record in records {
+        writer.write_all(&[record.forward as u8])?;
+    }
+    for record in records {
+        writer.write_all(&record.vehicles.to_le_bytes())?;
+    }
+    let columns: [fn(&WayRecord) -> f64; 3] = [|r| r.flow, |r| r.speed, |r| r.density];
+    for column in &columns {
+        for record in records {
+            writer.write_all(&(column(record) as f32).to_bits().to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads records written by `write_series_bin`. Flows, speeds and densities
+/// come back with `f32` precision.
+pub fn read_series_bin<R: Read>(mut reader: R) -> Result<Vec<WayRecord>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let invalid = |reason: &str| Error::Parse(format!("invalid time series file: {}", reason));
+    if bytes.len() < 20 || bytes[..8] != SERIES_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let mut u32_buf = [0; 4];
+    u32_buf.copy_from_slice(&bytes[8..12]);
+    if u32::from_le_bytes(u32_buf) != SERIES_VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let mut u64_buf = [0; 8];
+    u64_buf.copy_from_slice(&bytes[12..20]);
+    let count = u64::from_le_bytes(u64_buf) as usize;
+    if bytes.len() != 20 + count * (8 + 8 + 1 + 4 + 4 * 3) {
+        return Err(invalid("wrong length"));
+    }
+
+    let columns = &bytes[20..];
+    let times = &columns[..count * 8];
+    let way_ids = &columns[count * 8..count * 16];
+    let directions = &columns[count * 16..count * 17];
+    let rest = &columns[count * 17..];
+    let word = |column: &[u8], i: usize| {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(&column[i * 4..i * 4 + 4]);
+        u32::from_le_bytes(buf)
+    };
+    let long = |column: &[u8], i: usize| {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&column[i * 8..i * 8 + 8]);
+        u64::from_le_bytes(buf)
+    };
+    Ok((0..count).map(|i| WayRecord {
+        time: f64::from_bits(long(times, i)),
+        way_id: long(way_ids, i) as i64,
+        forward: directions[i] != 0,
+        vehicles: word(rest, i),
+        flow: f32::from_bits(word(rest, count + i)) as f64,
+        speed: f32::from_bits(word(rest, 2 * count + i)) as f64,
+        density: f32::from_bits(word(rest, 3 * count + i)) as f64
+    }).collect())
+}
+
+/// Writes trajectories as CSV with `vehicle_id,time,lat,lon` columns. Times
+/// are ISO 8601 timestamps counted from `start` (seconds since the epoch),
+/// which kepler.gl can animate with a time filter.
+pub fn write_trajectories_csv<W: Write>(mut writer: W, points: &[TrajectoryPoint], start: i64) -> Result<()> {
+    writeln!(writer, "vehicle_id,time,lat,lon")?;
+    for point in points {
+        writeln!(writer, "{},{},{:.7},{:.7}",
+            point.vehicle, format_timestamp(start + point.time.round() as i64), point.lat, point.lon)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct FeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature>
+}
+
+#[derive(Serialize)]
+struct Feature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    properties: Properties,
+    geometry: Geometry
+}
+
+#[derive(Serialize)]
+struct Properties {
+    vehicle_id: usize
+}
+
+#[derive(Serialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<[f64; 4]>
+}
+
+/// Writes trajectories as GeoJSON for the kepler.gl trip layer: one
+/// `LineString` per vehicle with `[lon, lat, 0, time]` coordinates, where
+/// time is in seconds since the epoch counted from `start`.
+pub fn write_trajectories_geojson<W: Write>(writer: W, points: &[TrajectoryPoint], start: i64) -> Result<()> {
+    let mut by_vehicle: BTreeMap<usize, Vec<[f64; 4]>> = BTreeMap::new();
+    for point in points {
+        by_vehicle.entry(point.vehicle).or_default().
+            push([point.lon, point.lat, 0.0, start as f64 + point.time]);
+    }
+    let features = by_vehicle.into_iter().
+        filter(|(_, coordinates)| coordinates.len() > 1).
+        map(|(vehicle_id, coordinates)| Feature {
+            kind: "Feature",
+            properties: Properties { vehicle_id },
+            geometry: Geometry { kind: "LineString", coordinates }
+        }).
+        collect();
+    Ok(serde_json::to_writer(writer, &FeatureCollection { kind: "FeatureCollection", features })?)
+}
+
+/// Delays along one direction of a way over a whole simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WayDelay {
+    pub way_id: i64,
+    pub name: Option<String>,
+    pub forward: bool,
+    /// Most vehicles that drove any one segment of the way.
+    pub vehicles: usize,
+    /// Seconds to drive the whole way at the speed limit.
+    pub free_flow_time: f64,
+    /// Mean seconds it took to drive the whole way.
+    pub travel_time: f64,
+    /// Seconds lost by all vehicles together compared to the speed limit.
+    pub total_delay: f64
+}
+
+/// Delays per way and direction, worst first.
+pub fn way_delays(simulation: &Simulation, map: &Map) -> Vec<WayDelay> {
+    let names: HashMap<i64, &Option<String>> = map.ways.iter().map(|way| (way.id, &way.name)).collect();
+    let mut delays: BTreeMap<(i64, bool), WayDelay> = BTreeMap::new();
+    for (link, edge) in simulation.links().iter().zip(&simulation.graph().edges) {
+        if link.traversals == 0 {
+            continue;
+        }
+        let delay = delays.entry((edge.way_id, !edge.forward)).or_insert_with(|| WayDelay {
+            way_id: edge.way_id,
+            name: names.get(&edge.way_id).and_then(|&name| name.clone()),
+            forward: edge.forward,
+            vehicles: 0,
+            free_flow_time: 0.0,
+            travel_time: 0.0,
+            total_delay: 0.0
+        });
+        delay.vehicles = delay.vehicles.max(link.traversals);
+        delay.free_flow_time += link.free_flow_time();
+        delay.travel_time += link.travel_time / link.traversals as f64;
+        delay.total_delay += link.travel_time - link.traversals as f64 * link.free_flow_time();
+    }
+    let mut delays: Vec<WayDelay> = delays.into_values().collect();
+    delays.sort_by(|a, b| b.total_delay.partial_cmp(&a.total_delay).unwrap());
+    delays
+}
+
+/// Writes a plain text report of the `limit` worst delayed ways.
+pub fn write_delay_report<W: Write>(mut writer: W, delays: &[WayDelay], limit: usize) -> Result<()> {
+    writeln!(writer, "Worst delayed ways")?;
+    if delays.is_empty() {
+        writeln!(writer, "  (no traffic)")?;
+    }
+    for (rank, delay) in delays.iter().take(limit).enumerate() {
+        let name = match delay.name {
+            Some(ref name) => format!("{} (way {}, {})", name, delay.way_id, direction(delay.forward)),
+            None => format!("way {} ({})", delay.way_id, direction(delay.forward))
+        };
+        writeln!(writer, "{:3}. {}: {} vehicles, {:.1} s mean delay, {:.1} min total delay",
+            rank + 1, name, delay.vehicles, delay.travel_time - delay.free_flow_time,
+            delay.total_delay / 60.0)?;
+    }
+    Ok(())
+}
+
+fn direction(forward: bool) -> &'static str {
+    if forward { "forward" } else { "backward" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use simulation::Config;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.01, maxlon: 0.01 },
+            nodes: vec![node(1, 0.0, 0.0), node(2, 0.005, 0.0), node(3, 0.01, 0.0)],
+            ways: vec![Way {
+                id: 1, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
+                name: Some("Main Street".to_string()), tags: vec![("maxspeed", "36")].into_iter().collect()
+            }],
+            relations: Vec::new()
+        }
+    }
+
+    fn run(map: &Map) -> Simulation<'_> {
+        let config = Config { record_interval: 10.0, trajectory_interval: Some(5.0), ..Config::default() };
+        let mut simulation = Simulation::new(map, config);
+        for i in 0..5 {
+            simulation.add_trip(1, 3, i as f64).unwrap();
+        }
+        simulation.run_until(1000.0);
+        simulation
+    }
+
+    #[test]
+    fn series_per_way() {
+        let map = create_map();
+        let simulation = run(&map);
+        let records = way_series(&simulation);
+        assert!(records.iter().all(|r| r.way_id == 1 && r.forward));
+        let peak = records.iter().map(|r| r.vehicles).max().unwrap();
+        assert_eq!(5, peak);
+        let busy = records.iter().find(|r| r.vehicles == 5).unwrap();
+        let length: f64 = simulation.links().iter().zip(&simulation.graph().edges).
+            filter(|&(_, edge)| edge.forward).
+            map(|(link, _)| link.length).
+            sum();
+        assert!((busy.density - 5.0 / length * 1000.0).abs() < 1e-9);
+        assert!(records.iter().any(|r| r.flow > 0.0));
+        assert!(records.iter().all(|r| r.speed <= 36.0 + 1e-9));
+    }
+
+    #[test]
+    fn series_files() {
+        let records = vec![
+            WayRecord { time: 0.0, way_id: 1, forward: true, vehicles: 2, flow: 0.0, speed: 12.5, density: 1.75 },
+            WayRecord { time: 60.0, way_id: -4, forward: false, vehicles: 0, flow: 360.0, speed: 0.0, density: 0.0 }
+        ];
+        let mut bin = Vec::new();
+        write_series_bin(&mut bin, &records).unwrap();
+        assert_eq!(20 + 2 * 33, bin.len());
+        assert_eq!(records, read_series_bin(&bin[..]).unwrap());
+        assert!(read_series_bin(&bin[..30]).is_err());
+
+        let mut csv = Vec::new();
+        write_series_csv(&mut csv, &records).unwrap();
+        assert_eq!(
+            "time,way_id,direction,vehicles,flow,speed,density\n\
+             0,1,forward,2,0.0,12.5,1.75\n\
+             60,-4,backward,0,360.0,0.0,0.00\n",
+            String::from_utf8(csv).unwrap());
+    }
+
+    #[test]
+    fn trajectory_files() {
+        let points = vec![
+            TrajectoryPoint { vehicle: 0, time: 0.0, lat: 36.1, lon: -86.8 },
+            TrajectoryPoint { vehicle: 1, time: 0.0, lat: 36.2, lon: -86.7 },
+            TrajectoryPoint { vehicle: 0, time: 5.0, lat: 36.15, lon: -86.8 }
+        ];
+        let mut csv = Vec::new();
+        write_trajectories_csv(&mut csv, &points, 1525249800).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!("vehicle_id,time,lat,lon", csv.lines().next().unwrap());
+        assert_eq!("0,2018-05-02T08:30:05Z,36.1500000,-86.8000000", csv.lines().nth(3).unwrap());
+
+        let mut json = Vec::new();
+        write_trajectories_geojson(&mut json, &points, 100).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(
+            "{\"type\":\"FeatureCollection\",\"features\":[{\"type\":\"Feature\",\
+             \"properties\":{\"vehicle_id\":0},\"geometry\":{\"type\":\"LineString\",\
+             \"coordinates\":[[-86.8,36.1,0.0,100.0],[-86.8,36.15,0.0,105.0]]}}]}",
+            json);
+    }
+
+    #[test]
+    fn delay_report() {
+        let map = create_map();
+        let simulation = run(&map);
+        let delays = way_delays(&simulation, &map);
+        assert_eq!(1, delays.len());
+        assert_eq!(5, delays[0].vehicles);
+        assert!(delays[0].total_delay > 0.0);
+        assert!(delays[0].travel_time > delays[0].free_flow_time);
+
+        let mut report = Vec::new();
+        write_delay_report(&mut report, &delays, 10).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("  1. Main Street (way 1, forward): 5 vehicles"));
+    }
+}
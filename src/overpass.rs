@@ -0,0 +1,231 @@
+// This module contains structures for deserialization of the JSON returned
+// by the Overpass API for `[out:json]` queries, and their conversion into the
+// OSM XML structures, so both formats end up as the same map entities.
+//
+// Which attributes elements have depends on the query: `out body` leaves out
+// versions, timestamps and users, `out geom` adds the positions of the nodes
+// of ways, and `out center` a single center point. Missing attributes become
+// zero or empty, except for positions: nodes without one, as returned by
+// `out ids` or `out tags`, are left out rather than placed at (0, 0). Ways
+// with positions get their nodes added to the map when the response doesn't
+// hold them, so `out geom` results can be routed on without recursing down
+// to nodes. Centers are ignored.
+
+use std::collections::{HashMap, HashSet};
+use serde_json::Value;
+use osm;
+
+/// An Overpass API JSON response.
+#[derive(Debug, Deserialize)]
+pub struct Response {
+    #[serde(default)]
+    pub version: Option<Value>,
+    #[serde(default)]
+    pub generator: Option<String>,
+    #[serde(default)]
+    pub osm3s: Option<Osm3s>,
+    #[serde(default)]
+    pub elements: Vec<Element>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Osm3s {
+    #[serde(default)]
+    pub timestamp_osm_base: Option<String>,
+    #[serde(default)]
+    pub copyright: Option<String>
+}
+
+/// A node, way or relation, with the attributes of all three.
+#[derive(Debug, Deserialize)]
+pub struct Element {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: i64,
+    #[serde(default)]
+    pub lat: Option<f64>,
+    #[serde(default)]
+    pub lon: Option<f64>,
+    #[serde(default)]
+    pub version: Option<u16>,
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    #[serde(default)]
+    pub changeset: Option<u64>,
+    #[serde(default)]
+    pub uid: Option<i64>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub nodes: Vec<i64>,
+    #[serde(default)]
+    pub members: Vec<Member>,
+    /// Positions of the nodes of a way, `null` where `out geom` was
+    /// limited to a bounding box.
+    #[serde(default)]
+    pub geometry: Vec<Option<Point>>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Member {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(rename = "ref")]
+    pub id: i64,
+    #[serde(default)]
+    pub role: String
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64
+}
+
+fn tags(tags: HashMap<String, String>) -> Vec<osm::Tag> {
+    tags.into_iter().map(|(k, v)| osm::Tag { k, v }).collect()
+}
+
+fn node(id: i64, lat: f64, lon: f64) -> osm::Node {
+    osm::Node {
+        id, lat, lon, version: 0, timestamp: String::new(), changeset: 0, uid: None, user: None,
+        tags: Vec::new()
+    }
+}
+
+impl From<Response> for osm::Map {
+    fn from(response: Response) -> osm::Map {
+        let mut map = osm::Map {
+            version: match response.version {
+                Some(Value::String(version)) => version,
+                Some(version) => version.to_string(),
+                None => "0.6".to_string()
+            },
+            generator: response.generator.unwrap_or_default(),
+            note: response.osm3s.as_ref().and_then(|osm3s| osm3s.copyright.clone()).unwrap_or_default(),
+            meta: osm::Meta {
+                osm_base: response.osm3s.and_then(|osm3s| osm3s.timestamp_osm_base).unwrap_or_default()
+            },
+            bounds: osm::Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.0, maxlon: 0.0 },
+            nodes: Vec::new(),
+            ways: Vec::new(),
+            relations: Vec::new()
+        };
+
+        let node_ids: HashSet<i64> = response.elements.iter().
+            filter(|element| element.kind == "node" && element.lat.is_some() && element.lon.is_some()).
+            map(|element| element.id).
+            collect();
+        let mut added = HashSet::new();
+        let mut geometry_nodes = Vec::new();
+        for element in response.elements {
+            let timestamp = element.timestamp.unwrap_or_default();
+            match element.kind.as_str() {
+                "node" => if let (Some(lat), Some(lon)) = (element.lat, element.lon) {
+                    map.nodes.push(osm::Node {
+                        timestamp,
+                        version: element.version.unwrap_or(0),
+                        changeset: element.changeset.unwrap_or(0),
+                        uid: element.uid,
+                        user: element.user,
+                        tags: tags(element.tags),
+                        ..node(element.id, lat, lon)
+                    });
+                },
+                "way" => {
+                    for (&id, point) in element.nodes.iter().zip(&element.geometry) {
+                        if let Some(point) = *point {
+                            if !node_ids.contains(&id) && added.insert(id) {
+                                geometry_nodes.push(node(id, point.lat, point.lon));
+                            }
+                        }
+                    }
+                    map.ways.push(osm::Way {
+                        id: element.id,
+                        version: element.version.unwrap_or(0),
+                        timestamp,
+                        changeset: element.changeset.unwrap_or(0),
+                        uid: element.uid,
+                        user: element.user,
+                        node_refs: element.nodes.into_iter().map(|id| osm::NodeRef { id }).collect(),
+                        tags: tags(element.tags)
+                    });
+                },
+                "relation" => map.relations.push(osm::Relation {
+                    id: element.id,
+                    version: element.version.unwrap_or(0),
+                    timestamp,
+                    changeset: element.changeset.unwrap_or(0),
+                    uid: element.uid,
+                    user: element.user,
+                    members: element.members.into_iter().
+                        map(|member| osm::Member { kind: member.kind, id: member.id, role: member.role }).
+                        collect(),
+                    tags: tags(element.tags)
+                }),
+                // Areas, counts and other derived elements
+                _ => {}
+            }
+        }
+        map.nodes.extend(geometry_nodes);
+
+        // Responses don't give bounds, so use those of the nodes
+        if let Some(first) = map.nodes.first() {
+            let mut bounds = osm::Bounds { minlat: first.lat, minlon: first.lon, maxlat: first.lat, maxlon: first.lon };
+            for node in &map.nodes {
+                bounds.minlat = bounds.minlat.min(node.lat);
+                bounds.minlon = bounds.minlon.min(node.lon);
+                bounds.maxlat = bounds.maxlat.max(node.lat);
+                bounds.maxlon = bounds.maxlon.max(node.lon);
+            }
+            map.bounds = bounds;
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::from_str;
+
+    #[test]
+    fn it_works() {
+        let s = r#"{
+            "version": 0.6,
+            "generator": "Overpass API 0.7.56.3 eb200aeb",
+            "osm3s": {
+                "timestamp_osm_base": "2018-05-14T21:45:02Z",
+                "copyright": "The data included in this document is from www.openstreetmap.org. The data is made available under ODbL."
+            },
+            "elements": [
+                {"type": "node", "id": 37060116, "lat": 36.0872268, "lon": -87.0759046, "timestamp": "2012-07-18T16:22:48Z",
+                 "version": 30, "changeset": 12288724, "user": "OSMF Redaction Account", "uid": 722137},
+                {"type": "node", "id": 37060125, "lat": 36.0873400, "lon": -87.0759682, "tags": {"created_by": "JOSM"}},
+                {"type": "way", "id": 5258226, "nodes": [37060116, 37060125, 37095773],
+                 "geometry": [{"lat": 36.0872268, "lon": -87.0759046}, null, {"lat": 36.0880000, "lon": -87.0700000}],
+                 "tags": {"highway": "motorway_link", "name": "Exit 182", "oneway": "yes"}},
+                {"type": "relation", "id": 23148, "members": [{"type": "way", "ref": 5258226, "role": "forward"}],
+                 "tags": {"type": "route"}},
+                {"type": "node", "id": 37095774, "tags": {"highway": "stop"}},
+                {"type": "area", "id": 3600023148}
+            ]
+        }"#;
+        let response: Response = from_str(s).unwrap();
+        let map: osm::Map = response.into();
+        assert_eq!("0.6", map.version);
+        assert_eq!("2018-05-14T21:45:02Z", map.meta.osm_base);
+        assert!(map.note.starts_with("The data included"));
+        assert_eq!(vec![37060116, 37060125, 37095773], map.nodes.iter().map(|node| node.id).collect::<Vec<i64>>());
+        assert_eq!(30, map.nodes[0].version);
+        assert_eq!(0, map.nodes[1].version);
+        assert_eq!(-87.07, map.nodes[2].lon);
+        assert_eq!((36.0872268, -87.0759682, 36.088, -87.07),
+            (map.bounds.minlat, map.bounds.minlon, map.bounds.maxlat, map.bounds.maxlon));
+        assert_eq!(3, map.ways[0].node_refs.len());
+        assert_eq!(3, map.ways[0].tags.len());
+        assert_eq!("forward", map.relations[0].members[0].role);
+    }
+}
@@ -0,0 +1,346 @@
+// This module applies live speed and closure overrides to the edge costs of
+// a routing graph, without rebuilding the graph or the cache it came from.
+//
+// Override files have one `way_id,from_node,to_node,speed_kmh` line per
+// override. Leaving out the node ids applies the override to the whole way in
+// both directions; with node ids it only applies to the segment from one node
+// to the other. A speed of `0` or `closed` closes the way or segment.
+//
+// Routing minimizes distance, so a speed below the speed limit makes an edge
+// proportionally longer for routing. Speeds above the limit have no effect.
+// Overrides scale the base cost of each edge, so delays at control devices
+// added with `control::add_penalties` are kept.
+//
+// Overrides change the costs of an in-memory `Graph`. A memory-mapped
+// `mapped::MappedGraph` is read-only and always routes on distances, so
+// overrides don't apply to it.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use entities::{Map, Way};
+use graph::Graph;
+use loader::{Error, Result};
+use roads;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub way_id: i64,
+    /// `(from, to)` node ids of the segment, or `None` for the whole way.
+    pub segment: Option<(i64, i64)>,
+    /// Speed in km/h, or `None` if closed.
+    pub speed: Option<f64>
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Overrides {
+    pub entries: Vec<Override>
+}
+
+impl Overrides {
+    pub fn new() -> Overrides {
+        Overrides::default()
+    }
+
+    pub fn set_speed(&mut self, way_id: i64, speed: f64) {
+        self.entries.push(Override { way_id, segment: None, speed: Some(speed) });
+    }
+
+    pub fn set_segment_speed(&mut self, way_id: i64, from: i64, to: i64, speed: f64) {
+        self.entries.push(Override { way_id, segment: Some((from, to)), speed: Some(speed) });
+    }
+
+    pub fn close(&mut self, way_id: i64) {
+        self.entries.push(Override { way_id, segment: None, speed: None });
+    }
+
+    pub fn close_segment(&mut self, way_id: i64, from: i64, to: i64) {
+        self.entries.push(Override { way_id, segment: Some((from, to)), speed: None });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reads overrides in the format described at the top of this module. A
+    /// header line and lines starting with `#` are skipped.
+    pub fn read_csv<R: Read>(reader: R) -> Result<Overrides> {
+        let mut overrides = Overrides::new();
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (number == 0 && line.starts_with("way_id")) {
+                continue;
+            }
+            let error = |reason: &str| Error::Parse(format!("line {}: {}", number + 1, reason));
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 4 {
+                return Err(error("expected way_id,from_node,to_node,speed_kmh"));
+            }
+            let way_id = fields[0].parse().map_err(|_| error("invalid way id"))?;
+            let segment = match (fields[1], fields[2]) {
+                ("", "") => None,
+                (from, to) => Some((
+                    from.parse().map_err(|_| error("invalid from node"))?,
+                    to.parse().map_err(|_| error("invalid to node"))?))
+            };
+            let speed = match fields[3] {
+                "closed" => None,
+                value => {
+                    let speed: f64 = value.parse().map_err(|_| error("invalid speed"))?;
+                    if speed == 0.0 {
+                        None
+                    } else if speed > 0.0 && speed.is_finite() {
+                        Some(speed)
+                    } else {
+                        return Err(error("invalid speed"));
+                    }
+                }
+            };
+            overrides.entries.push(Override { way_id, segment, speed });
+        }
+        Ok(overrides)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Overrides> {
+        Overrides::read_csv(File::open(path)?)
+    }
+
+    /// Sets the edge costs of `graph`, which must have been built from `map`,
+    /// to reflect these overrides. Costs of edges without an override are
+    /// reset to their base cost, so this can be called again whenever the
+    /// overrides change.
+    /// Segment overrides take precedence over overrides for a whole way.
+    ///
+    /// Returns the overrides that match no edge of the graph: those for ways
+    /// it doesn't have, and segment overrides whose nodes aren't adjacent on
+    /// the way in the given direction.
+    pub fn apply(&self, graph: &mut Graph, map: &Map) -> Vec<&Override> {
+        graph.reset_costs();
+        let mut by_way: HashMap<i64, Vec<(usize, &Override)>> = HashMap::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            by_way.entry(entry.way_id).or_default().push((i, entry));
+        }
+        if by_way.is_empty() {
+            return Vec::new();
+        }
+        let mut matched = vec![false; self.entries.len()];
+        let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+
+        for e in 0..graph.edges.len() {
+            let (way_id, from, to) = {
+                let edge = &graph.edges[e];
+                (edge.way_id, graph.nodes[edge.from].id, graph.nodes[edge.to].id)
+            };
+            let entries = match by_way.get(&way_id) {
+                Some(entries) => entries,
+                None => continue
+            };
+            for &(i, entry) in entries {
+                if entry.segment.is_none_or(|segment| segment == (from, to)) {
+                    matched[i] = true;
+                }
+            }
+            let entry = entries.iter().rev().find(|&&(_, entry)| entry.segment == Some((from, to))).
+                or_else(|| entries.iter().rev().find(|&&(_, entry)| entry.segment.is_none()));
+            if let Some(&(_, entry)) = entry {
+                let cost = match entry.speed {
+                    Some(speed) => graph.base_cost(e) * ways.get(&way_id).map_or(speed, |way| roads::max_speed(way)) / speed,
+                    None => f64::INFINITY
+                };
+                graph.set_cost(e, cost);
+            }
+        }
+        self.entries.iter().zip(matched).filter(|&(_, matched)| !matched).map(|(entry, _)| entry).collect()
+    }
+}
+
+/// Watches an override file and reloads it when it changes, for servers that
+/// pick up new overrides periodically.
+#[derive(Debug)]
+pub struct Watcher {
+    path: PathBuf,
+    // Modification time and length of the file when it was last read
+    seen: Option<(SystemTime, u64)>
+}
+
+impl Watcher {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Watcher {
+        Watcher { path: path.into(), seen: None }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the new overrides if the file has changed since the last
+    /// call, or `None` if it hasn't. A file that has been deleted yields no
+    /// overrides.
+    pub fn poll(&mut self) -> Result<Option<Overrides>> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                return Ok(self.seen.take().map(|_| Overrides::new()));
+            }
+        };
+        let stamp = (metadata.modified()?, metadata.len());
+        if self.seen == Some(stamp) {
+            return Ok(None);
+        }
+        let overrides = Overrides::load(&self.path)?;
+        self.seen = Some(stamp);
+        Ok(Some(overrides))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use entities::*;
+    use control;
+    use graph::RoutingGraph;
+    use pathfinder::find_path_in_graph;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: vec![("maxspeed", "50")].into_iter().collect()
+        }
+    }
+
+    // A direct way 1 from node 1 to 3, and a detour way 2 through node 4
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.01, maxlon: 0.002 },
+            nodes: vec![node(1, 0.0, 0.0), node(2, 0.005, 0.0), node(3, 0.01, 0.0), node(4, 0.005, 0.002)],
+            ways: vec![way(1, &[1, 2, 3]), way(2, &[1, 4, 3])],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn read_override_file() {
+        let csv = "way_id,from_node,to_node,speed_kmh\n1,,,25\n# roadworks\n2,1,4,closed\n2,4,3,0\n";
+        let overrides = Overrides::read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(vec![
+            Override { way_id: 1, segment: None, speed: Some(25.0) },
+            Override { way_id: 2, segment: Some((1, 4)), speed: None },
+            Override { way_id: 2, segment: Some((4, 3)), speed: None }
+        ], overrides.entries);
+
+        assert!(Overrides::read_csv("1,,,fast".as_bytes()).is_err());
+        assert!(Overrides::read_csv("1,2,,30".as_bytes()).is_err());
+        assert!(Overrides::read_csv("1,,30".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn overrides_change_routes() {
+        let map = create_map();
+        let mut graph = Graph::new(&map);
+        assert_eq!(Some(vec![1, 2, 3]), find_path_in_graph(&graph, 1, 3));
+
+        // A fifth of the speed limit makes the direct way five times as long
+        let mut overrides = Overrides::new();
+        overrides.set_speed(1, 10.0);
+        overrides.apply(&mut graph, &map);
+        assert_eq!(Some(vec![1, 4, 3]), find_path_in_graph(&graph, 1, 3));
+        let edge = &graph.edges[0];
+        assert!((edge.cost - edge.distance * 5.0).abs() < 1e-6);
+
+        // Closing one direction of a segment leaves the other one open
+        let mut overrides = Overrides::new();
+        overrides.close_segment(2, 4, 3);
+        overrides.close_segment(1, 2, 3);
+        assert!(overrides.apply(&mut graph, &map).is_empty());
+        assert_eq!(None, find_path_in_graph(&graph, 1, 3));
+        assert_eq!(Some(vec![3, 2, 1]), find_path_in_graph(&graph, 3, 1));
+
+        // Segment overrides win over whole-way ones
+        let mut overrides = Overrides::new();
+        overrides.close(1);
+        overrides.set_segment_speed(1, 1, 2, 100.0);
+        overrides.apply(&mut graph, &map);
+        let from = graph.node_index(1).unwrap();
+        let open: Vec<i64> = graph.neighbors(from).iter().map(|&(n, _)| graph.node_id(n)).collect();
+        assert_eq!(vec![2, 4], open);
+        assert_eq!(graph.edges[0].distance, graph.edges[0].cost);
+
+        Overrides::new().apply(&mut graph, &map);
+        assert!(graph.edges.iter().all(|edge| edge.cost == edge.distance));
+    }
+
+    #[test]
+    fn keeps_control_penalties() {
+        // A stop sign at node 2 delays the edges of way 1 arriving there
+        let mut map = create_map();
+        map.nodes[1].tags.insert("highway", "stop");
+        let mut graph = control::routing_graph(&map);
+        let penalized: Vec<f64> = graph.edges.iter().map(|edge| edge.cost).collect();
+        assert!(penalized[0] > graph.edges[0].distance);
+
+        // Half the speed limit doubles the cost of way 2 and leaves way 1 alone
+        let mut overrides = Overrides::new();
+        overrides.set_speed(2, 25.0);
+        overrides.apply(&mut graph, &map);
+        for (edge, &cost) in graph.edges.iter().zip(&penalized) {
+            let expected = if edge.way_id == 2 { cost * 2.0 } else { cost };
+            assert!((edge.cost - expected).abs() < 1e-6);
+        }
+
+        Overrides::new().apply(&mut graph, &map);
+        assert_eq!(penalized, graph.edges.iter().map(|edge| edge.cost).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn reports_overrides_matching_no_edge() {
+        let map = create_map();
+        let mut graph = Graph::new(&map);
+        let mut overrides = Overrides::new();
+        overrides.close_segment(1, 1, 3);
+        overrides.close(9);
+        overrides.set_segment_speed(2, 4, 1, 20.0);
+        let unapplied = overrides.apply(&mut graph, &map);
+        assert_eq!(vec![&overrides.entries[0], &overrides.entries[1]], unapplied);
+        assert!(graph.edges.iter().filter(|edge| edge.way_id == 1).all(|edge| edge.cost == edge.distance));
+    }
+
+    #[test]
+    fn watcher_reloads_changed_file() {
+        let dir = env::temp_dir().join("jamville-overrides");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("overrides.csv");
+        let _ = fs::remove_file(&path);
+
+        let mut watcher = Watcher::new(&path);
+        assert_eq!(None, watcher.poll().unwrap());
+
+        fs::write(&path, "1,,,30\n").unwrap();
+        assert_eq!(1, watcher.poll().unwrap().unwrap().len());
+        assert_eq!(None, watcher.poll().unwrap());
+
+        fs::write(&path, "1,,,30\n2,,,closed\n").unwrap();
+        assert_eq!(2, watcher.poll().unwrap().unwrap().len());
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Some(Overrides::new()), watcher.poll().unwrap());
+        assert_eq!(None, watcher.poll().unwrap());
+    }
+}
@@ -1,108 +1,348 @@
+use avoid::Avoid;
+use control;
 use entities::*;
-use std::collections::HashMap;
+use graph::{Graph, RoutingGraph};
+use std::collections::{BinaryHeap, HashMap};
 use std::cmp::Ordering;
+use turns::TurnGraph;
 
+/// Finds the shortest path between two nodes of `map`, returning the ids of
+/// the nodes along the way (including both ends). Traffic signals, stop
+/// signs and other control devices add their delay to the roads leading to
+/// them, as in `control::routing_graph`.
+///
+/// Panics if either node id is not present in the map.
 pub fn find_path(map: &Map, start_id: i64, goal_id: i64) -> Option<Vec<i64>> {
+    let graph = control::routing_graph(map);
+    find_path_in_graph(&graph, start_id, goal_id)
+}
+
+/// Same as `find_path`, but stays away from everything in `avoid`.
+///
+/// Panics if either node id is not present in the map.
+pub fn find_path_avoiding(map: &Map, start_id: i64, goal_id: i64, avoid: &Avoid) -> Option<Vec<i64>> {
+    let graph = control::routing_graph(map);
+    let allowed = avoid.edge_filter(map, &graph);
+    find_path_filtered(&graph, start_id, goal_id, |edge| allowed[edge])
+}
+
+/// Same as `find_path`, but reuses a graph that has already been built.
+/// Control devices only delay routes if the graph was built with
+/// `control::routing_graph`.
+pub fn find_path_in_graph<G: RoutingGraph>(graph: &G, start_id: i64, goal_id: i64) -> Option<Vec<i64>> {
+    a_star(graph, start_id, goal_id, |node| graph.neighbors(node))
+}
+
+/// Same as `find_path_in_graph`, but only uses the edges (by their index
+/// into `graph.edges`) for which `allowed` returns true.
+pub fn find_path_filtered<F: Fn(usize) -> bool>(graph: &Graph, start_id: i64, goal_id: i64, allowed: F) -> Option<Vec<i64>> {
+    a_star(graph, start_id, goal_id, |node| {
+        graph.outgoing(node).iter().
+            filter(|&&e| allowed(e) && graph.edges[e].cost.is_finite()).
+            map(|&e| (graph.edges[e].to, graph.edges[e].cost)).
+            collect()
+    })
+}
+
+// A* search, with the edges leaving each node given by `neighbors` as
+// `(neighbor index, cost)`.
+fn a_star<G, N>(graph: &G, start_id: i64, goal_id: i64, neighbors: N) -> Option<Vec<i64>>
+    where G: RoutingGraph, N: Fn(usize) -> Vec<(usize, f64)>
+{
     // Find start/goal nodes
-    let start = map.find_node(start_id).expect("Invalid start node");
-    let goal = map.find_node(goal_id).expect("Invalid goal node");
+    let start = graph.node_index(start_id).expect("Invalid start node");
+    let goal = graph.node_index(goal_id).expect("Invalid goal node");
 
     // Check to see if start and goal node are the same
-    if start.id == goal.id {
-        return Some(vec![start.id]);
+    if start == goal {
+        return Some(vec![start_id]);
     }
 
     // The set of nodes already evaluated
-    let mut closed_set: Vec<&Node> = Vec::new();
+    let mut closed_set = vec![false; graph.node_count()];
 
-    // The set of currently discovered nodes that are not evaluated yet.
-    // Initially, only the start node is known.
-    let mut open_set: Vec<&Node> = vec![&start];
+    // The set of currently discovered nodes that are not evaluated yet,
+    // ordered by lowest f_score. Initially, only the start node is known.
+    let mut open_set = BinaryHeap::new();
+    open_set.push(State { f_score: graph.distance(start, goal), node: start });
 
     // For each node, which node it can most efficiently be reached from. If a
     // node can be reached from many nodes, came_from will eventually contain
     // the most efficient previous step.
-    let mut came_from: HashMap<i64, i64> = HashMap::new();
+    let mut came_from: HashMap<usize, usize> = HashMap::new();
 
     // For each node, the cost of getting from the start node to that node.
     // Default value should be infinity.
-    let mut g_score: HashMap<i64, f64> = HashMap::new();
+    let mut g_score: HashMap<usize, f64> = HashMap::new();
 
     // The cost of going from start to start is zero.
-    g_score.insert(start.id, 0.0);
+    g_score.insert(start, 0.0);
 
-    // For each node, the total cost of getting from the start node to the goal
-    // by passing by that node. That value is partly known, partly heuristic.
-    // Default value should be infinity.
-    let mut f_score: HashMap<i64, f64> = HashMap::new();
-
-    // For the first node, that value is completely heuristic.
-    f_score.insert(start.id, start.haversine_distance(goal));
-
-    while !open_set.is_empty() {
-        // Find node in open_set with the lowest f_score value
-        open_set.sort_by(|a, b| {
-            let a_score = *f_score.get(&a.id).unwrap();
-            let b_score = *f_score.get(&b.id).unwrap();
-            if a_score < b_score {
-                Ordering::Less
-            } else if a_score > b_score {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
-            }
-        });
-        let current = open_set[0];
+    while let Some(State { node: current, .. }) = open_set.pop() {
         if current == goal {
-            return Some(reconstruct_path(&came_from, current.id))
+            return Some(reconstruct_path(graph, &came_from, current))
+        }
+        if closed_set[current] {
+            // Stale entry for a node that was reached more cheaply
+            continue;
         }
+        closed_set[current] = true;
 
-        open_set.remove(0);
-        closed_set.push(current);
+        for (neighbor, cost) in neighbors(current) {
+            if closed_set[neighbor] {
+                // Ignore the neighbor which is already evaluated.
+                continue;
+            }
 
-        // Find ways that include current node
-        let current_ways = map.ways_for_node(current);
-        for way in &current_ways {
-            let neighbor_ids = way.neighbors_of_node(current.id);
-            for neighbor_id in &neighbor_ids {
-                if closed_set.iter().find(|n| n.id == *neighbor_id).is_some() {
-                    // Ignore the neighbor which is already evaluated.
+            // The distance from start to a neighbor
+            let tentative_g_score = g_score[&current] + cost;
+            if let Some(n) = g_score.get(&neighbor) {
+                if tentative_g_score >= *n {
+                    // This is not a better path.
                     continue;
                 }
-                let neighbor = map.find_node(*neighbor_id).expect("invalid node id");
+            }
 
-                // Discover a new node
-                if open_set.iter().find(|n| n.id == *neighbor_id).is_none() {
-                    open_set.push(neighbor);
-                }
+            // This path is the best until now. Record it!
+            came_from.insert(neighbor, current);
+            g_score.insert(neighbor, tentative_g_score);
+            let f_score = tentative_g_score + graph.distance(neighbor, goal);
+            open_set.push(State { f_score, node: neighbor });
+        }
+    }
+
+    None
+}
+
+/// Same as `find_path_in_graph`, but also pays the penalty of every turn
+/// along the way. The search runs over edges rather than nodes, so a node may
+/// be passed more than once when going around a block beats turning.
+///
+/// Panics if either node id is not present in the graph.
+pub fn find_path_with_turns(turns: &TurnGraph, start_id: i64, goal_id: i64) -> Option<Vec<i64>> {
+    let graph = turns.graph();
+    let start = graph.node_index(start_id).expect("Invalid start node");
+    let goal = graph.node_index(goal_id).expect("Invalid goal node");
+    if start == goal {
+        return Some(vec![start_id]);
+    }
+
+    // A* where every state is the edge just driven along
+    let mut closed_set = vec![false; graph.edges.len()];
+    let mut g_score = vec![f64::INFINITY; graph.edges.len()];
+    let mut came_from: HashMap<usize, usize> = HashMap::new();
+    let mut open_set = BinaryHeap::new();
+    for &e in graph.outgoing(start) {
+        let edge = &graph.edges[e];
+        if edge.cost.is_finite() {
+            g_score[e] = edge.cost;
+            open_set.push(State { f_score: edge.cost + graph.distance(edge.to, goal), node: e });
+        }
+    }
 
-                // The distance from start to a neighbor
-                let tentative_g_score = g_score.get(&current.id).unwrap() +
-                    current.haversine_distance(neighbor);
-                if let Some(n) = g_score.get(&neighbor.id) {
-                    if tentative_g_score >= *n {
-                        // This is not a better path.
-                        continue;
-                    }
+    while let Some(State { node: current, .. }) = open_set.pop() {
+        if graph.edges[current].to == goal {
+            let mut path = vec![goal_id];
+            let mut edge = current;
+            loop {
+                path.push(graph.node_id(graph.edges[edge].from));
+                match came_from.get(&edge) {
+                    Some(&previous) => edge = previous,
+                    None => break
                 }
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if closed_set[current] {
+            continue;
+        }
+        closed_set[current] = true;
 
-                // This path is the best until now. Record it!
-                came_from.insert(neighbor.id, current.id);
-                g_score.insert(neighbor.id, tentative_g_score);
-                f_score.insert(neighbor.id, tentative_g_score + neighbor.haversine_distance(goal));
+        for &(next, penalty) in turns.turns(current) {
+            let edge = &graph.edges[next];
+            let tentative = g_score[current] + penalty + edge.cost;
+            if tentative < g_score[next] {
+                g_score[next] = tentative;
+                came_from.insert(next, current);
+                open_set.push(State { f_score: tentative + graph.distance(edge.to, goal), node: next });
             }
         }
     }
 
-    return None;
+    None
 }
 
-fn reconstruct_path(came_from: &HashMap<i64, i64>, node_id: i64) -> Vec<i64> {
-    let mut result = vec![node_id];
-    let mut current_id = node_id;
-    while came_from.contains_key(&current_id) {
-        current_id = *came_from.get(&current_id).unwrap();
-        result.push(current_id);
+/// Finds the quickest path between two nodes when setting off at
+/// `departure`, where `travel_time` gives the seconds it takes to drive an
+/// edge (by its index into `graph.edges`) when entering it at a given time.
+/// Returns the node ids along the way and the arrival time.
+///
+/// Travel times must not let a later departure arrive earlier, as is the case
+/// for `profiles::EdgeTimes`. Edges with an infinite cost, such as those
+/// closed by overrides, are never used.
+///
+/// Panics if either node id is not present in the graph.
+pub fn find_path_departing<F>(graph: &Graph, start_id: i64, goal_id: i64, departure: f64, travel_time: F) -> Option<(Vec<i64>, f64)>
+    where F: Fn(usize, f64) -> f64
+{
+    let start = graph.node_index(start_id).expect("Invalid start node");
+    let goal = graph.node_index(goal_id).expect("Invalid goal node");
+
+    // Dijkstra's algorithm on arrival times
+    let mut arrival = vec![f64::INFINITY; graph.nodes.len()];
+    let mut came_from: HashMap<usize, usize> = HashMap::new();
+    let mut closed_set = vec![false; graph.nodes.len()];
+    let mut open_set = BinaryHeap::new();
+    arrival[start] = departure;
+    open_set.push(State { f_score: departure, node: start });
+
+    while let Some(State { node: current, .. }) = open_set.pop() {
+        if current == goal {
+            return Some((reconstruct_path(graph, &came_from, current), arrival[current]));
+        }
+        if closed_set[current] {
+            continue;
+        }
+        closed_set[current] = true;
+
+        for &e in graph.outgoing(current) {
+            if !graph.edges[e].cost.is_finite() {
+                continue;
+            }
+            let neighbor = graph.edges[e].to;
+            let tentative = arrival[current] + travel_time(e, arrival[current]);
+            if tentative < arrival[neighbor] {
+                arrival[neighbor] = tentative;
+                came_from.insert(neighbor, current);
+                open_set.push(State { f_score: tentative, node: neighbor });
+            }
+        }
+    }
+
+    None
+}
+
+/// Lowest costs from one node to every other node of a `Graph`, and the
+/// edges that reach them.
+#[derive(Debug, Clone)]
+pub struct ShortestPathTree {
+    pub source: usize,
+    /// Cost of reaching each node, infinite if it can't be reached.
+    pub cost: Vec<f64>,
+    /// Index of the edge each node is reached through.
+    pub via: Vec<Option<usize>>
+}
+
+impl ShortestPathTree {
+    /// Returns the indices of the edges from the source to `target`, or
+    /// `None` if it can't be reached.
+    pub fn edges_to(&self, graph: &Graph, target: usize) -> Option<Vec<usize>> {
+        if !self.cost[target].is_finite() {
+            return None;
+        }
+        let mut edges = Vec::new();
+        let mut current = target;
+        while let Some(edge) = self.via[current] {
+            edges.push(edge);
+            current = graph.edges[edge].from;
+        }
+        edges.reverse();
+        Some(edges)
+    }
+}
+
+/// Runs Dijkstra's algorithm from `source`, with the cost of every edge given
+/// by `edge_cost` (called with its index into `graph.edges`). Edges with an
+/// infinite cost are never used.
+pub fn shortest_path_tree<F: Fn(usize) -> f64>(graph: &Graph, source: usize, edge_cost: F) -> ShortestPathTree {
+    let n = graph.nodes.len();
+    let mut cost = vec![f64::INFINITY; n];
+    let mut via = vec![None; n];
+    let mut closed = vec![false; n];
+    let mut open_set = BinaryHeap::new();
+    cost[source] = 0.0;
+    open_set.push(State { f_score: 0.0, node: source });
+
+    while let Some(State { node: current, .. }) = open_set.pop() {
+        if closed[current] {
+            continue;
+        }
+        closed[current] = true;
+        for &e in graph.outgoing(current) {
+            let edge = &graph.edges[e];
+            let tentative = cost[current] + edge_cost(e);
+            if tentative < cost[edge.to] {
+                cost[edge.to] = tentative;
+                via[edge.to] = Some(e);
+                open_set.push(State { f_score: tentative, node: edge.to });
+            }
+        }
+    }
+
+    ShortestPathTree { source, cost, via }
+}
+
+/// Runs Dijkstra's algorithm from `source` like `shortest_path_tree`, but
+/// only settles nodes that cost at most `max_cost` to reach, so the search
+/// stays in the neighbourhood of the source. Returns the cost of every node
+/// settled and the index of the edge it is reached through.
+pub fn shortest_paths_within<F: Fn(usize) -> f64>(graph: &Graph, source: usize, max_cost: f64, edge_cost: F) -> HashMap<usize, (f64, Option<usize>)> {
+    let mut tentative: HashMap<usize, (f64, Option<usize>)> = HashMap::new();
+    let mut settled = HashMap::new();
+    let mut open_set = BinaryHeap::new();
+    tentative.insert(source, (0.0, None));
+    open_set.push(State { f_score: 0.0, node: source });
+
+    while let Some(State { f_score: cost, node: current }) = open_set.pop() {
+        if cost > max_cost {
+            break;
+        }
+        if settled.contains_key(&current) {
+            continue;
+        }
+        settled.insert(current, tentative[&current]);
+        for &e in graph.outgoing(current) {
+            let edge = &graph.edges[e];
+            let cost = cost + edge_cost(e);
+            if cost <= max_cost && !settled.contains_key(&edge.to) && tentative.get(&edge.to).is_none_or(|&(known, _)| cost < known) {
+                tentative.insert(edge.to, (cost, Some(e)));
+                open_set.push(State { f_score: cost, node: edge.to });
+            }
+        }
+    }
+    settled
+}
+
+// Entry in the open set. Ordering is reversed so that `BinaryHeap` pops the
+// lowest f_score first.
+#[derive(Debug, PartialEq)]
+struct State {
+    f_score: f64,
+    node: usize
+}
+
+impl Eq for State {}
+
+impl Ord for State {
+    fn cmp(&self, other: &State) -> Ordering {
+        other.f_score.partial_cmp(&self.f_score).unwrap_or(Ordering::Equal).
+            then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &State) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn reconstruct_path<G: RoutingGraph>(graph: &G, came_from: &HashMap<usize, usize>, node: usize) -> Vec<i64> {
+    let mut result = vec![graph.node_id(node)];
+    let mut current = node;
+    while let Some(&previous) = came_from.get(&current) {
+        current = previous;
+        result.push(graph.node_id(current));
     }
     result.reverse();
     result
@@ -111,7 +351,8 @@ fn reconstruct_path(came_from: &HashMap<i64, i64>, node_id: i64) -> Vec<i64> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use tags::Tags;
+    use turns::TurnCosts;
 
     fn create_map() -> Map {
         Map {
@@ -126,9 +367,9 @@ mod tests {
     fn find_path_same_start_end() {
         let mut map = create_map();
         map.nodes.push(Node {
-            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: "foo".to_string(),
+            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
 
         let expected = vec![1];
@@ -140,20 +381,20 @@ mod tests {
     fn find_path_same_way_minimal_distance() {
         let mut map = create_map();
         map.nodes.push(Node {
-            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: "foo".to_string(),
+            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: "foo".to_string(),
+            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.ways.push(Way {
-            id: 1, version: 1, timestamp: "foo".to_string(), changeset: 1,
+            id: 1, version: 1, timestamp: None, changeset: 1,
             uid: Some(1), user: Some("viking".to_string()),
             node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }],
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
 
         let expected = vec![1, 2];
@@ -165,25 +406,25 @@ mod tests {
     fn find_path_same_way_multiple_steps() {
         let mut map = create_map();
         map.nodes.push(Node {
-            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: "foo".to_string(),
+            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: "foo".to_string(),
+            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: "foo".to_string(),
+            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.ways.push(Way {
-            id: 1, version: 1, timestamp: "foo".to_string(), changeset: 1,
+            id: 1, version: 1, timestamp: None, changeset: 1,
             uid: Some(1), user: Some("viking".to_string()),
             node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
 
         let expected = vec![1, 2, 3];
@@ -195,25 +436,25 @@ mod tests {
     fn find_path_same_way_multiple_steps_reverse() {
         let mut map = create_map();
         map.nodes.push(Node {
-            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: "foo".to_string(),
+            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: "foo".to_string(),
+            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: "foo".to_string(),
+            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.ways.push(Way {
-            id: 1, version: 1, timestamp: "foo".to_string(), changeset: 1,
+            id: 1, version: 1, timestamp: None, changeset: 1,
             uid: Some(1), user: Some("viking".to_string()),
             node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
 
         let expected = vec![3, 2, 1];
@@ -225,35 +466,181 @@ mod tests {
     fn find_path_connected_ways() {
         let mut map = create_map();
         map.nodes.push(Node {
-            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: "foo".to_string(),
+            id: 1, lat: 5.0, lon: 5.0, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: "foo".to_string(),
+            id: 2, lat: 5.1, lon: 5.1, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.nodes.push(Node {
-            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: "foo".to_string(),
+            id: 3, lat: 5.2, lon: 5.2, version: 1, timestamp: None,
             changeset: 1, uid: Some(1), user: Some("viking".to_string()),
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.ways.push(Way {
-            id: 1, version: 1, timestamp: "foo".to_string(), changeset: 1,
+            id: 1, version: 1, timestamp: None, changeset: 1,
             uid: Some(1), user: Some("viking".to_string()),
             node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }],
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
         map.ways.push(Way {
-            id: 2, version: 1, timestamp: "foo".to_string(), changeset: 1,
+            id: 2, version: 1, timestamp: None, changeset: 1,
             uid: Some(1), user: Some("viking".to_string()),
             node_refs: vec![NodeRef { id: 2 }, NodeRef { id: 3 }],
-            name: None, tags: HashMap::new()
+            name: None, tags: Tags::new()
         });
 
         let expected = vec![1, 2, 3];
         let actual = find_path(&map, 1, 3).expect("couldn't find path");
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn shortest_path_tree_with_edge_costs() {
+        let mut map = create_map();
+        for &(id, lat) in &[(1, 5.0), (2, 5.1), (3, 5.2)] {
+            map.nodes.push(Node {
+                id, lat, lon: 5.0, version: 1, timestamp: None,
+                changeset: 1, uid: Some(1), user: Some("viking".to_string()),
+                name: None, tags: Tags::new()
+            });
+        }
+        map.ways.push(Way {
+            id: 1, version: 1, timestamp: None, changeset: 1,
+            uid: Some(1), user: Some("viking".to_string()),
+            node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
+            name: None, tags: Tags::new()
+        });
+        map.ways.push(Way {
+            id: 2, version: 1, timestamp: None, changeset: 1,
+            uid: Some(1), user: Some("viking".to_string()),
+            node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 3 }],
+            name: None, tags: Tags::new()
+        });
+        let graph = Graph::new(&map);
+        let source = graph.node_index(1).unwrap();
+        let target = graph.node_index(3).unwrap();
+
+        // Way 2 is shorter, until it costs more than way 1
+        let tree = shortest_path_tree(&graph, source, |e| graph.edges[e].distance);
+        let edges = tree.edges_to(&graph, target).unwrap();
+        assert_eq!(vec![2], edges.iter().map(|&e| graph.edges[e].way_id).collect::<Vec<i64>>());
+
+        let tree = shortest_path_tree(&graph, source, |e| if graph.edges[e].way_id == 2 { 1e9 } else { 1.0 });
+        let edges = tree.edges_to(&graph, target).unwrap();
+        assert_eq!(vec![1, 1], edges.iter().map(|&e| graph.edges[e].way_id).collect::<Vec<i64>>());
+        assert_eq!(2.0, tree.cost[target]);
+
+        let tree = shortest_path_tree(&graph, source, |_| f64::INFINITY);
+        assert_eq!(None, tree.edges_to(&graph, target));
+        assert_eq!(Some(Vec::new()), tree.edges_to(&graph, source));
+
+        // A bounded search stops before node 3
+        let reached = shortest_paths_within(&graph, source, 1.5, |e| if graph.edges[e].way_id == 2 { 1e9 } else { 1.0 });
+        assert_eq!(2, reached.len());
+        assert_eq!((1.0, Some(graph.outgoing(source)[0])), reached[&graph.node_index(2).unwrap()]);
+        assert!(!reached.contains_key(&target));
+    }
+
+    #[test]
+    fn find_path_departing_avoids_slow_periods() {
+        let mut map = create_map();
+        for &(id, lat, lon) in &[(1, 5.0, 5.0), (2, 5.001, 5.0), (3, 5.002, 5.0), (4, 5.001, 5.001)] {
+            map.nodes.push(Node {
+                id, lat, lon, version: 1, timestamp: None,
+                changeset: 1, uid: Some(1), user: Some("viking".to_string()),
+                name: None, tags: Tags::new()
+            });
+        }
+        for &(id, ref node_ids) in &[(1, vec![1, 2, 3]), (2, vec![1, 4, 3])] {
+            map.ways.push(Way {
+                id, version: 1, timestamp: None, changeset: 1,
+                uid: Some(1), user: Some("viking".to_string()),
+                node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+                name: None, tags: Tags::new()
+            });
+        }
+        let graph = Graph::new(&map);
+
+        // Way 1 is direct but crawls at 1 m/s for the first 1000 seconds
+        let travel_time = |e: usize, time: f64| {
+            let edge = &graph.edges[e];
+            let speed = if edge.way_id == 1 && time < 1000.0 { 1.0 } else { 10.0 };
+            edge.distance / speed
+        };
+        let (path, arrival) = find_path_departing(&graph, 1, 3, 0.0, travel_time).unwrap();
+        assert_eq!(vec![1, 4, 3], path);
+        assert!(arrival > 0.0 && arrival < 100.0);
+
+        let (path, arrival) = find_path_departing(&graph, 1, 3, 2000.0, travel_time).unwrap();
+        assert_eq!(vec![1, 2, 3], path);
+        assert!((arrival - 2000.0 - graph.edges[0].distance * 2.0 / 10.0).abs() < 1e-6);
+
+        assert_eq!(Some((vec![1], 5.0)), find_path_departing(&graph, 1, 1, 5.0, travel_time));
+
+        // Closing way 1 leaves only the detour, even once it's fast
+        let mut graph = Graph::new(&map);
+        for e in 0..graph.edges.len() {
+            if graph.edges[e].way_id == 1 {
+                graph.set_cost(e, f64::INFINITY);
+            }
+        }
+        let travel_time = |e: usize, time: f64| {
+            let edge = &graph.edges[e];
+            let speed = if edge.way_id == 1 && time < 1000.0 { 1.0 } else { 10.0 };
+            edge.distance / speed
+        };
+        let (path, _) = find_path_departing(&graph, 1, 3, 2000.0, travel_time).unwrap();
+        assert_eq!(vec![1, 4, 3], path);
+    }
+
+    #[test]
+    fn find_path_with_turns_prefers_fewer_turns() {
+        // A 3x3 grid of streets, node ids row by row from the south west
+        let mut map = create_map();
+        for row in 0..3 {
+            for column in 0..3 {
+                map.nodes.push(Node {
+                    id: 1 + row * 3 + column, lat: 5.0 + row as f64 * 0.001, lon: 5.0 + column as f64 * 0.001,
+                    version: 1, timestamp: None, changeset: 1, uid: Some(1), user: Some("viking".to_string()),
+                    name: None, tags: Tags::new()
+                });
+            }
+        }
+        for i in 0..3 {
+            for &(id, ref node_ids) in &[(1 + i, vec![1 + i * 3, 2 + i * 3, 3 + i * 3]), (4 + i, vec![1 + i, 4 + i, 7 + i])] {
+                map.ways.push(Way {
+                    id, version: 1, timestamp: None, changeset: 1,
+                    uid: Some(1), user: Some("viking".to_string()),
+                    node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+                    name: None, tags: Tags::new()
+                });
+            }
+        }
+        let graph = Graph::new(&map);
+        let turns = TurnGraph::new(&graph, &TurnCosts::default());
+
+        let path = find_path_with_turns(&turns, 1, 9).unwrap();
+        assert_eq!(5, path.len());
+        let steps: Vec<i64> = path.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert_eq!(1, steps.windows(2).filter(|pair| pair[0] != pair[1]).count(), "{:?}", path);
+
+        // Closing the corner forces the route through the middle of the grid
+        let mut graph = Graph::new(&map);
+        for e in 0..graph.edges.len() {
+            let to = graph.node_id(graph.edges[e].to);
+            if to == 3 || to == 7 {
+                graph.set_cost(e, f64::INFINITY);
+            }
+        }
+        let turns = TurnGraph::new(&graph, &TurnCosts::default());
+        let path = find_path_with_turns(&turns, 1, 9).unwrap();
+        assert!(path.contains(&5), "{:?}", path);
+
+        assert_eq!(Some(vec![5]), find_path_with_turns(&turns, 5, 5));
+        assert_eq!(None, find_path_with_turns(&turns, 1, 3));
+    }
 }
@@ -0,0 +1,264 @@
+// This module holds time-dependent speed profiles for ways, such as observed
+// or simulated speeds in 15-minute buckets across a week, and turns them into
+// edge travel times for departure-time routing.
+//
+// Profiles are stored per way and direction rather than per edge, so they
+// survive rebuilding the graph. Within a bucket the speed is constant; an edge
+// that is still being driven when a bucket ends continues at the speed of the
+// next bucket, which guarantees that leaving later never means arriving
+// earlier.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use entities::{Map, Way};
+use graph::Graph;
+use loader::{Error, Result};
+use roads;
+
+/// Length of a profile week in seconds.
+pub const WEEK: f64 = 7.0 * 24.0 * 3600.0;
+
+/// Speeds in km/h per way, direction and time bucket. Times are seconds since
+/// the start of the profile period (Monday 00:00 for weekly profiles) and
+/// wrap around at its end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profiles {
+    pub bucket_seconds: f64,
+    pub bucket_count: usize,
+    // Speeds for (way id, forward); NaN where a bucket has no data
+    speeds: HashMap<(i64, bool), Vec<f64>>
+}
+
+impl Default for Profiles {
+    /// Weekly profiles in 15-minute buckets.
+    fn default() -> Profiles {
+        Profiles::new(900.0, (WEEK / 900.0) as usize)
+    }
+}
+
+impl Profiles {
+    pub fn new(bucket_seconds: f64, bucket_count: usize) -> Profiles {
+        Profiles { bucket_seconds, bucket_count, speeds: HashMap::new() }
+    }
+
+    pub fn period(&self) -> f64 {
+        self.bucket_seconds * self.bucket_count as f64
+    }
+
+    /// Index of the bucket containing `time`.
+    pub fn bucket(&self, time: f64) -> usize {
+        let offset = time.rem_euclid(self.period());
+        ((offset / self.bucket_seconds) as usize).min(self.bucket_count - 1)
+    }
+
+    /// Sets the speed in km/h along a way in one bucket.
+    ///
+    /// Panics if the bucket is out of range or the speed isn't positive,
+    /// the same values `read_csv` rejects.
+    pub fn set(&mut self, way_id: i64, forward: bool, bucket: usize, speed: f64) {
+        assert!(bucket < self.bucket_count, "bucket {} out of range", bucket);
+        assert!(speed.is_finite() && speed > 0.0, "speed {} is not positive", speed);
+        let count = self.bucket_count;
+        self.speeds.entry((way_id, forward)).
+            or_insert_with(|| vec![f64::NAN; count])[bucket] = speed;
+    }
+
+    /// Speed in km/h along a way at `time`, if the profile has one.
+    pub fn speed(&self, way_id: i64, forward: bool, time: f64) -> Option<f64> {
+        self.speeds.get(&(way_id, forward)).
+            map(|speeds| speeds[self.bucket(time)]).
+            filter(|speed| speed.is_finite())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.speeds.is_empty()
+    }
+
+    /// Seconds it takes to drive `distance` meters along a way when setting
+    /// off at `departure`. Buckets without data use `default_speed` (km/h).
+    pub fn travel_time(&self, way_id: i64, forward: bool, distance: f64, departure: f64, default_speed: f64) -> f64 {
+        let speeds = self.speeds.get(&(way_id, forward));
+        let mut time = departure;
+        let mut remaining = distance;
+        loop {
+            let bucket = self.bucket(time);
+            let speed = speeds.map(|speeds| speeds[bucket]).
+                filter(|speed| speed.is_finite()).
+                unwrap_or(default_speed) / 3.6;
+            let offset = time.rem_euclid(self.period());
+            let left_in_bucket = ((bucket + 1) as f64 * self.bucket_seconds - offset).max(1e-9);
+            if speed * left_in_bucket >= remaining {
+                return time + remaining / speed - departure;
+            }
+            remaining -= speed * left_in_bucket;
+            time += left_in_bucket;
+        }
+    }
+
+    /// Reads `way_id,direction,bucket,speed` lines, where direction is
+    /// `forward` or `backward` (relative to the order of the way's nodes) and
+    /// speed is in km/h. A header line is skipped.
+    pub fn read_csv<R: Read>(&mut self, reader: R) -> Result<()> {
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || (number == 0 && line.starts_with("way_id")) {
+                continue;
+            }
+            let error = |reason: &str| Error::Parse(format!("line {}: {}", number + 1, reason));
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 4 {
+                return Err(error("expected way_id,direction,bucket,speed"));
+            }
+            let way_id = fields[0].parse().map_err(|_| error("invalid way id"))?;
+            let forward = match fields[1] {
+                "forward" => true,
+                "backward" => false,
+                _ => return Err(error("direction must be forward or backward"))
+            };
+            let bucket: usize = fields[2].parse().map_err(|_| error("invalid bucket"))?;
+            if bucket >= self.bucket_count {
+                return Err(error("bucket out of range"));
+            }
+            let speed: f64 = fields[3].parse().map_err(|_| error("invalid speed"))?;
+            if !speed.is_finite() || speed <= 0.0 {
+                return Err(error("speed must be positive"));
+            }
+            self.set(way_id, forward, bucket, speed);
+        }
+        Ok(())
+    }
+
+    /// Writes the profiles in the format read by `read_csv`, leaving out
+    /// buckets without data.
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "way_id,direction,bucket,speed")?;
+        let mut keys: Vec<&(i64, bool)> = self.speeds.keys().collect();
+        keys.sort_by_key(|&&(way_id, forward)| (way_id, !forward));
+        for key in keys {
+            let direction = if key.1 { "forward" } else { "backward" };
+            for (bucket, speed) in self.speeds[key].iter().enumerate() {
+                if speed.is_finite() {
+                    writeln!(writer, "{},{},{},{:.1}", key.0, direction, bucket, speed)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads weekly profiles in 15-minute buckets from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profiles> {
+        let mut profiles = Profiles::default();
+        profiles.read_csv(File::open(path)?)?;
+        Ok(profiles)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_csv(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Time-dependent travel times for the edges of a `Graph`, falling back to
+/// the speed limit where the profiles have no data.
+pub struct EdgeTimes<'p> {
+    profiles: &'p Profiles,
+    // Speed limit in km/h of each edge
+    default_speeds: Vec<f64>,
+    edges: Vec<(i64, bool, f64)>
+}
+
+impl<'p> EdgeTimes<'p> {
+    pub fn new(map: &Map, graph: &Graph, profiles: &'p Profiles) -> EdgeTimes<'p> {
+        let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+        EdgeTimes {
+            profiles,
+            default_speeds: graph.edges.iter().map(|edge| roads::max_speed(ways[&edge.way_id])).collect(),
+            edges: graph.edges.iter().map(|edge| (edge.way_id, edge.forward, edge.distance)).collect()
+        }
+    }
+
+    /// Seconds it takes to drive the edge at index `edge` when entering it at
+    /// `departure`.
+    pub fn travel_time(&self, edge: usize, departure: f64) -> f64 {
+        let (way_id, forward, distance) = self.edges[edge];
+        self.profiles.travel_time(way_id, forward, distance, departure, self.default_speeds[edge])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_profiles_are_weekly() {
+        let profiles = Profiles::default();
+        assert_eq!(672, profiles.bucket_count);
+        assert_eq!(WEEK, profiles.period());
+        assert_eq!(0, profiles.bucket(0.0));
+        assert_eq!(1, profiles.bucket(900.0));
+        assert_eq!(671, profiles.bucket(WEEK - 1.0));
+        assert_eq!(0, profiles.bucket(WEEK));
+        assert_eq!(671, profiles.bucket(-1.0));
+    }
+
+    #[test]
+    fn travel_time_across_buckets() {
+        let mut profiles = Profiles::new(100.0, 3);
+        profiles.set(1, true, 0, 36.0);
+        profiles.set(1, true, 1, 18.0);
+
+        // 10 m/s within one bucket
+        assert!((profiles.travel_time(1, true, 500.0, 0.0, 72.0) - 50.0).abs() < 1e-9);
+        // 500 m in the first bucket, then 5 m/s for the remaining 250 m
+        assert!((profiles.travel_time(1, true, 750.0, 50.0, 72.0) - 100.0).abs() < 1e-9);
+        // The third bucket has no data and uses the default speed of 20 m/s
+        assert!((profiles.travel_time(1, true, 400.0, 200.0, 72.0) - 20.0).abs() < 1e-9);
+        // Other directions and ways use the default speed throughout
+        assert!((profiles.travel_time(1, false, 400.0, 0.0, 72.0) - 20.0).abs() < 1e-9);
+
+        // Leaving later never means arriving earlier
+        let mut arrival = 0.0;
+        for departure in 0..600 {
+            let departure = departure as f64;
+            let next = departure + profiles.travel_time(1, true, 2000.0, departure, 72.0);
+            assert!(next >= arrival);
+            arrival = next;
+        }
+    }
+
+    #[test]
+    fn profiles_csv_round_trip() {
+        let csv = "way_id,direction,bucket,speed\n7,forward,0,50\n7,backward,3,12.5\n";
+        let mut profiles = Profiles::default();
+        profiles.read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(Some(50.0), profiles.speed(7, true, 100.0));
+        assert_eq!(None, profiles.speed(7, true, 900.0));
+        assert_eq!(Some(12.5), profiles.speed(7, false, 3.0 * 900.0));
+
+        let mut written = Vec::new();
+        profiles.write_csv(&mut written).unwrap();
+        assert_eq!("way_id,direction,bucket,speed\n7,forward,0,50.0\n7,backward,3,12.5\n",
+            String::from_utf8(written).unwrap());
+
+        assert!(profiles.read_csv("7,up,0,50".as_bytes()).is_err());
+        assert!(profiles.read_csv("7,forward,672,50".as_bytes()).is_err());
+        assert!(profiles.read_csv("7,forward,0,0".as_bytes()).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "not positive")]
+    fn set_rejects_zero_speed() {
+        Profiles::new(100.0, 3).set(1, true, 0, 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn set_rejects_missing_bucket() {
+        Profiles::new(100.0, 3).set(1, true, 3, 50.0);
+    }
+}
@@ -0,0 +1,165 @@
+// This module interprets the tags of ways that describe how they can be
+// driven: direction, number of lanes and speed limit.
+
+use entities::Way;
+
+/// Direction(s) in which a way may be travelled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oneway {
+    No,
+    /// Only in the order of the way's nodes.
+    Forward,
+    /// Only against the order of the way's nodes.
+    Reverse
+}
+
+pub fn oneway(way: &Way) -> Oneway {
+    match way.tags.get("oneway") {
+        Some("yes") | Some("true") | Some("1") => Oneway::Forward,
+        Some("-1") | Some("reverse") => Oneway::Reverse,
+        Some("no") | Some("false") | Some("0") => Oneway::No,
+        _ => {
+            let implied = way.tags.get("junction") == Some("roundabout") ||
+                way.tags.get("highway") == Some("motorway");
+            if implied { Oneway::Forward } else { Oneway::No }
+        }
+    }
+}
+
+/// Number of lanes available in one direction of travel. `forward` refers to
+/// the order of the way's nodes.
+pub fn lanes(way: &Way, forward: bool) -> u32 {
+    let directional = if forward { "lanes:forward" } else { "lanes:backward" };
+    if let Some(lanes) = way.tags.get(directional).and_then(parse_lanes) {
+        return lanes;
+    }
+    let total = way.tags.get("lanes").and_then(parse_lanes);
+    match (oneway(way), total) {
+        (Oneway::No, Some(total)) => (total / 2).max(1),
+        (_, Some(total)) => total,
+        (_, None) => default_lanes(way.tags.get("highway").unwrap_or(""))
+    }
+}
+
+fn parse_lanes(value: &str) -> Option<u32> {
+    value.trim().parse().ok().filter(|&lanes| lanes > 0)
+}
+
+/// Typical number of lanes per direction for a `highway` class.
+pub fn default_lanes(highway: &str) -> u32 {
+    match highway {
+        "motorway" | "trunk" => 2,
+        _ => 1
+    }
+}
+
+/// Speed limit in km/h, from the `maxspeed` tag if it can be understood and
+/// from the `highway` class otherwise.
+pub fn max_speed(way: &Way) -> f64 {
+    way.tags.get("maxspeed").
+        and_then(parse_speed).
+        unwrap_or_else(|| default_speed(way.tags.get("highway").unwrap_or("")))
+}
+
+/// Parses a `maxspeed` value such as `50` or `30 mph` into km/h.
+pub fn parse_speed(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (number, factor) = if let Some(number) = value.strip_suffix("mph") {
+        (number.trim(), 1.609344)
+    } else if let Some(number) = value.strip_suffix("km/h") {
+        (number.trim(), 1.0)
+    } else {
+        (value, 1.0)
+    };
+    number.parse::<f64>().ok().filter(|&speed| speed > 0.0).map(|speed| speed * factor)
+}
+
+/// Typical speed in km/h for a `highway` class.
+pub fn default_speed(highway: &str) -> f64 {
+    match highway {
+        "motorway" => 110.0,
+        "trunk" => 90.0,
+        "primary" => 70.0,
+        "secondary" => 60.0,
+        "tertiary" => 50.0,
+        "motorway_link" | "trunk_link" => 60.0,
+        "primary_link" | "secondary_link" | "tertiary_link" => 40.0,
+        "unclassified" | "residential" => 40.0,
+        "living_street" => 10.0,
+        "service" => 20.0,
+        _ => 30.0
+    }
+}
+
+/// Capacity in vehicles per hour for one direction of travel.
+pub fn capacity(way: &Way, forward: bool) -> f64 {
+    lanes(way, forward) as f64 * lane_capacity(way.tags.get("highway").unwrap_or(""))
+}
+
+/// Typical capacity of one lane in vehicles per hour for a `highway` class.
+pub fn lane_capacity(highway: &str) -> f64 {
+    match highway {
+        "motorway" => 2000.0,
+        "trunk" => 1800.0,
+        "primary" => 1600.0,
+        "secondary" => 1400.0,
+        "tertiary" => 1200.0,
+        "motorway_link" | "trunk_link" | "primary_link" | "secondary_link" | "tertiary_link" => 1500.0,
+        "unclassified" | "residential" => 800.0,
+        "living_street" | "service" => 400.0,
+        _ => 600.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::NodeRef;
+    use tags::Tags;
+
+    fn way(tags: &[(&str, &str)]) -> Way {
+        Way {
+            id: 1, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }],
+            name: None, tags: tags.iter().cloned().collect::<Tags>()
+        }
+    }
+
+    #[test]
+    fn oneway_tags() {
+        assert_eq!(Oneway::No, oneway(&way(&[("highway", "residential")])));
+        assert_eq!(Oneway::Forward, oneway(&way(&[("oneway", "yes")])));
+        assert_eq!(Oneway::Reverse, oneway(&way(&[("oneway", "-1")])));
+        assert_eq!(Oneway::Forward, oneway(&way(&[("junction", "roundabout")])));
+        assert_eq!(Oneway::Forward, oneway(&way(&[("highway", "motorway")])));
+        assert_eq!(Oneway::No, oneway(&way(&[("highway", "motorway"), ("oneway", "no")])));
+    }
+
+    #[test]
+    fn lanes_per_direction() {
+        assert_eq!(1, lanes(&way(&[("highway", "residential")]), true));
+        assert_eq!(2, lanes(&way(&[("highway", "primary"), ("lanes", "4")]), false));
+        assert_eq!(3, lanes(&way(&[("lanes", "3"), ("oneway", "yes")]), true));
+        assert_eq!(1, lanes(&way(&[("lanes", "1")]), true));
+        let asymmetric = way(&[("lanes", "3"), ("lanes:forward", "2"), ("lanes:backward", "1")]);
+        assert_eq!(2, lanes(&asymmetric, true));
+        assert_eq!(1, lanes(&asymmetric, false));
+        assert_eq!(2, lanes(&way(&[("highway", "motorway"), ("lanes", "lots")]), true));
+    }
+
+    #[test]
+    fn speed_limits() {
+        assert_eq!(50.0, max_speed(&way(&[("maxspeed", "50")])));
+        assert!((max_speed(&way(&[("maxspeed", "30 mph")])) - 48.28).abs() < 0.01);
+        assert_eq!(40.0, max_speed(&way(&[("highway", "residential"), ("maxspeed", "signals")])));
+        assert_eq!(110.0, max_speed(&way(&[("highway", "motorway")])));
+        assert_eq!(30.0, max_speed(&way(&[])));
+    }
+
+    #[test]
+    fn capacities() {
+        assert_eq!(3200.0, capacity(&way(&[("highway", "primary"), ("lanes", "4")]), true));
+        assert_eq!(4000.0, capacity(&way(&[("highway", "motorway")]), true));
+        assert_eq!(600.0, capacity(&way(&[]), false));
+    }
+}
@@ -0,0 +1,800 @@
+// This module runs a time-stepped microscopic traffic simulation. Every edge
+// of the routing graph becomes a link with the lanes and speed limit of its
+// way. Vehicles follow routes from the pathfinder and pick their speed with
+// the Intelligent Driver Model (Treiber, Hennecke & Helbing, 2000).
+//
+// Traffic signals run fixed-time plans. Drivers treat the stop line of a red
+// light like a standing vehicle, unless they are too close to stop in time.
+
+use std::collections::HashMap;
+use control::{self, Control, SignalPlan};
+use demand::Trip;
+use entities::{Map, Way};
+use graph::{Graph, RoutingGraph};
+use pathfinder::find_path_in_graph;
+use roads;
+
+/// Parameters of the Intelligent Driver Model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Idm {
+    /// Maximum acceleration in m/s².
+    pub max_acceleration: f64,
+    /// Comfortable deceleration in m/s².
+    pub comfortable_deceleration: f64,
+    /// Desired time gap to the vehicle ahead in seconds.
+    pub time_headway: f64,
+    /// Gap kept to the vehicle ahead when standing, in meters.
+    pub min_gap: f64,
+    /// How abruptly drivers stop accelerating near their desired speed.
+    pub delta: f64,
+    /// Length of a vehicle in meters.
+    pub vehicle_length: f64
+}
+
+impl Default for Idm {
+    fn default() -> Idm {
+        Idm {
+            max_acceleration: 1.0,
+            comfortable_deceleration: 1.5,
+            time_headway: 1.5,
+            min_gap: 2.0,
+            delta: 4.0,
+            vehicle_length: 5.0
+        }
+    }
+}
+
+impl Idm {
+    /// Acceleration of a vehicle driving at `speed` towards `desired_speed`
+    /// (both in m/s). `leader` holds the gap in meters to the vehicle or
+    /// obstacle ahead and its speed, if there is one.
+    pub fn acceleration(&self, speed: f64, desired_speed: f64, leader: Option<(f64, f64)>) -> f64 {
+        let a = self.max_acceleration;
+        let free_road = 1.0 - (speed / desired_speed).powf(self.delta);
+        match leader {
+            Some((gap, leader_speed)) => {
+                let braking = speed * (speed - leader_speed) /
+                    (2.0 * (a * self.comfortable_deceleration).sqrt());
+                let desired_gap = self.min_gap + (speed * self.time_headway + braking).max(0.0);
+                a * (free_road - (desired_gap / gap.max(0.01)).powi(2))
+            },
+            None => a * free_road
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Length of a time step in seconds.
+    pub step: f64,
+    /// Seconds between snapshots of the link states.
+    pub record_interval: f64,
+    /// Seconds between recorded vehicle positions, or `None` to record no
+    /// trajectories.
+    pub trajectory_interval: Option<f64>,
+    pub idm: Idm,
+    /// Plan run by every traffic signal unless changed with
+    /// `Simulation::set_signal_plan`.
+    pub signal_plan: SignalPlan
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            step: 0.5,
+            record_interval: 60.0,
+            trajectory_interval: None,
+            idm: Idm::default(),
+            signal_plan: SignalPlan::default()
+        }
+    }
+}
+
+/// A directed road segment, matching one edge of the routing graph.
+#[derive(Debug)]
+pub struct Link {
+    pub from: i64,
+    pub to: i64,
+    pub way_id: i64,
+    /// Length in meters.
+    pub length: f64,
+    pub lanes: usize,
+    /// Speed limit in m/s.
+    pub max_speed: f64,
+    /// Number of vehicles that have driven the whole link.
+    pub traversals: usize,
+    /// Total time in seconds those vehicles spent on the link.
+    pub travel_time: f64,
+    /// Control device at the end of the link that applies to it.
+    pub control: Option<Control>,
+    // Signal phase the link belongs to, if it ends at traffic signals
+    phase: Option<usize>,
+    // Vehicles on each lane, front first
+    queues: Vec<Vec<usize>>,
+    // Vehicles that left the link since the last snapshot
+    exits: usize
+}
+
+impl Link {
+    /// Time in seconds to drive the link at the speed limit.
+    pub fn free_flow_time(&self) -> f64 {
+        self.length / self.max_speed
+    }
+
+    pub fn vehicle_count(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn vehicles(&self) -> Vec<usize> {
+        self.queues.iter().flat_map(|queue| queue.iter().cloned()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VehicleState {
+    /// Not departed yet, or waiting for room on its first link.
+    Waiting,
+    Driving,
+    Arrived
+}
+
+#[derive(Debug)]
+pub struct Vehicle {
+    pub id: usize,
+    /// Links to drive along, in order.
+    pub route: Vec<usize>,
+    /// Index into `route` of the current link.
+    pub route_index: usize,
+    pub lane: usize,
+    /// Distance in meters from the start of the current link.
+    pub position: f64,
+    /// Speed in m/s.
+    pub speed: f64,
+    pub state: VehicleState,
+    /// Scheduled departure time in seconds.
+    pub departure: f64,
+    /// Time the vehicle actually entered the network.
+    pub entered: Option<f64>,
+    pub arrived: Option<f64>,
+    // Time the vehicle entered its current link
+    link_entered: f64
+}
+
+impl Vehicle {
+    pub fn link(&self) -> usize {
+        self.route[self.route_index]
+    }
+}
+
+/// Number of vehicles and their mean speed on one link at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkState {
+    pub link: usize,
+    pub vehicles: usize,
+    /// Mean speed in m/s, zero if there are no vehicles.
+    pub mean_speed: f64,
+    /// Vehicles that left the link since the previous snapshot.
+    pub exits: usize
+}
+
+/// Position of a vehicle at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrajectoryPoint {
+    pub vehicle: usize,
+    pub time: f64,
+    pub lat: f64,
+    pub lon: f64
+}
+
+/// States of all links with traffic at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub time: f64,
+    pub links: Vec<LinkState>
+}
+
+pub struct Simulation<'a> {
+    pub config: Config,
+    graph: Graph<'a>,
+    links: Vec<Link>,
+    vehicles: Vec<Vehicle>,
+    time: f64,
+    next_record: f64,
+    history: Vec<Snapshot>,
+    next_trajectory: f64,
+    trajectories: Vec<TrajectoryPoint>,
+    // Plans of the signalised nodes, by graph node index
+    signals: HashMap<usize, SignalPlan>
+}
+
+impl<'a> Simulation<'a> {
+    /// Sets up a simulation of the roads of `map`.
+    ///
+    /// Panics if the signal plan of `config` isn't valid.
+    pub fn new(map: &'a Map, config: Config) -> Simulation<'a> {
+        assert!(config.signal_plan.is_valid(), "invalid signal plan {:?}", config.signal_plan);
+        // Routes take the expected delay at control devices into account
+        let graph = control::routing_graph(map);
+        let ways: HashMap<i64, &Way> = map.ways.iter().map(|way| (way.id, way)).collect();
+        let controls = control::edge_controls(&graph);
+        let phases = control::signal_phases(&graph);
+        let links = graph.edges.iter().enumerate().map(|(e, edge)| {
+            let way = ways[&edge.way_id];
+            let lanes = roads::lanes(way, edge.forward) as usize;
+            Link {
+                from: graph.node_id(edge.from),
+                to: graph.node_id(edge.to),
+                way_id: edge.way_id,
+                length: edge.distance,
+                lanes,
+                max_speed: roads::max_speed(way) / 3.6,
+                traversals: 0,
+                travel_time: 0.0,
+                control: controls[e],
+                phase: phases[e],
+                queues: vec![Vec::new(); lanes],
+                exits: 0
+            }
+        }).collect::<Vec<Link>>();
+        let signals = graph.edges.iter().zip(&links).
+            filter(|&(_, link)| link.phase.is_some()).
+            map(|(edge, _)| (edge.to, config.signal_plan.clone())).
+            collect();
+
+        Simulation {
+            config, graph, links, signals,
+            vehicles: Vec::new(),
+            time: 0.0,
+            next_record: 0.0,
+            history: Vec::new(),
+            next_trajectory: 0.0,
+            trajectories: Vec::new()
+        }
+    }
+
+    /// Adds a vehicle travelling between two nodes, departing at `departure`
+    /// seconds. Returns the vehicle id, or `None` if either node is not in
+    /// the map or there is no route.
+    pub fn add_trip(&mut self, origin: i64, destination: i64, departure: f64) -> Option<usize> {
+        self.graph.node_index(origin)?;
+        self.graph.node_index(destination)?;
+        let path = find_path_in_graph(&self.graph, origin, destination)?;
+        let route = self.route_links(&path)?;
+        Some(self.add_vehicle(route, departure))
+    }
+
+    /// Adds a vehicle for every trip that has a route between its nodes and
+    /// returns the ids of the trips that were left out, including those with
+    /// nodes that are not in the map.
+    pub fn add_trips(&mut self, trips: &[Trip]) -> Vec<usize> {
+        trips.iter().
+            filter(|trip| self.add_trip(trip.origin, trip.destination, trip.departure).is_none()).
+            map(|trip| trip.id).
+            collect()
+    }
+
+    /// Adds a vehicle driving along the given links. Returns the vehicle id.
+    pub fn add_vehicle(&mut self, route: Vec<usize>, departure: f64) -> usize {
+        let id = self.vehicles.len();
+        self.vehicles.push(Vehicle {
+            id, route, route_index: 0, lane: 0, position: 0.0, speed: 0.0,
+            state: VehicleState::Waiting, departure, entered: None, arrived: None,
+            link_entered: 0.0
+        });
+        id
+    }
+
+    /// Converts a path of node ids into the links between them, picking the
+    /// shortest link where several connect the same nodes.
+    pub fn route_links(&self, path: &[i64]) -> Option<Vec<usize>> {
+        if path.len() < 2 {
+            return None;
+        }
+        path.windows(2).map(|pair| {
+            let from = self.graph.node_index(pair[0])?;
+            let to = self.graph.node_index(pair[1])?;
+            let edges = &self.graph.edges;
+            self.graph.outgoing(from).iter().cloned().
+                filter(|&e| edges[e].to == to).
+                min_by(|&a, &b| edges[a].distance.partial_cmp(&edges[b].distance).unwrap())
+        }).collect()
+    }
+
+    /// Changes the plan of the traffic signals at a node. Returns `false` if
+    /// there are no signals at the node or the plan isn't valid.
+    pub fn set_signal_plan(&mut self, node_id: i64, plan: SignalPlan) -> bool {
+        if !plan.is_valid() {
+            return false;
+        }
+        match self.graph.node_index(node_id).and_then(|node| self.signals.get_mut(&node)) {
+            Some(signal) => {
+                *signal = plan;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Whether traffic may leave a link at the current time. Links that don't
+    /// end at traffic signals are always green.
+    pub fn is_green(&self, l: usize) -> bool {
+        match self.links[l].phase {
+            Some(phase) => self.signals[&self.graph.edges[l].to].is_green(phase, self.time),
+            None => true
+        }
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn graph(&self) -> &Graph<'a> {
+        &self.graph
+    }
+
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+
+    pub fn vehicles(&self) -> &[Vehicle] {
+        &self.vehicles
+    }
+
+    /// Link states recorded every `Config::record_interval` seconds.
+    pub fn history(&self) -> &[Snapshot] {
+        &self.history
+    }
+
+    /// Vehicle positions recorded every `Config::trajectory_interval`
+    /// seconds.
+    pub fn trajectories(&self) -> &[TrajectoryPoint] {
+        &self.trajectories
+    }
+
+    /// Whether every vehicle has arrived.
+    pub fn is_finished(&self) -> bool {
+        self.vehicles.iter().all(|v| v.state == VehicleState::Arrived)
+    }
+
+    /// Steps the simulation until `end` seconds or until every vehicle has
+    /// arrived, whichever comes first.
+    pub fn run_until(&mut self, end: f64) {
+        while self.time < end && !self.is_finished() {
+            self.step();
+        }
+    }
+
+    /// Advances the simulation by one time step.
+    pub fn step(&mut self) {
+        if self.time >= self.next_record {
+            self.record();
+            self.next_record += self.config.record_interval;
+        }
+        self.depart();
+        if let Some(interval) = self.config.trajectory_interval {
+            if self.time >= self.next_trajectory {
+                self.record_trajectories();
+                self.next_trajectory += interval;
+            }
+        }
+
+        // Accelerations are computed from the state at the start of the step
+        let mut accelerations = HashMap::new();
+        for link in &self.links {
+            for queue in &link.queues {
+                for (i, &v) in queue.iter().enumerate() {
+                    let leader = if i > 0 { Some(queue[i - 1]) } else { None };
+                    accelerations.insert(v, self.acceleration(v, leader));
+                }
+            }
+        }
+
+        let dt = self.config.step;
+        let length = self.config.idm.vehicle_length;
+        for l in 0..self.links.len() {
+            for lane in 0..self.links[l].lanes {
+                let mut front: Option<f64> = None;
+                for i in 0..self.links[l].queues[lane].len() {
+                    let v = self.links[l].queues[lane][i];
+                    let vehicle = &mut self.vehicles[v];
+                    let a = accelerations[&v];
+                    let speed = (vehicle.speed + a * dt).max(0.0);
+                    let advance = if speed > 0.0 {
+                        vehicle.speed * dt + 0.5 * a * dt * dt
+                    } else {
+                        // Stopped during the step
+                        0.5 * vehicle.speed * vehicle.speed / (-a).max(1e-9)
+                    };
+                    vehicle.speed = speed;
+                    vehicle.position += advance.max(0.0);
+                    if let Some(front) = front {
+                        // Never drive into the vehicle ahead
+                        if vehicle.position > front - length {
+                            vehicle.position = (front - length).max(0.0);
+                            vehicle.speed = vehicle.speed.min(advance.max(0.0) / dt);
+                        }
+                    }
+                    front = Some(vehicle.position);
+                }
+            }
+        }
+
+        self.advance_links();
+        self.time += dt;
+    }
+
+    // Lets waiting vehicles onto their first link when there is room.
+    fn depart(&mut self) {
+        let gap = self.config.idm.vehicle_length + self.config.idm.min_gap;
+        for v in 0..self.vehicles.len() {
+            if self.vehicles[v].state != VehicleState::Waiting || self.vehicles[v].departure > self.time {
+                continue;
+            }
+            let l = self.vehicles[v].link();
+            let lane = self.entry_lane(l);
+            if let Some(&rear) = self.links[l].queues[lane].last() {
+                if self.vehicles[rear].position < gap {
+                    continue;
+                }
+            }
+            self.links[l].queues[lane].push(v);
+            let vehicle = &mut self.vehicles[v];
+            vehicle.lane = lane;
+            vehicle.state = VehicleState::Driving;
+            vehicle.entered = Some(self.time);
+            vehicle.link_entered = self.time;
+        }
+    }
+
+    // Picks the lane with the most room at its start.
+    fn entry_lane(&self, l: usize) -> usize {
+        let link = &self.links[l];
+        (0..link.lanes).max_by(|&a, &b| {
+            let room = |lane: usize| link.queues[lane].last().
+                map(|&v| self.vehicles[v].position).
+                unwrap_or(f64::INFINITY);
+            room(a).partial_cmp(&room(b)).unwrap().then(b.cmp(&a))
+        }).unwrap_or(0)
+    }
+
+    fn acceleration(&self, v: usize, leader: Option<usize>) -> f64 {
+        let idm = &self.config.idm;
+        let vehicle = &self.vehicles[v];
+        let link = &self.links[vehicle.link()];
+        let leader = match leader {
+            Some(leader) => {
+                let leader = &self.vehicles[leader];
+                Some((leader.position - vehicle.position - idm.vehicle_length, leader.speed))
+            },
+            None => {
+                let next = self.leader_on_next_link(vehicle);
+                match self.red_light(vehicle) {
+                    Some(stop) if next.is_none_or(|(gap, _)| stop.0 < gap) => Some(stop),
+                    _ => next
+                }
+            }
+        };
+        idm.acceleration(vehicle.speed, link.max_speed, leader)
+    }
+
+    // Returns the stop line of a red light at the end of the vehicle's link as
+    // a standing obstacle, unless stopping would take more than twice the
+    // comfortable deceleration.
+    fn red_light(&self, vehicle: &Vehicle) -> Option<(f64, f64)> {
+        if self.is_green(vehicle.link()) {
+            return None;
+        }
+        let remaining = self.links[vehicle.link()].length - vehicle.position;
+        let deceleration = 2.0 * self.config.idm.comfortable_deceleration;
+        if vehicle.speed * vehicle.speed > 2.0 * deceleration * remaining {
+            return None;
+        }
+        Some((remaining, 0.0))
+    }
+
+    // Looks across the end of the current link for the last vehicle on the
+    // next link of the route.
+    fn leader_on_next_link(&self, vehicle: &Vehicle) -> Option<(f64, f64)> {
+        let next = *vehicle.route.get(vehicle.route_index + 1)?;
+        let remaining = self.links[vehicle.link()].length - vehicle.position;
+        let lane = self.entry_lane(next);
+        self.links[next].queues[lane].last().map(|&rear| {
+            let rear = &self.vehicles[rear];
+            (remaining + rear.position - self.config.idm.vehicle_length, rear.speed)
+        })
+    }
+
+    // Moves vehicles that reached the end of their link onto the next one.
+    fn advance_links(&mut self) {
+        let length = self.config.idm.vehicle_length;
+        for l in 0..self.links.len() {
+            for lane in 0..self.links[l].lanes {
+                while let Some(&v) = self.links[l].queues[lane].first() {
+                    let link_length = self.links[l].length;
+                    if self.vehicles[v].position < link_length {
+                        break;
+                    }
+                    let next = self.vehicles[v].route.get(self.vehicles[v].route_index + 1).cloned();
+                    let now = self.time + self.config.step;
+                    match next {
+                        None => {
+                            self.links[l].queues[lane].remove(0);
+                            self.leave_link(l, v, now);
+                            let vehicle = &mut self.vehicles[v];
+                            vehicle.state = VehicleState::Arrived;
+                            vehicle.arrived = Some(now);
+                        },
+                        Some(next) => {
+                            let next_lane = self.entry_lane(next);
+                            let mut position = self.vehicles[v].position - link_length;
+                            if let Some(&rear) = self.links[next].queues[next_lane].last() {
+                                let limit = self.vehicles[rear].position - length;
+                                if limit < 0.0 {
+                                    // No room yet, wait at the end of the link
+                                    let vehicle = &mut self.vehicles[v];
+                                    vehicle.position = link_length;
+                                    vehicle.speed = 0.0;
+                                    break;
+                                }
+                                position = position.min(limit);
+                            }
+                            self.links[l].queues[lane].remove(0);
+                            self.leave_link(l, v, now);
+                            self.links[next].queues[next_lane].push(v);
+                            let vehicle = &mut self.vehicles[v];
+                            vehicle.link_entered = now;
+                            vehicle.route_index += 1;
+                            vehicle.lane = next_lane;
+                            vehicle.position = position;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn leave_link(&mut self, l: usize, v: usize, now: f64) {
+        let link = &mut self.links[l];
+        link.exits += 1;
+        link.traversals += 1;
+        link.travel_time += now - self.vehicles[v].link_entered;
+    }
+
+    fn record(&mut self) {
+        let links = self.links.iter().enumerate().
+            filter(|&(_, link)| link.vehicle_count() > 0 || link.exits > 0).
+            map(|(l, link)| {
+                let vehicles = link.vehicles();
+                let total: f64 = vehicles.iter().map(|&v| self.vehicles[v].speed).sum();
+                let mean_speed = if vehicles.is_empty() { 0.0 } else { total / vehicles.len() as f64 };
+                LinkState { link: l, vehicles: vehicles.len(), mean_speed, exits: link.exits }
+            }).
+            collect();
+        for link in &mut self.links {
+            link.exits = 0;
+        }
+        self.history.push(Snapshot { time: self.time, links });
+    }
+
+    fn record_trajectories(&mut self) {
+        for vehicle in &self.vehicles {
+            if vehicle.state != VehicleState::Driving {
+                continue;
+            }
+            let link = &self.links[vehicle.link()];
+            let edge = &self.graph.edges[vehicle.link()];
+            let from = self.graph.nodes[edge.from].position();
+            let to = self.graph.nodes[edge.to].position();
+            let fraction = if link.length > 0.0 { (vehicle.position / link.length).min(1.0) } else { 0.0 };
+            self.trajectories.push(TrajectoryPoint {
+                vehicle: vehicle.id,
+                time: self.time,
+                lat: from.0 + (to.0 - from.0) * fraction,
+                lon: from.1 + (to.1 - from.1) * fraction
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::{Bounds, Meta, Node, NodeRef};
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    // A straight road of about 1.1 km between nodes 1 and 3
+    fn create_map(tags: &[(&str, &str)]) -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.01, maxlon: 0.01 },
+            nodes: vec![node(1, 0.0, 0.0), node(2, 0.005, 0.0), node(3, 0.01, 0.0)],
+            ways: vec![Way {
+                id: 1, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
+                name: None, tags: tags.iter().cloned().collect::<Tags>()
+            }],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn idm_free_road_and_braking() {
+        let idm = Idm::default();
+        assert_eq!(1.0, idm.acceleration(0.0, 10.0, None));
+        assert!(idm.acceleration(10.0, 10.0, None).abs() < 1e-9);
+        assert!(idm.acceleration(10.0, 10.0, Some((5.0, 0.0))) < -idm.comfortable_deceleration);
+    }
+
+    #[test]
+    fn free_vehicle_reaches_speed_limit() {
+        let map = create_map(&[("maxspeed", "36")]);
+        let mut simulation = Simulation::new(&map, Config::default());
+        let id = simulation.add_trip(1, 3, 0.0).unwrap();
+        assert_eq!(2, simulation.vehicles()[id].route.len());
+
+        let mut top_speed: f64 = 0.0;
+        while !simulation.is_finished() && simulation.time() < 600.0 {
+            simulation.step();
+            top_speed = top_speed.max(simulation.vehicles()[id].speed);
+        }
+        let vehicle = &simulation.vehicles()[id];
+        assert_eq!(VehicleState::Arrived, vehicle.state);
+        assert!(top_speed > 9.5 && top_speed <= 10.0);
+        // 1.1 km at up to 10 m/s
+        let travel_time = vehicle.arrived.unwrap();
+        assert!(travel_time > 111.0 && travel_time < 140.0, "{}", travel_time);
+    }
+
+    #[test]
+    fn skips_trips_with_unknown_nodes() {
+        let map = create_map(&[]);
+        let mut simulation = Simulation::new(&map, Config::default());
+        assert_eq!(None, simulation.add_trip(1, 99, 0.0));
+        let trips = vec![
+            Trip { id: 0, origin: 1, destination: 3, departure: 0.0 },
+            Trip { id: 1, origin: 99, destination: 3, departure: 0.0 }
+        ];
+        assert_eq!(vec![1], simulation.add_trips(&trips));
+        assert_eq!(1, simulation.vehicles().len());
+    }
+
+    #[test]
+    fn follower_keeps_its_distance() {
+        let map = create_map(&[]);
+        let mut simulation = Simulation::new(&map, Config::default());
+        let leader = simulation.add_trip(1, 3, 0.0).unwrap();
+        let follower = simulation.add_trip(1, 3, 0.0).unwrap();
+
+        while !simulation.is_finished() && simulation.time() < 600.0 {
+            simulation.step();
+            let (a, b) = (&simulation.vehicles()[leader], &simulation.vehicles()[follower]);
+            if a.state == VehicleState::Driving && b.state == VehicleState::Driving && a.link() == b.link() {
+                assert!(a.position - b.position >= Idm::default().vehicle_length);
+            }
+        }
+        let (a, b) = (&simulation.vehicles()[leader], &simulation.vehicles()[follower]);
+        assert!(simulation.is_finished());
+        assert!(b.entered.unwrap() > a.entered.unwrap());
+        assert!(b.arrived.unwrap() > a.arrived.unwrap());
+    }
+
+    #[test]
+    fn multiple_lanes_allow_parallel_vehicles() {
+        let map = create_map(&[("lanes", "4")]);
+        let mut simulation = Simulation::new(&map, Config::default());
+        let a = simulation.add_trip(1, 3, 0.0).unwrap();
+        let b = simulation.add_trip(1, 3, 0.0).unwrap();
+        simulation.step();
+
+        let vehicles = simulation.vehicles();
+        assert_eq!(VehicleState::Driving, vehicles[a].state);
+        assert_eq!(VehicleState::Driving, vehicles[b].state);
+        assert!(vehicles[a].lane != vehicles[b].lane);
+        assert_eq!(vehicles[a].entered, vehicles[b].entered);
+    }
+
+    #[test]
+    fn records_link_occupancy() {
+        let map = create_map(&[]);
+        let config = Config { record_interval: 10.0, ..Config::default() };
+        let mut simulation = Simulation::new(&map, config);
+        simulation.add_trip(1, 3, 0.0).unwrap();
+        simulation.add_trip(3, 1, 5.0).unwrap();
+        simulation.run_until(600.0);
+
+        let history = simulation.history();
+        assert_eq!(0.0, history[0].time);
+        assert_eq!(10.0, history[1].time);
+        assert_eq!(2, history[1].links.iter().map(|state| state.vehicles).sum::<usize>());
+        assert!(history[1].links.iter().all(|state| state.mean_speed > 0.0));
+        assert!(simulation.is_finished());
+    }
+
+    #[test]
+    fn no_route_without_connection() {
+        let mut map = create_map(&[]);
+        map.nodes.push(node(4, 0.0, 0.01));
+        let mut simulation = Simulation::new(&map, Config::default());
+        assert_eq!(None, simulation.add_trip(1, 4, 0.0));
+    }
+
+    #[test]
+    fn adds_routable_trips() {
+        let mut map = create_map(&[]);
+        map.nodes.push(node(4, 0.0, 0.01));
+        let mut simulation = Simulation::new(&map, Config::default());
+        let trips = vec![
+            Trip { id: 0, origin: 1, destination: 3, departure: 0.0 },
+            Trip { id: 1, origin: 1, destination: 4, departure: 0.0 },
+            Trip { id: 2, origin: 3, destination: 2, departure: 30.0 }
+        ];
+        assert_eq!(vec![1], simulation.add_trips(&trips));
+        assert_eq!(2, simulation.vehicles().len());
+        assert_eq!(30.0, simulation.vehicles()[1].departure);
+    }
+
+    #[test]
+    fn records_traversals_and_trajectories() {
+        let map = create_map(&[("maxspeed", "36")]);
+        let config = Config { trajectory_interval: Some(10.0), ..Config::default() };
+        let mut simulation = Simulation::new(&map, config);
+        let id = simulation.add_trip(1, 3, 0.0).unwrap();
+        simulation.run_until(600.0);
+
+        let vehicle = &simulation.vehicles()[id];
+        let links = simulation.links();
+        let first = &links[vehicle.route[0]];
+        assert_eq!(1, first.traversals);
+        assert!(first.travel_time > first.free_flow_time());
+        let total: f64 = vehicle.route.iter().map(|&l| links[l].travel_time).sum();
+        assert!((total - vehicle.arrived.unwrap()).abs() < 1e-9);
+        let exits: usize = simulation.history().iter().
+            flat_map(|snapshot| snapshot.links.iter().map(|state| state.exits)).
+            sum();
+        assert!(exits <= 2);
+
+        let points = simulation.trajectories();
+        assert_eq!(0.0, points[0].time);
+        assert_eq!((0.0, 0.0), (points[0].lat, points[0].lon));
+        assert!(points.windows(2).all(|w| w[1].lat > w[0].lat && w[1].time - w[0].time == 10.0));
+        assert!(points.last().unwrap().lat <= 0.01);
+    }
+
+    #[test]
+    fn vehicles_stop_at_red_lights() {
+        let mut map = create_map(&[("maxspeed", "36")]);
+        map.nodes[1].tags.insert("highway", "traffic_signals");
+        let mut simulation = Simulation::new(&map, Config::default());
+        let id = simulation.add_trip(1, 3, 0.0).unwrap();
+        let first = simulation.vehicles()[id].route[0];
+        assert_eq!(Some(Control::TrafficSignals), simulation.links()[first].control);
+        assert!(!simulation.set_signal_plan(1, SignalPlan::default()));
+
+        // The light turns red at 41 s, before the vehicle gets there
+        while simulation.vehicles()[id].route_index == 0 {
+            assert!(simulation.time() < 600.0);
+            simulation.step();
+            if simulation.vehicles()[id].route_index == 0 && !simulation.is_green(first) {
+                assert!(simulation.vehicles()[id].position < simulation.links()[first].length);
+            }
+        }
+        assert!(simulation.time() >= 90.0);
+
+        // With the next green phase moved forward the vehicle drives through
+        let mut simulation = Simulation::new(&map, Config::default());
+        let id = simulation.add_trip(1, 3, 0.0).unwrap();
+        assert!(!simulation.set_signal_plan(2, SignalPlan { phases: 0, ..SignalPlan::default() }));
+        assert!(simulation.set_signal_plan(2, SignalPlan { offset: 30.0, ..SignalPlan::default() }));
+        simulation.run_until(600.0);
+        let link = &simulation.links()[simulation.vehicles()[id].route[0]];
+        assert!(link.travel_time < 70.0, "{}", link.travel_time);
+    }
+}
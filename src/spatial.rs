@@ -0,0 +1,183 @@
+// This module indexes the edges of a routing graph on a regular grid, so the
+// road segments near a point can be found without looking at every edge.
+// Distances and projections use a cheap ruler centred on the graph, which is
+// plenty for snapping points within a few hundred meters of a road.
+
+use std::collections::HashMap;
+use geo::{CheapRuler, DistanceMetric};
+use graph::Graph;
+
+/// Closest point of an edge to some position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snap {
+    /// Index of the edge in `Graph::edges`.
+    pub edge: usize,
+    /// Position along the edge, from 0 at its start to 1 at its end.
+    pub fraction: f64,
+    /// `(lat, lon)` of the closest point.
+    pub position: (f64, f64),
+    /// Distance in meters from the position that was snapped.
+    pub distance: f64
+}
+
+/// Grid of the edges of a `Graph`.
+#[derive(Debug)]
+pub struct SpatialIndex {
+    ruler: CheapRuler,
+    /// Size of a grid cell in meters.
+    cell_size: f64,
+    cells: HashMap<(i64, i64), Vec<usize>>
+}
+
+impl SpatialIndex {
+    /// Indexes every edge of `graph` on a grid of `cell_size` meters.
+    pub fn new(graph: &Graph, cell_size: f64) -> SpatialIndex {
+        let ruler = match graph.metric {
+            DistanceMetric::Cheap(ruler) => ruler,
+            DistanceMetric::Haversine => {
+                let lats = graph.nodes.iter().map(|node| node.lat);
+                let (min, max) = lats.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), lat| (min.min(lat), max.max(lat)));
+                CheapRuler::new(if min <= max { (min + max) / 2.0 } else { 0.0 })
+            }
+        };
+        let mut index = SpatialIndex { ruler, cell_size, cells: HashMap::new() };
+        for (e, edge) in graph.edges.iter().enumerate() {
+            let (x1, y1) = index.cell(graph.nodes[edge.from].position());
+            let (x2, y2) = index.cell(graph.nodes[edge.to].position());
+            for x in x1.min(x2)..=x1.max(x2) {
+                for y in y1.min(y2)..=y1.max(y2) {
+                    index.cells.entry((x, y)).or_default().push(e);
+                }
+            }
+        }
+        index
+    }
+
+    /// Removes the edges for which `keep` returns false from the index, so
+    /// positions are no longer snapped onto them.
+    pub fn retain_edges<F: Fn(usize) -> bool>(&mut self, keep: F) {
+        for edges in self.cells.values_mut() {
+            edges.retain(|&e| keep(e));
+        }
+    }
+
+    fn cell(&self, position: (f64, f64)) -> (i64, i64) {
+        let (kx, ky) = self.ruler.factors();
+        ((position.1 * kx / self.cell_size).floor() as i64, (position.0 * ky / self.cell_size).floor() as i64)
+    }
+
+    /// Snaps `position` onto every edge of `graph` within `radius` meters,
+    /// closest first. `graph` must be the one the index was built from.
+    pub fn edges_within(&self, graph: &Graph, position: (f64, f64), radius: f64) -> Vec<Snap> {
+        let (kx, ky) = self.ruler.factors();
+        let (x1, y1) = self.cell((position.0 - radius / ky, position.1 - radius / kx));
+        let (x2, y2) = self.cell((position.0 + radius / ky, position.1 + radius / kx));
+        let mut edges = Vec::new();
+        for x in x1..=x2 {
+            for y in y1..=y2 {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    edges.extend(cell.iter().cloned());
+                }
+            }
+        }
+        edges.sort();
+        edges.dedup();
+
+        let mut snaps: Vec<Snap> = edges.into_iter().
+            map(|e| self.snap(graph, e, position)).
+            filter(|snap| snap.distance <= radius).
+            collect();
+        snaps.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap().then(a.edge.cmp(&b.edge)));
+        snaps
+    }
+
+    /// Snaps `position` onto the closest edge within `radius` meters.
+    pub fn nearest(&self, graph: &Graph, position: (f64, f64), radius: f64) -> Option<Snap> {
+        self.edges_within(graph, position, radius).into_iter().next()
+    }
+
+    /// Projects `position` onto an edge of `graph`.
+    pub fn snap(&self, graph: &Graph, edge: usize, position: (f64, f64)) -> Snap {
+        let (kx, ky) = self.ruler.factors();
+        let from = graph.nodes[graph.edges[edge].from].position();
+        let to = graph.nodes[graph.edges[edge].to].position();
+        let (dx, dy) = ((to.1 - from.1) * kx, (to.0 - from.0) * ky);
+        let (px, py) = ((position.1 - from.1) * kx, (position.0 - from.0) * ky);
+        let length = dx * dx + dy * dy;
+        let fraction = if length > 0.0 { ((px * dx + py * dy) / length).clamp(0.0, 1.0) } else { 0.0 };
+        let closest = (from.0 + (to.0 - from.0) * fraction, from.1 + (to.1 - from.1) * fraction);
+        Snap { edge, fraction, position: closest, distance: self.ruler.distance(position, closest) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    // An east-west way 1 and a north-south way 2 crossing at node 2
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 36.0, minlon: -86.0, maxlat: 36.01, maxlon: -85.99 },
+            nodes: vec![
+                node(1, 36.005, -86.0), node(2, 36.005, -85.995), node(3, 36.005, -85.99),
+                node(4, 36.0, -85.995), node(5, 36.01, -85.995)
+            ],
+            ways: vec![
+                Way {
+                    id: 1, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                    node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
+                    name: None, tags: Tags::new()
+                },
+                Way {
+                    id: 2, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                    node_refs: vec![NodeRef { id: 4 }, NodeRef { id: 2 }, NodeRef { id: 5 }],
+                    name: None, tags: Tags::new()
+                }
+            ],
+            relations: Vec::new()
+        }
+    }
+
+    #[test]
+    fn snaps_onto_nearby_edges() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let index = SpatialIndex::new(&graph, 100.0);
+
+        // About 22 m north of way 1, a quarter of the way from node 1 to 2
+        let position = (36.0052, -85.99875);
+        let snaps = index.edges_within(&graph, position, 50.0);
+        assert_eq!(2, snaps.len());
+        assert!(snaps.iter().all(|snap| graph.edges[snap.edge].way_id == 1));
+        let snap = &snaps[0];
+        assert!((snap.distance - 22.2).abs() < 0.5, "{}", snap.distance);
+        assert!((snap.position.0 - 36.005).abs() < 1e-9);
+        assert!((snap.position.1 + 85.99875).abs() < 1e-9);
+
+        let nearest = index.nearest(&graph, (36.0075, -85.9951), 500.0).unwrap();
+        assert_eq!(2, graph.edges[nearest.edge].way_id);
+        assert!(index.nearest(&graph, (36.1, -86.1), 500.0).is_none());
+    }
+
+    #[test]
+    fn clamps_to_edge_ends() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let index = SpatialIndex::new(&graph, 100.0);
+        let snap = index.snap(&graph, 0, (36.005, -86.001));
+        assert_eq!(0.0, snap.fraction);
+        assert_eq!((36.005, -86.0), snap.position);
+        assert!((snap.distance - 90.0).abs() < 1.0, "{}", snap.distance);
+    }
+}
@@ -0,0 +1,224 @@
+// This module estimates travel speeds from map-matched GPS traces. Every pair
+// of consecutive matched points gives a speed, the distance along the roads
+// between them divided by the time between them, which is counted for each
+// way and direction driven in between and for the time-of-day bucket of the
+// pair's midpoint. Outliers are rejected by their distance from the median
+// in median absolute deviations, and the harmonic mean of the rest becomes
+// the profile speed, since that is what averages travel times correctly.
+
+use std::collections::HashMap;
+use graph::Graph;
+use matching::{GpsPoint, Matching};
+use profiles::Profiles;
+
+/// Seconds from the Unix epoch, a Thursday, back to the Monday before it,
+/// where weekly profiles start.
+pub const EPOCH_WEEKDAY_OFFSET: i64 = 3 * 86400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Offset of local time from UTC in seconds, so buckets follow the local
+    /// time of day.
+    pub utc_offset: i64,
+    /// Samples further from the median than this many (scaled) median
+    /// absolute deviations are rejected.
+    pub outlier_threshold: f64,
+    /// Buckets with fewer samples left after rejecting outliers get no
+    /// speed.
+    pub min_samples: usize,
+    /// Speeds above this many km/h are dropped as GPS errors.
+    pub max_speed: f64
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { utc_offset: 0, outlier_threshold: 3.0, min_samples: 3, max_speed: 200.0 }
+    }
+}
+
+/// Collects speed samples from matched traces and turns them into profiles.
+#[derive(Debug)]
+pub struct Estimator<'g, 'a: 'g> {
+    pub config: Config,
+    graph: &'g Graph<'a>,
+    profiles: Profiles,
+    // Speeds in km/h for (way id, forward, bucket)
+    samples: HashMap<(i64, bool, usize), Vec<f64>>
+}
+
+impl<'g, 'a> Estimator<'g, 'a> {
+    /// Creates an estimator for traces matched on `graph`, producing
+    /// profiles with the buckets of `profiles`, which should be empty.
+    pub fn new(graph: &'g Graph<'a>, profiles: Profiles, config: Config) -> Estimator<'g, 'a> {
+        Estimator { config, graph, profiles, samples: HashMap::new() }
+    }
+
+    /// Number of samples collected so far.
+    pub fn sample_count(&self) -> usize {
+        self.samples.values().map(|samples| samples.len()).sum()
+    }
+
+    /// Adds the speeds between consecutive matched points of a trace, along
+    /// the routes the matching found between them.
+    pub fn add_trace(&mut self, trace: &[GpsPoint], matching: &Matching) {
+        let graph = self.graph;
+        let matched: Vec<(&GpsPoint, _, _)> = trace.iter().zip(&matching.points).zip(&matching.legs).
+            filter_map(|((point, snap), leg)| snap.as_ref().map(|snap| (point, snap, leg))).
+            collect();
+        for pair in matched.windows(2) {
+            let ((from, a, _), (to, b, leg)) = (pair[0], pair[1]);
+            let elapsed = (to.time - from.time) as f64;
+            // Points starting a new route weren't reached from the previous one
+            let leg = match *leg {
+                Some(ref leg) if elapsed > 0.0 => leg,
+                _ => continue
+            };
+            let (first, last) = (&graph.edges[a.edge], &graph.edges[b.edge]);
+
+            // Edges driven between the points, and the distance along them
+            let (edges, distance) = if a.edge == b.edge && b.fraction >= a.fraction {
+                (vec![a.edge], (b.fraction - a.fraction) * first.distance)
+            } else {
+                // Matched edges count unless the point is right at their end
+                let mut edges = Vec::new();
+                if a.fraction < 1.0 - 1e-6 {
+                    edges.push(a.edge);
+                }
+                let mut distance = (1.0 - a.fraction) * first.distance + b.fraction * last.distance;
+                for &edge in leg {
+                    distance += graph.edges[edge].distance;
+                    edges.push(edge);
+                }
+                if b.fraction > 1e-6 {
+                    edges.push(b.edge);
+                }
+                (edges, distance)
+            };
+            let speed = distance / elapsed * 3.6;
+            if speed <= 0.0 || speed > self.config.max_speed {
+                continue;
+            }
+
+            let midpoint = (from.time + to.time) / 2 + self.config.utc_offset + EPOCH_WEEKDAY_OFFSET;
+            let bucket = self.profiles.bucket(midpoint as f64);
+            let mut ways: Vec<(i64, bool)> = edges.iter().
+                map(|&e| (graph.edges[e].way_id, graph.edges[e].forward)).
+                collect();
+            ways.sort();
+            ways.dedup();
+            for (way_id, forward) in ways {
+                self.samples.entry((way_id, forward, bucket)).or_default().push(speed);
+            }
+        }
+    }
+
+    /// Returns profiles with the estimated speed of every way, direction and
+    /// bucket that has enough samples.
+    pub fn profiles(&self) -> Profiles {
+        let mut profiles = self.profiles.clone();
+        for (&(way_id, forward, bucket), samples) in &self.samples {
+            let kept = reject_outliers(samples, self.config.outlier_threshold);
+            if kept.len() >= self.config.min_samples.max(1) {
+                let harmonic = kept.len() as f64 / kept.iter().map(|speed| 1.0 / speed).sum::<f64>();
+                profiles.set(way_id, forward, bucket, harmonic);
+            }
+        }
+        profiles
+    }
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) { (sorted[middle - 1] + sorted[middle]) / 2.0 } else { sorted[middle] }
+}
+
+/// Keeps the values within `threshold` median absolute deviations of the
+/// median, with the deviation scaled to match the standard deviation of
+/// normally distributed values.
+pub fn reject_outliers(values: &[f64], threshold: f64) -> Vec<f64> {
+    if values.len() < 3 {
+        return values.to_vec();
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let center = median(&sorted);
+    let mut deviations: Vec<f64> = sorted.iter().map(|value| (value - center).abs()).collect();
+    deviations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    // A floor on the spread keeps rounding noise from rejecting values when
+    // most of them are the same
+    let spread = (1.4826 * median(&deviations)).max(center.abs() * 1e-6);
+    sorted.into_iter().filter(|value| (value - center).abs() <= threshold * spread).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use matching::Matcher;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    // A road of about 900 m running east from node 1 through node 2 to 3
+    fn create_map() -> Map {
+        Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 36.0, minlon: -86.0, maxlat: 36.001, maxlon: -85.99 },
+            nodes: vec![node(1, 36.0, -86.0), node(2, 36.0, -85.995), node(3, 36.0, -85.99)],
+            ways: vec![Way {
+                id: 1, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+                node_refs: vec![NodeRef { id: 1 }, NodeRef { id: 2 }, NodeRef { id: 3 }],
+                name: None, tags: Tags::new()
+            }],
+            relations: Vec::new()
+        }
+    }
+
+    // Points every `interval` seconds driving east at a constant speed
+    fn trace(start: i64, interval: i64, count: usize) -> Vec<GpsPoint> {
+        (0..count).map(|i| GpsPoint {
+            time: start + interval * i as i64, lat: 36.0, lon: -86.0 + 0.01 * i as f64 / (count - 1) as f64
+        }).collect()
+    }
+
+    #[test]
+    fn outliers_are_rejected() {
+        assert_eq!(vec![30.0, 31.0, 32.0, 33.0], reject_outliers(&[31.0, 90.0, 30.0, 33.0, 32.0], 3.0));
+        assert_eq!(vec![40.0, 40.0, 40.0], reject_outliers(&[40.0, 40.0, 5.0, 40.0], 3.0));
+        assert_eq!(vec![5.0, 40.0], reject_outliers(&[5.0, 40.0], 3.0));
+    }
+
+    #[test]
+    fn estimates_speeds_per_bucket() {
+        let map = create_map();
+        let graph = Graph::new(&map);
+        let matcher = Matcher::new(&graph, Default::default());
+        let mut estimator = Estimator::new(&graph, Profiles::default(), Config::default());
+
+        // Monday 8:00, three traces at about 10 m/s, one crawling at 1 m/s
+        let monday = 4 * 86400 + 8 * 3600;
+        for &(start, interval) in &[(monday, 9), (monday + 60, 9), (monday + 120, 9), (monday + 180, 90)] {
+            let points = trace(start, interval, 11);
+            estimator.add_trace(&points, &matcher.match_trace(&points));
+        }
+        assert_eq!(40, estimator.sample_count());
+
+        let profiles = estimator.profiles();
+        let speed = profiles.speed(1, true, 8.0 * 3600.0).unwrap();
+        assert!((speed - 36.0).abs() < 1.0, "{}", speed);
+        assert_eq!(None, profiles.speed(1, false, 8.0 * 3600.0));
+        assert_eq!(None, profiles.speed(1, true, 9.0 * 3600.0));
+
+        // Too few samples in a bucket give no speed
+        let mut estimator = Estimator::new(&graph, Profiles::default(), Config { min_samples: 20, ..Config::default() });
+        let points = trace(monday, 9, 11);
+        estimator.add_trace(&points, &matcher.match_trace(&points));
+        assert!(estimator.profiles().is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+// This module stores OSM tags compactly. Keys and values are interned in a
+// process-wide string pool, so every distinct string is kept in memory once
+// and a tag takes up two small ids.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::FromIterator;
+use std::sync::RwLock;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// Id of a string in the global pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+struct Pool {
+    strings: Vec<&'static str>,
+    ids: HashMap<&'static str, u32>
+}
+
+lazy_static! {
+    static ref POOL: RwLock<Pool> = RwLock::new(Pool {
+        strings: Vec::new(),
+        ids: HashMap::new()
+    });
+}
+
+impl Symbol {
+    /// Returns the symbol for `s`, adding it to the pool if needed. Strings
+    /// are never removed from the pool.
+    pub fn intern(s: &str) -> Symbol {
+        if let Some(symbol) = Symbol::lookup(s) {
+            return symbol;
+        }
+        let mut pool = POOL.write().unwrap();
+        if let Some(&id) = pool.ids.get(s) {
+            return Symbol(id);
+        }
+        let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+        let id = pool.strings.len() as u32;
+        pool.strings.push(s);
+        pool.ids.insert(s, id);
+        Symbol(id)
+    }
+
+    /// Returns the symbol for `s` if it has been interned before.
+    pub fn lookup(s: &str) -> Option<Symbol> {
+        POOL.read().unwrap().ids.get(s).map(|&id| Symbol(id))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        POOL.read().unwrap().strings[self.0 as usize]
+    }
+}
+
+/// Tags of an OSM object, stored as `(key, value)` symbol pairs sorted by
+/// key. The lookup methods mirror `HashMap<String, String>`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Tags {
+    pairs: Vec<(Symbol, Symbol)>
+}
+
+impl Tags {
+    pub fn new() -> Tags {
+        Tags { pairs: Vec::new() }
+    }
+
+    fn position(&self, key: &str) -> Result<usize, usize> {
+        match Symbol::lookup(key) {
+            Some(key) => self.pairs.binary_search_by_key(&key, |&(k, _)| k),
+            None => Err(0)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.position(key).ok().map(|i| self.pairs[i].1.as_str())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_ok()
+    }
+
+    /// Sets a tag, returning the previous value if there was one.
+    pub fn insert<K: AsRef<str>, V: AsRef<str>>(&mut self, key: K, value: V) -> Option<&'static str> {
+        let key = Symbol::intern(key.as_ref());
+        let value = Symbol::intern(value.as_ref());
+        match self.pairs.binary_search_by_key(&key, |&(k, _)| k) {
+            Ok(i) => {
+                let old = self.pairs[i].1;
+                self.pairs[i].1 = value;
+                Some(old.as_str())
+            },
+            Err(i) => {
+                self.pairs.insert(i, (key, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<&'static str> {
+        match self.position(key) {
+            Ok(i) => Some(self.pairs.remove(i).1.as_str()),
+            Err(_) => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Iterates over `(key, value)` pairs. The order is stable but not
+    /// alphabetical.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'static str, &'static str)> + 'a {
+        self.pairs.iter().map(|&(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Returns the tags sorted alphabetically by key.
+    pub fn sorted(&self) -> Vec<(&'static str, &'static str)> {
+        let mut result: Vec<_> = self.iter().collect();
+        result.sort();
+        result
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> FromIterator<(K, V)> for Tags {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Tags {
+        let mut tags = Tags::new();
+        for (k, v) in iter {
+            tags.insert(k, v);
+        }
+        tags
+    }
+}
+
+impl fmt::Debug for Tags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.sorted()).finish()
+    }
+}
+
+// Tags are serialized as a plain string map, so files don't depend on the
+// order in which strings happened to be interned.
+impl Serialize for Tags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k, v) in self.sorted() {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Tags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tags, D::Error> {
+        deserializer.deserialize_map(TagsVisitor)
+    }
+}
+
+struct TagsVisitor;
+
+impl<'de> Visitor<'de> for TagsVisitor {
+    type Value = Tags;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of tags")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Tags, M::Error> {
+        let mut tags = Tags::new();
+        while let Some((k, v)) = access.next_entry::<String, String>()? {
+            tags.insert(k, v);
+        }
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+
+    #[test]
+    fn intern_returns_same_symbol() {
+        let a = Symbol::intern("highway");
+        let b = Symbol::intern(&String::from("highway"));
+        assert_eq!(a, b);
+        assert_eq!("highway", a.as_str());
+        assert_eq!(None, Symbol::lookup("tags-test-never-interned"));
+    }
+
+    #[test]
+    fn tags_lookup() {
+        let mut tags = Tags::new();
+        assert_eq!(None, tags.insert("highway", "residential"));
+        assert_eq!(None, tags.insert(String::from("lanes"), String::from("2")));
+        assert_eq!(Some("residential"), tags.insert("highway", "primary"));
+
+        assert_eq!(2, tags.len());
+        assert_eq!(Some("primary"), tags.get("highway"));
+        assert!(tags.contains_key("lanes"));
+        assert!(!tags.contains_key("oneway"));
+        assert_eq!(vec![("highway", "primary"), ("lanes", "2")], tags.sorted());
+
+        assert_eq!(Some("2"), tags.remove("lanes"));
+        assert_eq!(None, tags.get("lanes"));
+    }
+
+    #[test]
+    fn tags_equal_regardless_of_insertion_order() {
+        let a: Tags = vec![("tags-test-a", "1"), ("tags-test-b", "2")].into_iter().collect();
+        let b: Tags = vec![("tags-test-b", "2"), ("tags-test-a", "1")].into_iter().collect();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn tags_serialization_round_trip() {
+        let tags: Tags = vec![("name:en", "Main Street"), ("surface", "asphalt")].into_iter().collect();
+        let bytes = bincode::serialize(&tags).unwrap();
+        let actual: Tags = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(tags, actual);
+    }
+}
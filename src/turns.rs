@@ -0,0 +1,210 @@
+// This module puts a price on turning. It measures the angle between
+// consecutive edges from node coordinates and builds an edge-based view of a
+// routing graph, where every step from one edge onto the next carries the
+// penalty for the turn it makes. `pathfinder::find_path_with_turns` searches
+// it.
+//
+// Penalties are given in meters, the unit of edge costs. Left and right turns
+// are only penalized at intersections, so that bends in a road stay free;
+// U-turns are penalized everywhere.
+
+use std::collections::HashSet;
+use control;
+use geo;
+use graph::Graph;
+
+/// Side of the road traffic drives on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Right,
+    Left
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Turn {
+    Straight,
+    Right,
+    Left,
+    UTurn
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnCosts {
+    /// Turns sharper than this many degrees are left or right turns.
+    pub straight_angle: f64,
+    /// Turns sharper than this many degrees are U-turns.
+    pub u_turn_angle: f64,
+    pub right: f64,
+    pub left: f64,
+    pub u_turn: f64
+}
+
+impl Default for TurnCosts {
+    fn default() -> TurnCosts {
+        TurnCosts::driving_on(Side::Right)
+    }
+}
+
+impl TurnCosts {
+    /// Default penalties for traffic driving on the given side, where turns
+    /// across oncoming traffic cost more than turns away from it.
+    pub fn driving_on(side: Side) -> TurnCosts {
+        let (near, far) = (15.0, 50.0);
+        let (right, left) = match side {
+            Side::Right => (near, far),
+            Side::Left => (far, near)
+        };
+        TurnCosts { straight_angle: 30.0, u_turn_angle: 150.0, right, left, u_turn: 200.0 }
+    }
+
+    /// Classifies a turn angle as returned by `turn_angle`.
+    pub fn classify(&self, angle: f64) -> Turn {
+        if angle.abs() > self.u_turn_angle {
+            Turn::UTurn
+        } else if angle > self.straight_angle {
+            Turn::Right
+        } else if angle < -self.straight_angle {
+            Turn::Left
+        } else {
+            Turn::Straight
+        }
+    }
+
+    pub fn cost(&self, turn: Turn) -> f64 {
+        match turn {
+            Turn::Straight => 0.0,
+            Turn::Right => self.right,
+            Turn::Left => self.left,
+            Turn::UTurn => self.u_turn
+        }
+    }
+}
+
+/// Change of heading in degrees when driving from `from` through `via` to
+/// `to`, all `(lat, lon)` positions. Right turns are positive, left turns
+/// negative, and going back the way one came gives 180.
+pub fn turn_angle(from: (f64, f64), via: (f64, f64), to: (f64, f64)) -> f64 {
+    let angle = geo::initial_bearing(via, to) - geo::final_bearing(from, via);
+    let angle = (angle + 540.0) % 360.0 - 180.0;
+    if angle == -180.0 { 180.0 } else { angle }
+}
+
+/// Edge-based view of a routing graph. Each edge knows the edges it can be
+/// followed by and the turn penalty for doing so; edge costs are still read
+/// from the underlying graph, so overrides applied to it take effect.
+#[derive(Debug)]
+pub struct TurnGraph<'g, 'a: 'g> {
+    graph: &'g Graph<'a>,
+    // `(next edge, penalty)` for every edge
+    turns: Vec<Vec<(usize, f64)>>
+}
+
+impl<'g, 'a> TurnGraph<'g, 'a> {
+    pub fn new(graph: &'g Graph<'a>, costs: &TurnCosts) -> TurnGraph<'g, 'a> {
+        let junctions: HashSet<usize> = control::intersections(graph).iter().
+            filter(|intersection| intersection.legs >= 3).
+            map(|intersection| intersection.node).
+            collect();
+        let turns = graph.edges.iter().map(|edge| {
+            let from = graph.nodes[edge.from].position();
+            let via = graph.nodes[edge.to].position();
+            graph.outgoing(edge.to).iter().map(|&next| {
+                let to = graph.edges[next].to;
+                let turn = if to == edge.from {
+                    Turn::UTurn
+                } else {
+                    costs.classify(turn_angle(from, via, graph.nodes[to].position()))
+                };
+                let penalty = if turn == Turn::UTurn || junctions.contains(&edge.to) {
+                    costs.cost(turn)
+                } else {
+                    0.0
+                };
+                (next, penalty)
+            }).collect()
+        }).collect();
+        TurnGraph { graph, turns }
+    }
+
+    pub fn graph(&self) -> &'g Graph<'a> {
+        self.graph
+    }
+
+    /// Returns `(next edge, penalty)` for every edge that may follow `edge`.
+    pub fn turns(&self, edge: usize) -> &[(usize, f64)] {
+        &self.turns[edge]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    #[test]
+    fn angles_and_classes() {
+        let costs = TurnCosts::default();
+        let right = turn_angle((0.0, 0.0), (0.001, 0.0), (0.001, 0.001));
+        let left = turn_angle((0.0, 0.0), (0.001, 0.0), (0.001, -0.001));
+        let back = turn_angle((0.0, 0.0), (0.001, 0.0), (0.0, 0.0));
+        assert!((right - 90.0).abs() < 1e-3);
+        assert!((left + 90.0).abs() < 1e-3);
+        assert!((back - 180.0).abs() < 1e-3);
+        assert_eq!(Turn::Right, costs.classify(right));
+        assert_eq!(Turn::Left, costs.classify(left));
+        assert_eq!(Turn::UTurn, costs.classify(back));
+        assert_eq!(Turn::Straight, costs.classify(-20.0));
+
+        let left_hand = TurnCosts::driving_on(Side::Left);
+        assert!(costs.cost(Turn::Left) > costs.cost(Turn::Right));
+        assert!(left_hand.cost(Turn::Left) < left_hand.cost(Turn::Right));
+    }
+
+    #[test]
+    fn penalties_only_at_junctions() {
+        // A bend at node 2, and a junction at node 3 with a spur to node 5
+        let map = Map {
+            version: "0.1".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 0.002, maxlon: 0.002 },
+            nodes: vec![
+                node(1, 0.0, 0.0), node(2, 0.001, 0.0), node(3, 0.001, 0.001),
+                node(4, 0.001, 0.002), node(5, 0.002, 0.001)
+            ],
+            ways: vec![way(1, &[1, 2, 3, 4]), way(2, &[3, 5])],
+            relations: Vec::new()
+        };
+        let graph = Graph::new(&map);
+        let costs = TurnCosts::default();
+        let turns = TurnGraph::new(&graph, &costs);
+        let edge = |from: i64, to: i64| graph.edges.iter().
+            position(|e| graph.nodes[e.from].id == from && graph.nodes[e.to].id == to).
+            unwrap();
+        let penalty = |a: (i64, i64), b: (i64, i64)| turns.turns(edge(a.0, a.1)).iter().
+            find(|&&(next, _)| next == edge(b.0, b.1)).
+            map(|&(_, penalty)| penalty);
+
+        assert_eq!(Some(0.0), penalty((1, 2), (2, 3)));
+        assert_eq!(Some(costs.u_turn), penalty((1, 2), (2, 1)));
+        assert_eq!(Some(0.0), penalty((2, 3), (3, 4)));
+        assert_eq!(Some(costs.left), penalty((2, 3), (3, 5)));
+        assert_eq!(Some(costs.right), penalty((4, 3), (3, 5)));
+        assert_eq!(None, penalty((1, 2), (3, 4)));
+    }
+}
@@ -0,0 +1,265 @@
+// This module checks the referential integrity of imported maps. Extracts
+// often reference objects they don't contain: Overpass queries without
+// recursion leave out the nodes of ways, and relations usually reach beyond
+// any extract. Files can also hold duplicate ids, impossible coordinates and
+// ways too short to be a road segment.
+//
+// Validation reports every problem found and, depending on the options,
+// drops the offending objects. Drops cascade in a fixed order: duplicates
+// first, then nodes with bad coordinates, then ways referencing missing
+// nodes, degenerate ways and finally relation members pointing at objects
+// that are gone.
+
+use std::collections::HashSet;
+use std::fmt;
+use entities::Map;
+
+/// Something wrong with a map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A second element of a kind (`node`, `way` or `relation`) with an id
+    /// already used.
+    DuplicateId { kind: &'static str, id: i64 },
+    /// A node with coordinates that aren't a valid latitude and longitude.
+    InvalidCoordinates { node_id: i64, lat: f64, lon: f64 },
+    /// A node outside the bounds declared by the map.
+    OutsideBounds { node_id: i64 },
+    /// A way referencing a node the map doesn't have.
+    MissingNode { way_id: i64, node_id: i64 },
+    /// A way with fewer than two distinct nodes.
+    DegenerateWay { way_id: i64 },
+    /// A relation member the map doesn't have.
+    MissingMember { relation_id: i64, kind: String, member_id: i64 }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::DuplicateId { kind, id } => write!(f, "duplicate {} {}", kind, id),
+            Problem::InvalidCoordinates { node_id, lat, lon } =>
+                write!(f, "node {} has invalid coordinates {}, {}", node_id, lat, lon),
+            Problem::OutsideBounds { node_id } => write!(f, "node {} is outside the map bounds", node_id),
+            Problem::MissingNode { way_id, node_id } => write!(f, "way {} references missing node {}", way_id, node_id),
+            Problem::DegenerateWay { way_id } => write!(f, "way {} has fewer than two distinct nodes", way_id),
+            Problem::MissingMember { relation_id, ref kind, member_id } =>
+                write!(f, "relation {} references missing {} {}", relation_id, kind, member_id)
+        }
+    }
+}
+
+/// Which offending objects to drop. By default problems are only reported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    /// Keep only the first element with each id.
+    pub drop_duplicates: bool,
+    /// Drop nodes with invalid coordinates.
+    pub drop_invalid_coordinates: bool,
+    /// Drop nodes outside the map bounds. Extracts with complete ways have
+    /// such nodes on purpose.
+    pub drop_outside_bounds: bool,
+    /// Drop ways referencing missing nodes, rather than leaving out just
+    /// their segments touching those nodes as `Graph::new` does.
+    pub drop_missing_nodes: bool,
+    /// Drop ways with fewer than two distinct nodes.
+    pub drop_degenerate_ways: bool,
+    /// Remove missing members from relations, dropping relations left
+    /// without members.
+    pub drop_missing_members: bool
+}
+
+impl Options {
+    /// Drops everything that can't be used, but keeps nodes outside the
+    /// bounds.
+    pub fn repair() -> Options {
+        Options {
+            drop_duplicates: true,
+            drop_invalid_coordinates: true,
+            drop_outside_bounds: false,
+            drop_missing_nodes: true,
+            drop_degenerate_ways: true,
+            drop_missing_members: true
+        }
+    }
+}
+
+/// Checks `map`, dropping offending objects as `options` asks, and returns
+/// the problems found.
+pub fn validate(map: &mut Map, options: &Options) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let mut seen = HashSet::new();
+    map.nodes.retain(|node| {
+        let first = seen.insert(node.id);
+        if !first {
+            problems.push(Problem::DuplicateId { kind: "node", id: node.id });
+        }
+        first || !options.drop_duplicates
+    });
+    let mut seen = HashSet::new();
+    map.ways.retain(|way| {
+        let first = seen.insert(way.id);
+        if !first {
+            problems.push(Problem::DuplicateId { kind: "way", id: way.id });
+        }
+        first || !options.drop_duplicates
+    });
+    let mut seen = HashSet::new();
+    map.relations.retain(|relation| {
+        let first = seen.insert(relation.id);
+        if !first {
+            problems.push(Problem::DuplicateId { kind: "relation", id: relation.id });
+        }
+        first || !options.drop_duplicates
+    });
+
+    let bounds = &map.bounds;
+    map.nodes.retain(|node| {
+        let (lat, lon) = node.position();
+        if !(lat.is_finite() && lon.is_finite() && lat.abs() <= 90.0 && lon.abs() <= 180.0) {
+            problems.push(Problem::InvalidCoordinates { node_id: node.id, lat, lon });
+            return !options.drop_invalid_coordinates;
+        }
+        if lat < bounds.minlat || lat > bounds.maxlat || lon < bounds.minlon || lon > bounds.maxlon {
+            problems.push(Problem::OutsideBounds { node_id: node.id });
+            return !options.drop_outside_bounds;
+        }
+        true
+    });
+
+    let node_ids: HashSet<i64> = map.nodes.iter().map(|node| node.id).collect();
+    map.ways.retain(|way| {
+        let mut complete = true;
+        for node_ref in &way.node_refs {
+            if !node_ids.contains(&node_ref.id) {
+                problems.push(Problem::MissingNode { way_id: way.id, node_id: node_ref.id });
+                complete = false;
+            }
+        }
+        if !complete && options.drop_missing_nodes {
+            return false;
+        }
+        let first = way.node_refs.first().map(|node_ref| node_ref.id);
+        if !way.node_refs.iter().any(|node_ref| Some(node_ref.id) != first) {
+            problems.push(Problem::DegenerateWay { way_id: way.id });
+            return !options.drop_degenerate_ways;
+        }
+        true
+    });
+
+    // Dropping a relation that lost all its members leaves relations
+    // referencing it with a missing member, so repeat until none is dropped
+    let way_ids: HashSet<i64> = map.ways.iter().map(|way| way.id).collect();
+    loop {
+        let relation_ids: HashSet<i64> = map.relations.iter().map(|relation| relation.id).collect();
+        for relation in &mut map.relations {
+            let relation_id = relation.id;
+            relation.members.retain(|member| {
+                let present = match member.kind.as_str() {
+                    "node" => node_ids.contains(&member.id),
+                    "way" => way_ids.contains(&member.id),
+                    "relation" => relation_ids.contains(&member.id),
+                    _ => false
+                };
+                if !present {
+                    problems.push(Problem::MissingMember { relation_id, kind: member.kind.clone(), member_id: member.id });
+                }
+                present || !options.drop_missing_members
+            });
+        }
+        let count = map.relations.len();
+        if options.drop_missing_members {
+            map.relations.retain(|relation| !relation.members.is_empty());
+        }
+        if map.relations.len() == count {
+            break;
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::*;
+    use tags::Tags;
+
+    fn node(id: i64, lat: f64, lon: f64) -> Node {
+        Node {
+            id, lat, lon, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            node_refs: node_ids.iter().map(|&id| NodeRef { id }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn relation(id: i64, members: &[(&str, i64)]) -> Relation {
+        Relation {
+            id, version: 1, timestamp: None, changeset: 1, uid: None, user: None,
+            members: members.iter().map(|&(kind, id)| Member { kind: kind.to_string(), id, role: String::new() }).collect(),
+            name: None, tags: Tags::new()
+        }
+    }
+
+    fn create_map() -> Map {
+        Map {
+            version: "0.6".to_string(), generator: "test".to_string(),
+            note: "foo".to_string(), meta: Meta { osm_base: "bar".to_string() },
+            bounds: Bounds { minlat: 0.0, minlon: 0.0, maxlat: 1.0, maxlon: 1.0 },
+            nodes: vec![
+                node(1, 0.1, 0.1), node(2, 0.2, 0.2), node(2, 0.3, 0.3), node(3, 95.0, 0.4),
+                node(4, 1.5, 0.5), node(5, 0.6, 0.6)
+            ],
+            ways: vec![way(10, &[1, 2]), way(11, &[2, 3, 9]), way(12, &[5, 5]), way(13, &[1, 4])],
+            relations: vec![
+                relation(20, &[("way", 10), ("way", 11), ("node", 8)]),
+                relation(21, &[("way", 12), ("relation", 99)]),
+                relation(22, &[("relation", 20)]),
+                relation(23, &[("relation", 21), ("way", 13)])
+            ]
+        }
+    }
+
+    #[test]
+    fn reports_without_dropping() {
+        let mut map = create_map();
+        let problems = validate(&mut map, &Options::default());
+        assert_eq!(vec![
+            Problem::DuplicateId { kind: "node", id: 2 },
+            Problem::InvalidCoordinates { node_id: 3, lat: 95.0, lon: 0.4 },
+            Problem::OutsideBounds { node_id: 4 },
+            Problem::MissingNode { way_id: 11, node_id: 9 },
+            Problem::DegenerateWay { way_id: 12 },
+            Problem::MissingMember { relation_id: 20, kind: "node".to_string(), member_id: 8 },
+            Problem::MissingMember { relation_id: 21, kind: "relation".to_string(), member_id: 99 }
+        ], problems);
+        assert_eq!(6, map.nodes.len());
+        assert_eq!(4, map.ways.len());
+        assert_eq!("way 11 references missing node 9", problems[3].to_string());
+    }
+
+    #[test]
+    fn repair_drops_and_cascades() {
+        let mut map = create_map();
+        let problems = validate(&mut map, &Options::repair());
+        // Dropping node 3 makes way 11 incomplete too
+        assert!(problems.contains(&Problem::MissingNode { way_id: 11, node_id: 3 }));
+        assert_eq!(vec![1, 2, 4, 5], map.nodes.iter().map(|node| node.id).collect::<Vec<i64>>());
+        assert_eq!((0.2, 0.2), map.nodes[1].position());
+        assert_eq!(vec![10, 13], map.ways.iter().map(|way| way.id).collect::<Vec<i64>>());
+        // Relation 21 loses all its members and is dropped, and relation 23
+        // loses it in turn
+        assert_eq!(vec![20, 22, 23], map.relations.iter().map(|relation| relation.id).collect::<Vec<i64>>());
+        assert_eq!(1, map.relations[0].members.len());
+        assert!(problems.contains(&Problem::MissingMember { relation_id: 23, kind: "relation".to_string(), member_id: 21 }));
+        assert_eq!(vec![Member { kind: "way".to_string(), id: 13, role: String::new() }], map.relations[2].members);
+
+        // A repaired map is clean
+        assert_eq!(vec![Problem::OutsideBounds { node_id: 4 }], validate(&mut map, &Options::repair()));
+    }
+}
@@ -0,0 +1,105 @@
+extern crate jamville;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use jamville::compression::Compression;
+use jamville::loader;
+
+const FIXTURE: &str = "tests/fixtures/small.osm";
+
+#[test]
+fn read_xml_fixture() {
+    let map = loader::read_xml(File::open(FIXTURE).unwrap()).unwrap();
+    assert_eq!(10, map.nodes.len());
+    assert_eq!(6, map.ways.len());
+    assert_eq!(1, map.relations.len());
+
+    let way = map.ways.iter().find(|w| w.id == 100).unwrap();
+    assert_eq!(Some("South Street".to_string()), way.name);
+    assert_eq!(Some("residential"), way.tags.get("highway"));
+    assert_eq!(Some(1525249800), way.timestamp);
+    assert_eq!(3, way.node_refs.len());
+}
+
+#[test]
+fn load_picks_format_by_extension() {
+    let map = loader::load(FIXTURE).unwrap();
+    assert_eq!(10, map.nodes.len());
+
+    match loader::load("tests/fixtures/small.txt") {
+        Err(loader::Error::UnknownFormat(_)) => {},
+        other => panic!("unexpected result: {:?}", other.map(|_| ()))
+    }
+}
+
+#[test]
+fn binary_round_trip() {
+    let map = loader::load(FIXTURE).unwrap();
+    let path = env::temp_dir().join("jamville-binary-round-trip.bin");
+    loader::save_bin(&path, &map).unwrap();
+    let reloaded = loader::load(&path).unwrap();
+
+    assert_eq!(map.nodes, reloaded.nodes);
+    assert_eq!(map.ways, reloaded.ways);
+    assert_eq!(map.relations, reloaded.relations);
+}
+
+#[test]
+fn xml_round_trip() {
+    let mut map = loader::load(FIXTURE).unwrap();
+    map.ways[0].name = Some("Smith & Sons \"Depot\"".to_string());
+    let path = env::temp_dir().join("jamville-xml-round-trip.osm");
+    loader::save_xml(&path, &map).unwrap();
+    let reloaded = loader::load(&path).unwrap();
+
+    assert_eq!(map.note, reloaded.note);
+    assert_eq!(map.meta.osm_base, reloaded.meta.osm_base);
+    assert_eq!(map.bounds.maxlon, reloaded.bounds.maxlon);
+    assert_eq!(map.nodes, reloaded.nodes);
+    assert_eq!(map.ways, reloaded.ways);
+    assert_eq!(map.relations, reloaded.relations);
+}
+
+#[test]
+fn overpass_json_matches_xml() {
+    let xml = loader::load(FIXTURE).unwrap();
+    let json = loader::load("tests/fixtures/small.json").unwrap();
+
+    assert_eq!(xml.nodes, json.nodes);
+    assert_eq!(xml.ways, json.ways);
+    assert_eq!(xml.relations, json.relations);
+    assert_eq!(xml.meta.osm_base, json.meta.osm_base);
+}
+
+#[test]
+fn missing_timestamps_stay_missing() {
+    let json = r#"{"elements": [
+        {"type": "node", "id": 1, "lat": 36.0, "lon": -86.0},
+        {"type": "node", "id": 2, "lat": 36.1, "lon": -86.0, "timestamp": "2018-05-02T08:30:00Z"}
+    ]}"#;
+    let map = loader::read_json(json.as_bytes()).unwrap();
+    assert_eq!(None, map.nodes[0].timestamp);
+
+    let mut xml = Vec::new();
+    loader::write_xml(&mut xml, &map).unwrap();
+    let xml = String::from_utf8(xml).unwrap();
+    assert_eq!(1, xml.matches("timestamp=").count());
+    let map = loader::read_xml(xml.as_bytes()).unwrap();
+    assert_eq!((None, Some(1525249800)), (map.nodes[0].timestamp, map.nodes[1].timestamp));
+}
+
+#[test]
+fn load_decompresses_on_the_fly() {
+    let xml = fs::read(FIXTURE).unwrap();
+    for &(compression, extension) in &[(Compression::Gzip, "osm.gz"), (Compression::Bzip2, "osm.bz2"), (Compression::Zstd, "osm.zst")] {
+        let mut encoder = compression.encoder(Vec::new()).unwrap();
+        encoder.write_all(&xml).unwrap();
+        let path = env::temp_dir().join(format!("jamville-compressed.{}", extension));
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let map = loader::load(&path).unwrap();
+        assert_eq!(10, map.nodes.len());
+        assert_eq!(6, map.ways.len());
+    }
+}
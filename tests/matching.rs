@@ -0,0 +1,20 @@
+extern crate jamville;
+
+use jamville::{loader, Graph};
+use jamville::matching::{load_trace, Config, Matcher};
+
+#[test]
+fn match_gpx_trace_onto_fixture() {
+    let map = loader::load("tests/fixtures/small.osm").unwrap();
+    let graph = Graph::new(&map);
+    let trace = load_trace("tests/fixtures/trace.gpx").unwrap();
+    assert_eq!(16, trace.len());
+    assert_eq!(12, trace[1].time - trace[0].time);
+
+    let matching = Matcher::new(&graph, Config::default()).match_trace(&trace);
+    assert_eq!(1, matching.routes.len());
+    // South Street, then north on Center Road
+    assert_eq!(vec![100, 104], matching.routes[0].ways);
+    assert_eq!(vec![1, 2, 5, 8], matching.routes[0].nodes);
+    assert!(matching.points.iter().all(|snap| snap.is_some()));
+}
@@ -0,0 +1,97 @@
+extern crate jamville;
+
+use jamville::{loader, Graph};
+use jamville::avoid::Avoid;
+use jamville::pathfinder::{find_path, find_path_avoiding, find_path_departing, find_path_in_graph, find_path_with_turns};
+use jamville::profiles::{EdgeTimes, Profiles};
+use jamville::turns::{TurnCosts, TurnGraph};
+
+fn load_fixture() -> jamville::Map {
+    loader::load("tests/fixtures/small.osm").unwrap()
+}
+
+#[test]
+fn find_path_along_single_way() {
+    let map = load_fixture();
+    assert_eq!(Some(vec![1, 2, 3]), find_path(&map, 1, 3));
+}
+
+#[test]
+fn find_path_across_ways() {
+    let map = load_fixture();
+    let graph = Graph::new(&map);
+    let path = find_path_in_graph(&graph, 1, 9).expect("couldn't find path");
+    assert_eq!(Some(&1), path.first());
+    assert_eq!(Some(&9), path.last());
+    assert_eq!(5, path.len());
+}
+
+#[test]
+fn find_path_to_unconnected_node() {
+    let map = load_fixture();
+    let graph = Graph::new(&map);
+    assert_eq!(None, find_path_in_graph(&graph, 1, 10));
+}
+
+#[test]
+fn graph_reused_between_queries() {
+    let map = load_fixture();
+    let graph = Graph::new(&map);
+    assert_eq!(Some(vec![4, 5, 6]), find_path_in_graph(&graph, 4, 6));
+    assert_eq!(Some(vec![6, 5, 4]), find_path_in_graph(&graph, 6, 4));
+}
+
+#[test]
+fn find_path_departing_with_profiles() {
+    let map = load_fixture();
+    let graph = Graph::new(&map);
+
+    // Middle Avenue crawls eastbound during the first hour of Monday
+    let csv = "way_id,direction,bucket,speed\n\
+               101,forward,0,2\n101,forward,1,2\n101,forward,2,2\n101,forward,3,2\n";
+    let mut profiles = Profiles::default();
+    profiles.read_csv(csv.as_bytes()).unwrap();
+    let times = EdgeTimes::new(&map, &graph, &profiles);
+    let travel_time = |edge, time| times.travel_time(edge, time);
+
+    let (path, rush_hour) = find_path_departing(&graph, 4, 6, 0.0, travel_time).unwrap();
+    assert!(!path.contains(&5));
+    let (path, night) = find_path_departing(&graph, 4, 6, 7200.0, travel_time).unwrap();
+    assert_eq!(vec![4, 5, 6], path);
+    assert!(night - 7200.0 < rush_hour);
+
+    // Westbound traffic is not affected
+    let (path, _) = find_path_departing(&graph, 6, 4, 0.0, travel_time).unwrap();
+    assert_eq!(vec![6, 5, 4], path);
+}
+
+#[test]
+fn find_path_avoiding_ways_and_nodes() {
+    let map = load_fixture();
+    let mut avoid = Avoid::new();
+    avoid.add_list("highway=primary").unwrap();
+    let path = find_path_avoiding(&map, 4, 6, &avoid).unwrap();
+    assert_eq!(5, path.len());
+    assert!(!path.contains(&5));
+
+    // Avoiding the only way out of a corner leaves no route
+    let mut avoid = Avoid::new();
+    avoid.add_list("node:2,node:4").unwrap();
+    assert_eq!(None, find_path_avoiding(&map, 1, 9, &avoid));
+
+    assert_eq!(find_path(&map, 1, 9), find_path_avoiding(&map, 1, 9, &Avoid::new()));
+}
+
+#[test]
+fn find_path_with_free_turns_matches_shortest_path() {
+    let map = load_fixture();
+    let graph = Graph::new(&map);
+    let free = TurnCosts { right: 0.0, left: 0.0, u_turn: 0.0, ..TurnCosts::default() };
+    let turns = TurnGraph::new(&graph, &free);
+    let shortest = find_path_in_graph(&graph, 1, 9).unwrap();
+    assert_eq!(shortest.len(), find_path_with_turns(&turns, 1, 9).unwrap().len());
+
+    let turns = TurnGraph::new(&graph, &TurnCosts::default());
+    let path = find_path_with_turns(&turns, 1, 9).unwrap();
+    assert_eq!((1, 9), (path[0], path[path.len() - 1]));
+}